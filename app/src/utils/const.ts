@@ -5,8 +5,22 @@ export const PROGRAM_ID = new PublicKey(
   "68DEzyuChhLYQjR8Ymo88JWRUh5hrPhuWHWMLBFGHzHC"
 );
 export const SEED_DESCRIPTOR = Buffer.from("wallet_descriptor");
+export const SEED_CONTACT = Buffer.from("contact");
+export const SEED_GROUP_MEMBER = Buffer.from("group_member");
 export const SEED_PRIVATE_CHAT = Buffer.from("privite_chat");
 export const SEED_GROUP_DESCRIPTOR = Buffer.from("group_descriptor");
+export const SEED_MESSAGE_PAGE = Buffer.from("message_page");
+export const SEED_RING_BUFFER = Buffer.from("ring_buffer");
 
-export const WALLET_DESCRIPTOR_VERSION = Buffer.from([1]);
-export const PRIVATE_CHAT_VERSION = Buffer.from([1]);
+export const WALLET_DESCRIPTOR_VERSION = Buffer.from([2]);
+export const CONTACT_VERSION = Buffer.from([1]);
+export const GROUP_MEMBER_VERSION = Buffer.from([1]);
+export const PRIVATE_CHAT_VERSION = Buffer.from([3]);
+export const MESSAGE_VERSION = Buffer.from([1]);
+export const KEY_STATEMENT_DOMAIN = "cherry-chat:pubkey:v1:";
+
+// Message pages hold `length` slots after their header, a message spans one or more slots.
+export const MESSAGE_PAGE_SIZE = 56;
+export const MESSAGE_PAGE_SLOTS = 32;
+export const MESSAGE_SLOT_SIZE = 256;
+export const MESSAGE_HEADER_SIZE = 112;
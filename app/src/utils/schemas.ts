@@ -4,7 +4,16 @@ export const PubkeySchema: Schema = {
   array: { type: "u8", len: 32 },
 };
 
-export const PeerSchema: Schema = {
+export const KeyRecordSchema: Schema = {
+  struct: {
+    pubkey: PubkeySchema,
+    valid_from: "i64",
+    valid_from_slot: "u64",
+  },
+};
+
+// Peers and groups of a wallet live in Contact and GroupMember accounts.
+export const DescriptorSchema: Schema = {
   struct: {
     pubkey: {
       array: {
@@ -12,31 +21,28 @@ export const PeerSchema: Schema = {
         len: 32,
       },
     },
-    status: "u8",
+    peer_count: "u32",
+    group_count: "u32",
+    created_groups: "u64",
+    key_history: { array: { type: KeyRecordSchema } },
   },
 };
-export const GroupSchema: Schema = {
+
+export const ContactSchema: Schema = {
   struct: {
-    account: {
-      array: {
-        type: "u8",
-        len: 32,
-      },
-    },
+    wallet: PubkeySchema,
+    peer: PubkeySchema,
     state: "u8",
   },
 };
 
-export const DescriptorSchema: Schema = {
+export const GroupMemberSchema: Schema = {
   struct: {
-    pubkey: {
-      array: {
-        type: "u8",
-        len: 32,
-      },
-    },
-    peers: { array: { type: PeerSchema } },
-    groups: { array: { type: GroupSchema } },
+    group: PubkeySchema,
+    wallet: PubkeySchema,
+    state: "u8",
+    role: "u8",
+    joined_at: "i64",
   },
 };
 
@@ -63,24 +69,118 @@ export const MessageSchema: Schema = {
   },
 };
 
+// PrivateChat is zero-copy, its padding is read as plain bytes.
 export const ChatSchema: Schema = {
   struct: {
     wallets: { array: { type: PubkeySchema, len: 2 } },
+    message_count: "u64",
+    current_page: "u32",
+    page_length: "u32",
+    mode: "u8",
+    close_votes: "u8",
+    pinned_count: "u8",
+    _padding: { array: { type: "u8", len: 5 } },
+    deposits: { array: { type: "u64", len: 2 } },
+    close_refund_to: PubkeySchema,
+    pinned: { array: { type: "u64", len: 5 } },
+    message_ttl: "i64",
+    allowed_schemes: "u8",
+    _padding2: { array: { type: "u8", len: 3 } },
+    legacy_messages: "u32",
+    open_pages: "u32",
+    closing: "u8",
+    prekey_sessions: "u8",
+    _padding3: { array: { type: "u8", len: 2 } },
+    generation: "u64",
+    event_count: "u64",
+  },
+};
+
+export const MessagePageSchema: Schema = {
+  struct: {
+    chat: PubkeySchema,
+    index: "u32",
     length: "u32",
-    messages: { array: { type: MessageSchema } },
+    count: "u32",
+    _padding: { array: { type: "u8", len: 4 } },
+  },
+};
+
+export const MessageHeaderSchema: Schema = {
+  struct: {
+    seq: "u64",
+    timestamp: "i64",
+    expires_at: "i64",
+    edited_at: "i64",
+    sender: PubkeySchema,
+    previous_hash: { array: { type: "u8", len: 32 } },
+    key_epoch: "u32",
+    body_length: "u32",
+    flags: "u8",
+    scheme: "u8",
+    kind: "u8",
+    kind_version: "u8",
+    _padding: { array: { type: "u8", len: 4 } },
+  },
+};
+
+export const DeviceEnvelopeSchema: Schema = {
+  struct: {
+    device: PubkeySchema,
+    envelope: { array: { type: "u8" } },
+  },
+};
+
+export const ContentRefSchema: Schema = {
+  struct: {
+    hash: { array: { type: "u8", len: 32 } },
+    length: "u64",
+    mime_type: { array: { type: "u8" } },
+    uri: { array: { type: "u8" } },
+    key_envelope: { option: { array: { type: "u8" } } },
+  },
+};
+
+export const ReplyToSchema: Schema = {
+  struct: {
+    seq: "u64",
+    chat: { option: { array: { type: "u8", len: 32 } } },
+  },
+};
+
+export const MessageBodySchema: Schema = {
+  struct: {
+    nonce: { array: { type: "u8" } },
+    device_envelopes: { array: { type: DeviceEnvelopeSchema } },
+    content: { array: { type: "u8" } },
+    attachment: { option: ContentRefSchema },
+    reply_to: { option: ReplyToSchema },
   },
 };
 
+// GroupDescriptor is zero-copy, its Borsh metadata follows the fixed header.
 export const GroupDescriptorSchema: Schema = {
   struct: {
+    owner: PubkeySchema,
+    message_count: "u64",
+    message_ttl: "i64",
+    pinned: { array: { type: "u64", len: 5 } },
+    current_page: "u32",
+    page_length: "u32",
+    member_count: "u32",
+    key_epoch: "u32",
+    group_type: "u8",
+    state: "u8",
+    mode: "u8",
+    pinned_count: "u8",
+    allowed_schemes: "u8",
+    _padding: { array: { type: "u8", len: 3 } },
+    legacy_messages: "u32",
+    _padding2: { array: { type: "u8", len: 4 } },
+    event_count: "u64",
+    _reserved: { array: { type: "u8", len: 48 } },
     title: { array: { type: "u8" } },
     description: { array: { type: "u8" } },
     image_url: { array: { type: "u8" } },
-    owner: { array: { type: "u8", len: 32 } },
-    group_type: "u8",
-    state: "u8",
-    members: { array: { type: GroupSchema } },
-    length: "u32",
-    messages: { array: { type: MessageSchema } },
   },
 };
@@ -1,5 +1,8 @@
 import {
+  Ed25519Program,
+  GetProgramAccountsFilter,
   PublicKey,
+  SYSVAR_INSTRUCTIONS_PUBKEY,
   TransactionInstruction,
   SystemProgram,
   VersionedTransaction,
@@ -15,15 +18,20 @@ import { hkdf } from '@noble/hashes/hkdf';
 import { sha256 } from '@noble/hashes/sha2';
 
 import * as ed2curve from 'ed2curve';
+import { utils } from "@coral-xyz/anchor";
 
 import { EventEmitter } from "./events";
 
 import { Account, Connection } from "./solana";
-import { ChatSchema, DescriptorSchema, GroupDescriptorSchema } from "./schemas";
-import type { DescriptorBorsh, ChatBorsh, GroupDescriptorBorsh, ChatListItem, ChatMetadata, ChatMap, GroupMap, Descriptor } from "./types";
-import { GroupPeerStatus, PeerStatus } from "./types";
+import { ChatSchema, ContactSchema, DescriptorSchema, GroupDescriptorSchema, GroupMemberSchema, MessageBodySchema, MessageHeaderSchema, MessagePageSchema } from "./schemas";
+import type { ContactBorsh, DescriptorBorsh, GroupMemberBorsh, ChatBorsh, GroupDescriptorBorsh, ChatListItem, ChatMetadata, ChatMap, GroupMap, Descriptor, MessageBodyBorsh, MessageHeaderBorsh, MessagePageBorsh } from "./types";
+import { ChatMode, EncryptionScheme, GroupPeerStatus, MessageKind, PeerStatus } from "./types";
 
-import { PROGRAM_ID, SEED_DESCRIPTOR, SEED_PRIVATE_CHAT, SEED_GROUP_DESCRIPTOR, WALLET_DESCRIPTOR_VERSION, PRIVATE_CHAT_VERSION } from "./const";
+import {
+  PROGRAM_ID, SEED_DESCRIPTOR, SEED_CONTACT, SEED_GROUP_MEMBER, SEED_PRIVATE_CHAT, SEED_GROUP_DESCRIPTOR, SEED_MESSAGE_PAGE, SEED_RING_BUFFER,
+  WALLET_DESCRIPTOR_VERSION, CONTACT_VERSION, GROUP_MEMBER_VERSION, PRIVATE_CHAT_VERSION, MESSAGE_VERSION, KEY_STATEMENT_DOMAIN,
+  MESSAGE_PAGE_SIZE, MESSAGE_PAGE_SLOTS, MESSAGE_SLOT_SIZE, MESSAGE_HEADER_SIZE,
+} from "./const";
 
 const _getHash = (data: Buffer | string) => {
   if (typeof data === 'string') {
@@ -74,6 +82,7 @@ const enc = (s: string) => new TextEncoder().encode(s);
 
 export const helpers = {
   getdisc: async (name: string) => (await getHash(Buffer.from(`global:${name}`))).subarray(0, 8),
+  getAccountDisc: (name: string) => getHash(Buffer.from(`account:${name}`)).subarray(0, 8),
 
   getDescriptorPda: (publicKey: PublicKey) => {
     const [descriptorPda] = PublicKey.findProgramAddressSync(
@@ -82,6 +91,20 @@ export const helpers = {
     );
     return descriptorPda;
   },
+  getContactPda: (publicKey: PublicKey, peer: PublicKey) => {
+    const [contactPda] = PublicKey.findProgramAddressSync(
+      [SEED_CONTACT, publicKey.toBuffer(), peer.toBuffer(), CONTACT_VERSION],
+      PROGRAM_ID
+    );
+    return contactPda;
+  },
+  getGroupMemberPda: (group: PublicKey, publicKey: PublicKey) => {
+    const [groupMemberPda] = PublicKey.findProgramAddressSync(
+      [SEED_GROUP_MEMBER, group.toBuffer(), publicKey.toBuffer(), GROUP_MEMBER_VERSION],
+      PROGRAM_ID
+    );
+    return groupMemberPda;
+  },
   getChatHash: (publicKey: PublicKey, peer: PublicKey) => {
     let raw = Buffer.alloc(64);
     for (let i = 0; i < 32; i++) {
@@ -107,6 +130,26 @@ export const helpers = {
     );
     return chatPda;
   },
+  getMessagePagePda: (chat: Buffer, index: number) => {
+    const [messagePagePda] = PublicKey.findProgramAddressSync(
+      [SEED_MESSAGE_PAGE, chat, numToBuffer_32(index), MESSAGE_VERSION],
+      PROGRAM_ID
+    );
+    return messagePagePda;
+  },
+  getRingBufferPda: (chat: Buffer) => {
+    const [ringBufferPda] = PublicKey.findProgramAddressSync(
+      [SEED_RING_BUFFER, chat, MESSAGE_VERSION],
+      PROGRAM_ID
+    );
+    return ringBufferPda;
+  },
+  // Statement the x25519 key signs to prove it belongs to the wallet.
+  getKeyStatement: (publicKey: PublicKey, x25519Public: Uint8Array) => Buffer.concat([
+    Buffer.from(KEY_STATEMENT_DOMAIN),
+    publicKey.toBuffer(),
+    Buffer.from(x25519Public),
+  ]),
   getNewGroupPda: (publicKey: PublicKey, groups_count: number) => {
     const [newGroupPda] = PublicKey.findProgramAddressSync(
       [SEED_GROUP_DESCRIPTOR, publicKey.toBuffer(), numToBuffer_64(groups_count)],
@@ -140,6 +183,8 @@ export class Stem {
 
   private _x25519Private: Uint8Array | null = null;
   private _x25519Public: Uint8Array | null = null;
+  // Ed25519 key the x25519 keys were converted from, it signs the proof of possession on register.
+  private _ed25519Secret: Uint8Array | null = null;
 
   private _emitter: EventEmitter = new EventEmitter();
 
@@ -220,11 +265,13 @@ export class Stem {
 
     this._x25519Private = x25519Private;
     this._x25519Public = x25519Public;
+    this._ed25519Secret = ed25519.secretKey;
   }
 
-  setKeypair(x25519Private: Uint8Array, x25519Public: Uint8Array) {
+  setKeypair(x25519Private: Uint8Array, x25519Public: Uint8Array, ed25519Secret: Uint8Array | null = null) {
     this._x25519Private = x25519Private;
     this._x25519Public = x25519Public;
+    this._ed25519Secret = ed25519Secret;
   }
 
   // todo: remove this, test only
@@ -263,6 +310,44 @@ export class Stem {
     return decryptedMessage.toString(CryptoJS.enc.Utf8);
   }
 
+  // Contacts and memberships are separate accounts, they are found by their discriminator and owner key.
+  _relationFilters(name: string, offset: number, key: PublicKey): GetProgramAccountsFilter[] {
+    return [
+      { memcmp: { offset: 0, bytes: utils.bytes.bs58.encode(helpers.getAccountDisc(name)) } },
+      { memcmp: { offset, bytes: key.toBase58() } },
+    ];
+  }
+  _contactFilters(wallet: PublicKey) {
+    // Contact { wallet, peer, state }
+    return this._relationFilters("Contact", 8, wallet);
+  }
+  _membershipFilters(wallet: PublicKey) {
+    // GroupMember { group, wallet, state, role, joined_at }
+    return this._relationFilters("GroupMember", 40, wallet);
+  }
+
+  async _fetchContacts(wallet: PublicKey) {
+    const accounts = await this._connection.connection.getProgramAccounts(PROGRAM_ID, {
+      filters: this._contactFilters(wallet),
+    });
+    return accounts.map(({ account }) => borsh.deserialize(ContactSchema, account.data.subarray(8)) as ContactBorsh);
+  }
+  async _fetchMemberships(wallet: PublicKey) {
+    const accounts = await this._connection.connection.getProgramAccounts(PROGRAM_ID, {
+      filters: this._membershipFilters(wallet),
+    });
+    return accounts.map(({ account }) => borsh.deserialize(GroupMemberSchema, account.data.subarray(8)) as GroupMemberBorsh);
+  }
+
+  async fetchGroupMembers(group: PublicKey) {
+    const accounts = await this._connection.connection.getProgramAccounts(PROGRAM_ID, {
+      filters: this._relationFilters("GroupMember", 8, group),
+    });
+    return accounts
+      .map(({ account }) => borsh.deserialize(GroupMemberSchema, account.data.subarray(8)) as GroupMemberBorsh)
+      .map((member) => ({ account: new PublicKey(member.wallet), state: member.state, role: member.role }));
+  }
+
   async _parseAndUpdatePeers() {
     console.log("Stem._parseAndUpdatePeers()");
 
@@ -286,15 +371,11 @@ export class Stem {
 
     if (this._descriptorAccount.isInitialized) {
       console.log('Stem._parseAndUpdatePeers descriptorAccount isInitialized');
-      const chats = borsh.deserialize(
-        DescriptorSchema,
-        this._descriptorAccount.data.subarray(8)
-      ) as DescriptorBorsh;
-
-      console.log('Stem._parseAndUpdatePeers pubkey', chats.pubkey);
+      const contacts = await this._fetchContacts(this._publicKey);
+      const memberships = await this._fetchMemberships(this._publicKey);
 
-      for (const peer of chats.peers) {
-        const peerPubKey = new PublicKey(peer.pubkey);
+      for (const contact of contacts) {
+        const peerPubKey = new PublicKey(contact.peer);
         const peerPubKeyString = peerPubKey.toBase58();
         const obj = this._chatsAccounts.get(peerPubKeyString);
         // ??
@@ -316,41 +397,49 @@ export class Stem {
             peerDescriptor.data.subarray(8)
           ) as Descriptor
 
+          const pages: Account[] = [];
+          const emitChatUpdated = () => {
+            console.log("STEM: Chat updated", account.publicKey.toBase58());
+            this._emitter.emit("onChatUpdated", {
+              pubkey: peerPubKey,
+              chat: this._parseChat(account, pages, new Uint8Array(peerDescriptorData.pubkey)),
+            });
+          };
           if (this._subscribe) {
-            account.onUpdate(() => {
-              console.log("STEM: Chat updated", account.publicKey.toBase58());
-              this._emitter.emit("onChatUpdated", {
-                pubkey: peerPubKey,
-                chat: this._parseChat(account, new Uint8Array(peerDescriptorData.pubkey)),
-              });
+            account.onUpdate(async () => {
+              await this._fetchChatPages(peerPubKey, account, pages, emitChatUpdated);
+              emitChatUpdated();
             });
           }
 
-          account.fetch();
+          account.fetch().then(() => this._fetchChatPages(peerPubKey, account, pages, emitChatUpdated));
           this._chatsAccounts.set(peerPubKeyString, {
             account,
+            pages,
             peer: peerDescriptorData,
-            status: peer.status,
+            status: contact.state,
           });
 
           chatListUpdated = true;
-        } else if (peer.status !== obj?.status) {
+        } else if (contact.state !== obj?.status) {
           this._chatsAccounts.set(peerPubKeyString, {
             account: obj?.account!,
+            pages: obj?.pages!,
             peer: obj?.peer!,
-            status: peer.status,
+            status: contact.state,
           });
           chatListUpdated = true;
         }
       }
 
-      for (const group of chats.groups) {
-        const groupPubKey = new PublicKey(group.account);
+      for (const group of memberships) {
+        const groupPubKey = new PublicKey(group.group);
         const groupPubKeyString = groupPubKey.toBase58();
         const obj = this._groupsAccounts.get(groupPubKeyString);
-        if (!obj) {
+        if (!obj || obj.state !== group.state) {
           this._groupsAccounts.set(groupPubKeyString, {
-            account: null,
+            account: obj?.account ?? null,
+            pages: obj?.pages ?? [],
             state: group.state,
           });
           groupListUpdated = true;
@@ -361,18 +450,23 @@ export class Stem {
             this._connection.connection,
             this._subscribe
           );
+          const pages: Account[] = [];
+          const emitGroupUpdated = () => {
+            this._emitter.emit("onGroupUpdated", {
+              pubkey: groupPubKey,
+              group: this._parseGroup(account, pages),
+            });
+          };
           if (this._subscribe) {
-            account.onUpdate(() => {
-              // console.log("STEM: Group updated", this._parseGroup(account));
-              this._emitter.emit("onGroupUpdated", {
-                pubkey: groupPubKey,
-                group: this._parseGroup(account),
-              });
+            account.onUpdate(async () => {
+              await this._fetchGroupPages(account, pages, emitGroupUpdated);
+              emitGroupUpdated();
             });
           }
-          account.fetch();
+          account.fetch().then(() => this._fetchGroupPages(account, pages, emitGroupUpdated));
           this._groupsAccounts.set(groupPubKeyString, {
             account,
+            pages,
             state: group.state,
           });
         }
@@ -407,6 +501,13 @@ export class Stem {
 
     if (this._subscribe) {
       this._descriptorAccount.onUpdate(this._parseAndUpdatePeers);
+      // Invites, accepts and joins only touch the contact and member accounts.
+      this._connection.connection.onProgramAccountChange(PROGRAM_ID, this._parseAndUpdatePeers, {
+        filters: this._contactFilters(this._publicKey),
+      });
+      this._connection.connection.onProgramAccountChange(PROGRAM_ID, this._parseAndUpdatePeers, {
+        filters: this._membershipFilters(this._publicKey),
+      });
     }
 
     this._isLoaded = true;
@@ -461,35 +562,109 @@ export class Stem {
     });
   }
 
-  _parseChat(account: Account, peerPublicKey: Uint8Array) {
+  // Opens the pages created since the last fetch, a chat has pages 0..current_page.
+  async _openPages(chat: Buffer, currentPage: number, pages: Account[], onUpdate: () => void) {
+    // The page is pushed before the fetch so concurrent updates do not open it twice.
+    while (pages.length <= currentPage) {
+      const page = new Account(helpers.getMessagePagePda(chat, pages.length), this._connection.connection, this._subscribe);
+      if (this._subscribe) {
+        page.onUpdate(onUpdate);
+      }
+      pages.push(page);
+      await page.fetch();
+    }
+  }
+
+  async _fetchChatPages(peer: PublicKey, account: Account, pages: Account[], onUpdate: () => void) {
+    if (!account.isInitialized) {
+      return;
+    }
+    const chat = borsh.deserialize(ChatSchema, account.data.subarray(8)) as ChatBorsh;
+    await this._openPages(helpers.getChatHash(this._publicKey, peer), chat.current_page, pages, onUpdate);
+  }
+
+  // Group pages are seeded with the group account instead of a chat hash.
+  async _fetchGroupPages(account: Account, pages: Account[], onUpdate: () => void) {
+    if (!account.isInitialized) {
+      return;
+    }
+    const group = borsh.deserialize(GroupDescriptorSchema, account.data.subarray(8)) as GroupDescriptorBorsh;
+    await this._openPages(account.publicKey.toBuffer(), group.current_page, pages, onUpdate);
+  }
+
+  // Walks the slots of a page, a message spans its header and body_length bytes of body.
+  _parsePage(page: Account) {
+    const messages: { header: MessageHeaderBorsh; body: MessageBodyBorsh | null }[] = [];
+    if (!page.isInitialized) {
+      return messages;
+    }
+
+    const info = borsh.deserialize(MessagePageSchema, page.data.subarray(8, MESSAGE_PAGE_SIZE)) as MessagePageBorsh;
+    let slot = 0;
+    while (slot < info.length) {
+      const offset = MESSAGE_PAGE_SIZE + slot * MESSAGE_SLOT_SIZE;
+      const header = borsh.deserialize(
+        MessageHeaderSchema,
+        page.data.subarray(offset, offset + MESSAGE_HEADER_SIZE)
+      ) as MessageHeaderBorsh;
+      const body = header.body_length > 0 ? borsh.deserialize(
+        MessageBodySchema,
+        page.data.subarray(offset + MESSAGE_HEADER_SIZE, offset + MESSAGE_HEADER_SIZE + header.body_length)
+      ) as MessageBodyBorsh : null;
+
+      messages.push({ header, body });
+      slot += Math.ceil((MESSAGE_HEADER_SIZE + header.body_length) / MESSAGE_SLOT_SIZE);
+    }
+
+    return messages;
+  }
+
+  _parseChat(account: Account, pages: Account[], peerPublicKey: Uint8Array) {
     const chat = borsh.deserialize(
       ChatSchema,
       account.data.subarray(8)
     ) as ChatBorsh;
     // debugger;
 
+    const messages = pages.flatMap((page) => this._parsePage(page));
+
     return {
       wallets: chat.wallets.map((wallet) => new PublicKey(wallet)),
-      length: chat.length,
-      messages: chat.messages.map((message, index) => ({
-        id:  _getHash(Buffer.concat([Buffer.from(message.sender), Buffer.from(message.content), Buffer.from(message.timestamp)])),
+      length: Number(chat.message_count),
+      messages: messages.map(({ header, body }, index) => ({
+        id: header.seq.toString(),
         index,
-        encrypted: message.encrypted,
-        sender: new PublicKey(message.sender),
-        content: this._decryptMessage(message.content, peerPublicKey),
-        content_raw: Buffer.from(message.content).toString(),
-        timestamp: new Date(
-          Buffer.from(message.timestamp.slice(0, 4)).readUint32LE() * 1000
-        ),
+        encrypted: header.scheme !== EncryptionScheme.None,
+        sender: new PublicKey(header.sender),
+        content: this._messageText(header, body, peerPublicKey),
+        content_raw: body ? Buffer.from(body.content).toString() : '',
+        timestamp: new Date(Number(header.timestamp) * 1000),
       })),
     };
   }
 
-  _parseGroup(account: Account) {
+  _messageText(header: MessageHeaderBorsh, body: MessageBodyBorsh | null, peerPublicKey: Uint8Array) {
+    if (!body || header.kind !== MessageKind.Text) {
+      return '';
+    }
+    if (header.scheme === EncryptionScheme.X25519AesCbc) {
+      return this._decryptMessage(body.content, peerPublicKey);
+    }
+    if (header.scheme === EncryptionScheme.None) {
+      // TextBody { text: String }
+      return Buffer.from(body.content).subarray(4).toString();
+    }
+    return '';
+  }
+
+  // Members are GroupMember accounts, see fetchGroupMembers.
+  _parseGroup(account: Account, pages: Account[]) {
     const group = borsh.deserialize(
       GroupDescriptorSchema,
       account.data.subarray(8)
     ) as GroupDescriptorBorsh;
+    const messages = pages.flatMap((page) => this._parsePage(page));
+
     return {
       title: Buffer.from(group.title).toString(),
       description: Buffer.from(group.description).toString(),
@@ -497,16 +672,17 @@ export class Stem {
       owner: new PublicKey(group.owner),
       group_type: group.group_type,
       state: group.state,
-      members: group.members.map((member) => ({account: new PublicKey(member.account), state: member.state})),
-      length: group.length,
-      messages: group.messages.map((message, index) => ({
-        id: _getHash(Buffer.concat([Buffer.from(message.sender), Buffer.from(message.content), Buffer.from(message.timestamp)])),
+      member_count: group.member_count,
+      length: Number(group.message_count),
+      messages: messages.map(({ header, body }, index) => ({
+        id: header.seq.toString(),
         index,
-        sender: new PublicKey(message.sender),
-        content: Buffer.from(message.content).toString(),
-        timestamp: new Date(
-          Buffer.from(message.timestamp.slice(0, 4)).readUint32LE() * 1000
-        ),
+        sender: new PublicKey(header.sender),
+        // Group messages are only readable in plaintext until the group keys land in the client.
+        content: body && header.kind === MessageKind.Text && header.scheme === EncryptionScheme.None
+          ? Buffer.from(body.content).subarray(4).toString()
+          : '',
+        timestamp: new Date(Number(header.timestamp) * 1000),
       })),
     };
   }
@@ -532,7 +708,7 @@ export class Stem {
     }
     const peerDescriptorData = peerAccount.peer;
 
-    return peerAccount?.account ? this._parseChat(peerAccount.account, new Uint8Array(peerDescriptorData.pubkey)) : null;
+    return peerAccount?.account ? this._parseChat(peerAccount.account, peerAccount.pages, new Uint8Array(peerDescriptorData.pubkey)) : null;
   }
 
   getGroup(pubkey: PublicKey) {
@@ -550,22 +726,25 @@ export class Stem {
       return null;
     }
 
-    return groupAccount?.account ? this._parseGroup(groupAccount.account) : null;
+    return groupAccount?.account ? this._parseGroup(groupAccount.account, groupAccount.pages) : null;
   }
 
   async fetchPublicGroup(pubkey: PublicKey, onUpdateCallback: ((group: any) => void)| undefined = undefined) {
     const groupAccount = new Account(pubkey, this._connection.connection, !!onUpdateCallback);
+    const pages: Account[] = [];
+    const emitGroupUpdated = () => onUpdateCallback?.({
+      pubkey: pubkey,
+      group: this._parseGroup(groupAccount, pages),
+    });
     if (!!onUpdateCallback) {
-      groupAccount.onUpdate(() => {
-        // console.log("STEM: Group updated", this._parseGroup(groupAccount));
-        onUpdateCallback({
-          pubkey: pubkey,
-          group: this._parseGroup(groupAccount),
-        });
+      groupAccount.onUpdate(async () => {
+        await this._fetchGroupPages(groupAccount, pages, emitGroupUpdated);
+        emitGroupUpdated();
       });
     }
     await groupAccount.fetch();
-    return this._parseGroup(groupAccount);
+    await this._fetchGroupPages(groupAccount, pages, emitGroupUpdated);
+    return this._parseGroup(groupAccount, pages);
   }
 
   async fetchUserAccount(publicKey: PublicKey) {
@@ -589,13 +768,8 @@ export class Stem {
     }
     res.isRegistered = true;
 
-    const data = borsh.deserialize(
-      DescriptorSchema,
-      this._descriptorAccount.data.subarray(8)
-    ) as DescriptorBorsh;
-
-    res.chats = data.peers.map(i => new PublicKey(i.pubkey));
-    res.groups = data.groups.map(i => new PublicKey(i.account));
+    res.chats = (await this._fetchContacts(publicKey)).map(i => new PublicKey(i.peer));
+    res.groups = (await this._fetchMemberships(publicKey)).map(i => new PublicKey(i.group));
 
     console.log('### fetchUserAccount', publicKey.toBase58(), res);
 
@@ -636,6 +810,16 @@ export class Stem {
       throw new Error("X25519 keys not generated");
     }
 
+    if (!this._ed25519Secret) {
+      throw new Error("Ed25519 key not generated");
+    }
+
+    // The program checks the instruction before register proves possession of the x25519 key.
+    const proofIx = Ed25519Program.createInstructionWithPrivateKey({
+      privateKey: this._ed25519Secret,
+      message: helpers.getKeyStatement(this._publicKey, this._x25519Public),
+    });
+
     const ix = new TransactionInstruction({
       programId: PROGRAM_ID,
       keys: [
@@ -647,13 +831,18 @@ export class Stem {
         {
           pubkey: this._publicKey,
           isSigner: true,
-          isWritable: false,
+          isWritable: true,
         },
         {
           pubkey: SystemProgram.programId,
           isSigner: false,
           isWritable: false,
         },
+        {
+          pubkey: SYSVAR_INSTRUCTIONS_PUBKEY,
+          isSigner: false,
+          isWritable: false,
+        },
       ],
       data: Buffer.concat([
         await helpers.getdisc("register"),
@@ -666,7 +855,7 @@ export class Stem {
     const txMessage  = new TransactionMessage({
       payerKey: this._publicKey,
       recentBlockhash: blockhash.blockhash,
-      instructions: [ix],
+      instructions: [proofIx, ix],
     }).compileToV0Message();
 
     const tx = new VersionedTransaction(txMessage);
@@ -711,13 +900,20 @@ export class Stem {
 
     const encryptedMessageBuffer = this._encryptMessage(this._x25519Private, Uint8Array.from(peerDescriptorData.pubkey), message);
 
+    // Optional accounts that are not passed are set to the program id.
+    const none = {
+      pubkey: PROGRAM_ID,
+      isSigner: false,
+      isWritable: false,
+    };
+
     const ix = new TransactionInstruction({
       programId: PROGRAM_ID,
       keys: [
         {
           pubkey: this._publicKey,
           isSigner: true,
-          isWritable: false,
+          isWritable: true,
         },
         {
           pubkey: invitee,
@@ -734,23 +930,44 @@ export class Stem {
           isSigner: false,
           isWritable: true,
         },
+        {
+          pubkey: helpers.getContactPda(this._publicKey, invitee),
+          isSigner: false,
+          isWritable: true,
+        },
+        {
+          pubkey: helpers.getContactPda(invitee, this._publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
         {
           pubkey: await helpers.getChatPda(this._publicKey, invitee),
           isSigner: false,
           isWritable: true,
         },
+        {
+          pubkey: helpers.getMessagePagePda(hash, 0),
+          isSigner: false,
+          isWritable: true,
+        },
         {
           pubkey: SystemProgram.programId,
           isSigner: false,
           isWritable: false,
         },
+        none, // sender devices
+        none, // receiver devices
+        none, // prekey bundle
       ],
       data: Buffer.concat([
         await helpers.getdisc("invite"),
         hash,
-        numToBuffer_8(1),
+        numToBuffer_8(EncryptionScheme.X25519AesCbc),
+        numToBuffer_32(0), // nonce, embedded in the content by this scheme
         numToBuffer_32(encryptedMessageBuffer.length),
         encryptedMessageBuffer,
+        numToBuffer_8(MessageKind.Text),
+        numToBuffer_32(0), // device envelopes
       ]),
     });
 
@@ -790,12 +1007,6 @@ export class Stem {
       throw Error("Peer not invited");
     }
 
-    const inviterPda = await helpers.getDescriptorPda(this._publicKey);
-    const inviteePda = await helpers.getDescriptorPda(invitee);
-
-    if (!inviterPda || !inviteePda) {
-      throw new Error("Descriptor PDA not generated");
-    }
 
     if (this._publicKey.toBase58() === invitee.toBase58()) {
       throw new Error("You can't invite yourself");
@@ -807,7 +1018,7 @@ export class Stem {
         {
           pubkey: this._publicKey,
           isSigner: true,
-          isWritable: false,
+          isWritable: true,
         },
         {
           pubkey: invitee,
@@ -815,25 +1026,17 @@ export class Stem {
           isWritable: false,
         },
         {
-          pubkey: inviterPda,
+          pubkey: helpers.getContactPda(this._publicKey, invitee),
           isSigner: false,
           isWritable: true,
         },
         {
-          pubkey: inviteePda,
+          pubkey: helpers.getContactPda(invitee, this._publicKey),
           isSigner: false,
           isWritable: true,
         },
-        {
-          pubkey: SystemProgram.programId,
-          isSigner: false,
-          isWritable: false,
-        },
       ],
-      data: Buffer.concat([
-        await helpers.getdisc("accept"),
-        await helpers.getChatHash(this._publicKey, invitee),
-      ]),
+      data: await helpers.getdisc("accept"),
     });
 
     const blockhash = await this._connection.getLatestBlockhash();
@@ -863,12 +1066,6 @@ export class Stem {
       throw Error("Peer not invited");
     }
 
-    const inviterPda = await helpers.getDescriptorPda(this._publicKey);
-    const inviteePda = await helpers.getDescriptorPda(invitee);
-
-    if (!inviterPda || !inviteePda) {
-      throw new Error("Descriptor PDA not generated");
-    }
 
     if (this._publicKey.toBase58() === invitee.toBase58()) {
       throw new Error("You can't invite yourself");
@@ -880,7 +1077,7 @@ export class Stem {
         {
           pubkey: this._publicKey,
           isSigner: true,
-          isWritable: false,
+          isWritable: true,
         },
         {
           pubkey: invitee,
@@ -888,12 +1085,12 @@ export class Stem {
           isWritable: false,
         },
         {
-          pubkey: inviterPda,
+          pubkey: helpers.getContactPda(this._publicKey, invitee),
           isSigner: false,
           isWritable: true,
         },
         {
-          pubkey: inviteePda,
+          pubkey: helpers.getContactPda(invitee, this._publicKey),
           isSigner: false,
           isWritable: true,
         },
@@ -944,16 +1141,38 @@ export class Stem {
 
     const encryptedMessageBuffer = this._encryptMessage(this._x25519Private, Uint8Array.from(peerDescriptorData.pubkey), message);
 
+    const hash = helpers.getChatHash(this._publicKey, invitee);
+    const chatPda = helpers.getChatPda(this._publicKey, invitee);
+    const chatAccount = new Account(chatPda, this._connection.connection, false);
+    await chatAccount.fetch();
+    const chat = borsh.deserialize(ChatSchema, chatAccount.data.subarray(8)) as ChatBorsh;
+
+    // Paged chats append to the current page until it is full, ring buffer chats overwrite their oldest slot.
+    const nextPage = chat.page_length >= MESSAGE_PAGE_SLOTS ? chat.current_page + 1 : chat.current_page;
+    const isRing = chat.mode === ChatMode.Ring;
+
+    // Optional accounts that are not passed are set to the program id.
+    const none = {
+      pubkey: PROGRAM_ID,
+      isSigner: false,
+      isWritable: false,
+    };
+
     const ix = new TransactionInstruction({
       programId: PROGRAM_ID,
       keys: [
         {
           pubkey: this._publicKey,
           isSigner: true,
-          isWritable: false,
+          isWritable: true,
         },
         {
-          pubkey: await helpers.getChatPda(this._publicKey, invitee),
+          pubkey: chatPda,
+          isSigner: false,
+          isWritable: true,
+        },
+        isRing ? none : {
+          pubkey: helpers.getMessagePagePda(hash, nextPage),
           isSigner: false,
           isWritable: true,
         },
@@ -962,13 +1181,28 @@ export class Stem {
           isSigner: false,
           isWritable: false,
         },
+        isRing ? {
+          pubkey: helpers.getRingBufferPda(hash),
+          isSigner: false,
+          isWritable: true,
+        } : none,
+        none, // reply page
+        none, // reply ring
+        none, // sender devices
+        none, // receiver devices
+        none, // prekey bundle
       ],
       data: Buffer.concat([
         await helpers.getdisc("sendmessage"),
-        await helpers.getChatHash(this._publicKey, invitee),
-        numToBuffer_8(1),
+        hash,
+        numToBuffer_8(EncryptionScheme.X25519AesCbc),
+        numToBuffer_32(0), // nonce, embedded in the content by this scheme
         numToBuffer_32(encryptedMessageBuffer.length),
         encryptedMessageBuffer,
+        numToBuffer_8(0), // attachment
+        numToBuffer_8(0), // reply to
+        numToBuffer_8(MessageKind.Text),
+        numToBuffer_32(0), // device envelopes
       ]),
     });
 
@@ -1009,14 +1243,21 @@ export class Stem {
   }
 
   async createCreateGroupTx(type: number, title: string, description: string, image_url: string) {
-    return this._createTxWrapper(async () => 
-       new TransactionInstruction({
+    return this._createTxWrapper(async () => {
+      // Group accounts are seeded with the number of groups the wallet created so far.
+      const descriptor = borsh.deserialize(
+        DescriptorSchema,
+        this._descriptorAccount.data.subarray(8)
+      ) as DescriptorBorsh;
+      const groupPda = helpers.getNewGroupPda(this._publicKey, Number(descriptor.created_groups));
+
+      return new TransactionInstruction({
       programId: PROGRAM_ID,
       keys: [
         {
           pubkey: this._publicKey,
           isSigner: true,
-          isWritable: false,
+          isWritable: true,
         },
         {
           pubkey: await helpers.getDescriptorPda(this._publicKey),
@@ -1024,7 +1265,12 @@ export class Stem {
           isWritable: true,
         },
         {
-          pubkey: await helpers.getNewGroupPda(this._publicKey, this._groupsAccounts.size),
+          pubkey: groupPda,
+          isSigner: false,
+          isWritable: true,
+        },
+        {
+          pubkey: helpers.getGroupMemberPda(groupPda, this._publicKey),
           isSigner: false,
           isWritable: true,
         },
@@ -1048,39 +1294,89 @@ export class Stem {
           description: Buffer.from(description),
           image_url: Buffer.from(image_url),
         }))
-      })
-    );
+      });
+    });
   }
 
   async createSendMessageToGroupTx(group: PublicKey, content: string) {
-    return this._createTxWrapper(async () => 
-      new TransactionInstruction({
+    return this._createTxWrapper(async () => {
+      const groupAccount = new Account(group, this._connection.connection, false);
+      await groupAccount.fetch();
+      const header = borsh.deserialize(GroupDescriptorSchema, groupAccount.data.subarray(8)) as GroupDescriptorBorsh;
+
+      // Paged groups append to the current page until it is full, ring buffer groups overwrite their oldest slot.
+      const nextPage = header.page_length >= MESSAGE_PAGE_SLOTS ? header.current_page + 1 : header.current_page;
+      const isRing = header.mode === ChatMode.Ring;
+
+      // Optional accounts that are not passed are set to the program id.
+      const none = {
+        pubkey: PROGRAM_ID,
+        isSigner: false,
+        isWritable: false,
+      };
+
+      return new TransactionInstruction({
         programId: PROGRAM_ID,
         keys: [
           {
             pubkey: this._publicKey,
             isSigner: true,
-            isWritable: false,
+            isWritable: true,
           },
           {
             pubkey: group,
             isSigner: false,
             isWritable: true,
           },
+          {
+            pubkey: helpers.getGroupMemberPda(group, this._publicKey),
+            isSigner: false,
+            isWritable: false,
+          },
+          isRing ? none : {
+            pubkey: helpers.getMessagePagePda(group.toBuffer(), nextPage),
+            isSigner: false,
+            isWritable: true,
+          },
           {
             pubkey: SystemProgram.programId,
             isSigner: false,
             isWritable: false,
-          }],
-          data: Buffer.from(borsh.serialize({struct: {
-            disc: {array: {type: "u8", len: 8}},
-            content: {array: {type: "u8"}},
-          }}, {
-            disc: await helpers.getdisc("send_message_to_group"),
-            content: Buffer.from(content),
-          }))
-        })
-    );
+          },
+          isRing ? {
+            pubkey: helpers.getRingBufferPda(group.toBuffer()),
+            isSigner: false,
+            isWritable: true,
+          } : none,
+          none, // reply page
+          none, // reply ring
+        ],
+        data: Buffer.from(borsh.serialize({struct: {
+          disc: {array: {type: "u8", len: 8}},
+          content: {array: {type: "u8"}},
+          attachment: {option: 'u8'},
+          reply_to: {option: 'u8'},
+          kind: 'u8',
+          mentions: {array: {type: {array: {type: "u8", len: 32}}}},
+          mention_all: 'bool',
+          scheme: 'u8',
+          nonce: {array: {type: "u8"}},
+          device_envelopes: {array: {type: "u8"}},
+        }}, {
+          disc: await helpers.getdisc("send_message_to_group"),
+          // TextBody { text: String }
+          content: Buffer.from(borsh.serialize('string', content)),
+          attachment: null,
+          reply_to: null,
+          kind: MessageKind.Text,
+          mentions: [],
+          mention_all: false,
+          scheme: EncryptionScheme.None,
+          nonce: [],
+          device_envelopes: [],
+        }))
+      });
+    });
   }
   async createInviteToGroupTx(group: PublicKey, invitee: PublicKey) {
     return this._createTxWrapper(async () => 
@@ -1090,7 +1386,7 @@ export class Stem {
           {
             pubkey: this._publicKey,
             isSigner: true,
-            isWritable: false,
+            isWritable: true,
           },
           {
             pubkey: group,
@@ -1102,6 +1398,11 @@ export class Stem {
             isSigner: false,
             isWritable: true,
           },
+          {
+            pubkey: helpers.getGroupMemberPda(group, invitee),
+            isSigner: false,
+            isWritable: true,
+          },
           {
             pubkey: SystemProgram.programId,
             isSigner: false,
@@ -1125,7 +1426,7 @@ export class Stem {
           {
             pubkey: this._publicKey,
             isSigner: true,
-            isWritable: false,
+            isWritable: true,
           },
           {
             pubkey: group,
@@ -1133,7 +1434,7 @@ export class Stem {
             isWritable: true,
           },
           {
-            pubkey: await helpers.getDescriptorPda(this._publicKey),
+            pubkey: helpers.getGroupMemberPda(group, this._publicKey),
             isSigner: false,
             isWritable: true,
           },
@@ -1158,7 +1459,7 @@ export class Stem {
           {
             pubkey: this._publicKey,
             isSigner: true,
-            isWritable: false,
+            isWritable: true,
           },
           {
             pubkey: await helpers.getDescriptorPda(this._publicKey),
@@ -1170,6 +1471,11 @@ export class Stem {
             isSigner: false,
             isWritable: true,
           },
+          {
+            pubkey: helpers.getGroupMemberPda(group, this._publicKey),
+            isSigner: false,
+            isWritable: true,
+          },
           {
             pubkey: SystemProgram.programId,
             isSigner: false,
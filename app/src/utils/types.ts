@@ -33,6 +33,12 @@ export enum GroupPeerStatus {
   Kicked = 4,
 }
 
+export enum GroupRole {
+  Member = 0,
+  Admin = 1,
+  Owner = 2,
+}
+
 export type Peer = {
   pubkey: PublicKey;
   status: PeerStatus;
@@ -40,11 +46,13 @@ export type Peer = {
 
 export type PeerAccount = {
   account: Account | null;
+  pages: Account[]; // message pages of the chat, by page index
   peer: Descriptor,
   status: PeerStatus;
 };
 export type GroupAccount = {
   account: Account | null;
+  pages: Account[]; // message pages of the group, by page index
   state: GroupPeerStatus;
 };
 
@@ -61,26 +69,56 @@ export type ChatMetadata = Record<string, {
 
 export type Descriptor = {
   pubkey: Uint8Array;
-  peers: Peer[];
-  groups: Peer[];
+  peer_count: number;
+  group_count: number;
+  created_groups: bigint;
 };
 
-export type PeerBorsh = {
+export type KeyRecordBorsh = {
   pubkey: Uint8Array;
-  status: PeerStatus;
+  valid_from: bigint;
+  valid_from_slot: bigint;
 };
 
-export type GroupBorsh = {
-  account: Uint8Array;
-  state: GroupPeerStatus;
+export type DescriptorBorsh = Descriptor & {
+  key_history: KeyRecordBorsh[];
 };
 
-export type DescriptorBorsh = {
-  pubkey: Uint8Array;
-  peers: PeerBorsh[];
-  groups: GroupBorsh[];
+// Contact is a wallet's relationship with a peer, GroupMember its membership in a group.
+export type ContactBorsh = {
+  wallet: Uint8Array;
+  peer: Uint8Array;
+  state: PeerStatus;
+};
+
+export type GroupMemberBorsh = {
+  group: Uint8Array;
+  wallet: Uint8Array;
+  state: GroupPeerStatus;
+  role: GroupRole;
+  joined_at: bigint;
 };
 
+export enum EncryptionScheme {
+  None = 0,
+  X25519AesCbc = 1,
+  X25519XChaCha20Poly1305 = 2,
+  GroupEpochAead = 3,
+}
+
+export enum MessageKind {
+  Text = 0,
+  System = 1,
+  Attachment = 2,
+  Payment = 3,
+  Poll = 4,
+}
+
+export enum ChatMode {
+  Paged = 0,
+  Ring = 1,
+}
+
 export type Message = {
   readonly id: string;
   readonly index: number;
@@ -116,18 +154,77 @@ export type MessageBorsh = {
 
 export type ChatBorsh = {
   readonly wallets: Uint8Array[];
+  readonly message_count: bigint;
+  readonly current_page: number;
+  readonly page_length: number;
+  readonly mode: ChatMode;
+  readonly close_votes: number;
+  readonly pinned_count: number;
+  readonly deposits: bigint[];
+  readonly close_refund_to: Uint8Array;
+  readonly pinned: bigint[];
+  readonly message_ttl: bigint;
+  readonly allowed_schemes: number;
+  readonly legacy_messages: number;
+  readonly open_pages: number;
+  readonly closing: number;
+  readonly prekey_sessions: number;
+  readonly generation: bigint;
+  readonly event_count: bigint;
+};
+
+export type MessagePageBorsh = {
+  readonly chat: Uint8Array;
+  readonly index: number;
   readonly length: number;
-  readonly messages: MessageBorsh[];
+  readonly count: number;
+};
+
+export type MessageHeaderBorsh = {
+  readonly seq: bigint;
+  readonly timestamp: bigint;
+  readonly expires_at: bigint;
+  readonly edited_at: bigint;
+  readonly sender: Uint8Array;
+  readonly previous_hash: Uint8Array;
+  readonly key_epoch: number;
+  readonly body_length: number;
+  readonly flags: number;
+  readonly scheme: EncryptionScheme;
+  readonly kind: MessageKind;
+  readonly kind_version: number;
+};
+
+export type DeviceEnvelopeBorsh = {
+  readonly device: Uint8Array;
+  readonly envelope: Uint8Array;
+};
+
+export type MessageBodyBorsh = {
+  readonly nonce: Uint8Array;
+  readonly device_envelopes: DeviceEnvelopeBorsh[];
+  readonly content: Uint8Array;
+  readonly attachment: unknown | null;
+  readonly reply_to: { seq: bigint; chat: Uint8Array | null } | null;
 };
 
 export type GroupDescriptorBorsh = {
-  readonly title: Uint8Array;
-  readonly description: Uint8Array;
-  readonly image_url: Uint8Array;
   readonly owner: Uint8Array;
+  readonly message_count: bigint;
+  readonly message_ttl: bigint;
+  readonly pinned: bigint[];
+  readonly current_page: number;
+  readonly page_length: number;
+  readonly member_count: number;
+  readonly key_epoch: number;
   readonly group_type: number;
   readonly state: number;
-  readonly members: GroupBorsh[];
-  readonly length: number;
-  readonly messages: MessageBorsh[];
+  readonly mode: ChatMode;
+  readonly pinned_count: number;
+  readonly allowed_schemes: number;
+  readonly legacy_messages: number;
+  readonly event_count: bigint;
+  readonly title: Uint8Array;
+  readonly description: Uint8Array;
+  readonly image_url: Uint8Array;
 };
\ No newline at end of file
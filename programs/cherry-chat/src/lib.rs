@@ -1,5 +1,12 @@
+// Anchor's `realloc` constraint still expands to the deprecated AccountInfo::realloc.
+#![allow(deprecated)]
+
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::hash::{hash};
+use anchor_lang::solana_program::ed25519_program;
+use anchor_lang::solana_program::sysvar::instructions::{self as instructions_sysvar, load_current_index_checked, load_instruction_at_checked};
+use anchor_lang::system_program::{allocate, assign, create_account, transfer, Allocate, Assign, CreateAccount, Transfer};
+use curve25519_dalek::montgomery::MontgomeryPoint;
 
 declare_id!("68DEzyuChhLYQjR8Ymo88JWRUh5hrPhuWHWMLBFGHzHC");
 
@@ -27,6 +34,114 @@ pub enum ErrorCode {
     GroupIsNotActive,
     #[msg("Group is not public")]
     GroupIsNotPublic,
+    #[msg("Invalid role")]
+    InvalidRole,
+    #[msg("Message page required")]
+    MessagePageRequired,
+    #[msg("Ring buffer required")]
+    RingBufferRequired,
+    #[msg("Ring buffer already enabled")]
+    RingBufferAlreadyEnabled,
+    #[msg("Invalid ring buffer capacity")]
+    InvalidRingBufferCapacity,
+    #[msg("Message too large")]
+    MessageTooLarge,
+    #[msg("Invalid attachment")]
+    InvalidAttachment,
+    #[msg("Attachments and replies are not supported in ring buffers")]
+    UnsupportedInRingBuffer,
+    #[msg("Message TTL is not supported in ring buffers")]
+    TtlUnsupportedInRingBuffer,
+    #[msg("Invalid upload length")]
+    InvalidUploadLength,
+    #[msg("Upload chunk out of bounds")]
+    UploadOutOfBounds,
+    #[msg("Upload hash mismatch")]
+    UploadHashMismatch,
+    #[msg("Refund does not match the close request")]
+    CloseRefundMismatch,
+    #[msg("Invalid refund recipient")]
+    InvalidRefundRecipient,
+    #[msg("Invalid message pages")]
+    InvalidMessagePages,
+    #[msg("Message not found")]
+    MessageNotFound,
+    #[msg("Not the message sender")]
+    NotMessageSender,
+    #[msg("Message is deleted")]
+    MessageDeleted,
+    #[msg("Not allowed to redact this message")]
+    NotAllowedToRedact,
+    #[msg("Replied message not found")]
+    ReplyNotFound,
+    #[msg("Too many reaction kinds on this message")]
+    TooManyReactionKinds,
+    #[msg("Pinned message list is full")]
+    PinListFull,
+    #[msg("Message already pinned")]
+    AlreadyPinned,
+    #[msg("Message not pinned")]
+    NotPinned,
+    #[msg("Invalid message body")]
+    InvalidMessageBody,
+    #[msg("Invalid message TTL")]
+    InvalidTtl,
+    #[msg("Nothing to purge")]
+    NothingToPurge,
+    #[msg("Too many mentions")]
+    TooManyMentions,
+    #[msg("Mentioned wallet is not a group member")]
+    InvalidMention,
+    #[msg("Wallet mentioned more than once")]
+    DuplicateMention,
+    #[msg("Invalid key epoch")]
+    InvalidKeyEpoch,
+    #[msg("Invalid key envelope")]
+    InvalidKeyEnvelope,
+    #[msg("Public key unchanged")]
+    PubkeyUnchanged,
+    #[msg("Missing proof of possession for the public key")]
+    MissingKeyProof,
+    #[msg("Invalid proof of possession for the public key")]
+    InvalidKeyProof,
+    #[msg("Too many one-time prekeys")]
+    TooManyPrekeys,
+    #[msg("Signed prekey not set")]
+    SignedPrekeyNotSet,
+    #[msg("Prekey bundle does not belong to the receiver")]
+    InvalidPrekeyBundle,
+    #[msg("Too many devices")]
+    TooManyDevices,
+    #[msg("Device already registered")]
+    DeviceAlreadyRegistered,
+    #[msg("Device not found")]
+    DeviceNotFound,
+    #[msg("Invalid device envelopes")]
+    InvalidDeviceEnvelopes,
+    #[msg("Encryption scheme not allowed in this chat")]
+    SchemeNotAllowed,
+    #[msg("Invalid nonce for the encryption scheme")]
+    InvalidNonce,
+    #[msg("Legacy messages of the chat are not migrated yet")]
+    MigrationPending,
+    #[msg("Chat does not match the legacy chat")]
+    InvalidMigration,
+    #[msg("The chat header of the page is required")]
+    ChatHeaderRequired,
+    #[msg("Chat is being closed")]
+    ChatClosing,
+    #[msg("The wallet refunded for legacy data is missing")]
+    RefundAccountRequired,
+    #[msg("Counter underflow")]
+    CounterUnderflow,
+    #[msg("Invalid chat account")]
+    InvalidChatAccount,
+    #[msg("Chat account is still in use")]
+    ChatAccountInUse,
+    #[msg("Generation does not match the chat")]
+    InvalidGeneration,
+    #[msg("Session of the wallet in this chat is already established")]
+    SessionEstablished,
 }
 
 fn get_hash(a: Pubkey, b: Pubkey) -> [u8; 32] {
@@ -47,661 +162,4076 @@ fn get_hash(a: Pubkey, b: Pubkey) -> [u8; 32] {
     }
 
     let h = hash(c.as_ref());
-    h.to_bytes().try_into().unwrap()
+    h.to_bytes()
 }
 
-#[program]
-pub mod cherry_chat {
-    use super::*;
-    pub fn register(ctx: Context<Register>, public_key: [u8; 32]) -> Result<()> {   
-        let descriptor = &mut ctx.accounts.wallet_descriptor;
-        let payer = &mut ctx.accounts.payer;
+// Statement signed with the x25519 key to bind it to the wallet, domain separated from other signatures.
+fn key_statement(wallet: Pubkey, public_key: [u8; 32]) -> Vec<u8> {
+    [KEY_STATEMENT_DOMAIN, wallet.as_ref(), public_key.as_ref()].concat()
+}
 
-        descriptor.pubkey = public_key;
-        descriptor.peers = vec![];
-        descriptor.groups = vec![];
+// Statement signed with the identity key over a signed prekey, so senders can trust the bundle.
+fn prekey_statement(wallet: Pubkey, prekey_id: u32, prekey: [u8; 32]) -> Vec<u8> {
+    [PREKEY_STATEMENT_DOMAIN, wallet.as_ref(), prekey_id.to_le_bytes().as_ref(), prekey.as_ref()].concat()
+}
 
-        msg!("Register: {:?} with public key {:?}", payer.key(), public_key);
+// Hands the oldest one-time prekey of the receiver to the sender of the message that starts their session.
+// An empty pool still hands out the signed prekey, the session is then set up without a one-time prekey.
+fn consume_prekey(bundle: &mut PrekeyBundle, consumer: Pubkey) -> Result<()> {
+    require!(bundle.signed_prekey != [0u8; 32], ErrorCode::SignedPrekeyNotSet);
 
-        Ok(())
+    let one_time_prekey = if bundle.one_time_prekeys.is_empty() {
+        None
+    } else {
+        Some(bundle.one_time_prekeys.remove(0))
+    };
+
+    emit!(PrekeyConsumedEvent {
+        wallet: bundle.wallet,
+        consumer,
+        identity_key: bundle.identity_key,
+        signed_prekey_id: bundle.signed_prekey_id,
+        signed_prekey: bundle.signed_prekey,
+        signed_prekey_signature: bundle.signed_prekey_signature,
+        one_time_prekey,
+        remaining: bundle.one_time_prekeys.len() as u32,
+    });
+
+    Ok(())
+}
+
+fn verify_key_possession(instructions: &AccountInfo, wallet: Pubkey, public_key: [u8; 32]) -> Result<()> {
+    verify_xeddsa_statement(instructions, public_key, &key_statement(wallet, public_key))?;
+    Ok(())
+}
+
+// Checks the instruction before the current one is an Ed25519 precompile verification of the statement
+// and returns its signature.
+fn verify_xeddsa_statement(instructions: &AccountInfo, public_key: [u8; 32], statement: &[u8]) -> Result<[u8; 64]> {
+    let current = load_current_index_checked(instructions)?;
+    require!(current > 0, ErrorCode::MissingKeyProof);
+    let proof = load_instruction_at_checked(current as usize - 1, instructions)?;
+    require!(proof.program_id == ed25519_program::ID, ErrorCode::MissingKeyProof);
+
+    check_ed25519_statement(&proof.data, public_key, statement)
+}
+
+// Checks Ed25519 precompile data verifies the statement with the x25519 key and returns the signature.
+// The signer is either Edwards form of the key: an XEdDSA key has the sign bit cleared, a key converted
+// from an Ed25519 keypair (ed2curve) keeps the sign of the original.
+fn check_ed25519_statement(data: &[u8], public_key: [u8; 32], statement: &[u8]) -> Result<[u8; 64]> {
+    // num signatures, padding, then signature, public key and message offsets
+    require!(data.len() >= 16 && data[0] == 1, ErrorCode::InvalidKeyProof);
+    let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+    require!(read_u16(4) == u16::MAX && read_u16(8) == u16::MAX && read_u16(14) == u16::MAX, ErrorCode::InvalidKeyProof);
+
+    let signature_offset = read_u16(2) as usize;
+    let public_key_offset = read_u16(6) as usize;
+    let message_offset = read_u16(10) as usize;
+    let message_size = read_u16(12) as usize;
+    let signature = data.get(signature_offset..signature_offset + 64).ok_or(ErrorCode::InvalidKeyProof)?;
+    let signer = data.get(public_key_offset..public_key_offset + 32).ok_or(ErrorCode::InvalidKeyProof)?;
+    let message = data.get(message_offset..message_offset + message_size).ok_or(ErrorCode::InvalidKeyProof)?;
+
+    let montgomery = MontgomeryPoint(public_key);
+    let is_signer = |sign: u8| montgomery.to_edwards(sign).is_some_and(|key| signer == key.compress().as_bytes());
+    require!(is_signer(0) || is_signer(1), ErrorCode::InvalidKeyProof);
+    require!(message == statement, ErrorCode::InvalidKeyProof);
+
+    let mut result = [0u8; 64];
+    result.copy_from_slice(signature);
+    Ok(result)
+}
+
+// Checks the chat allows the scheme and the nonce has the length the scheme uses.
+fn validate_scheme(scheme: EncryptionScheme, nonce: &[u8], allowed_schemes: u8) -> Result<()> {
+    require!(allowed_schemes & scheme.bit() != 0, ErrorCode::SchemeNotAllowed);
+    require!(nonce.len() == scheme.nonce_length(), ErrorCode::InvalidNonce);
+    Ok(())
+}
+
+// Checks every envelope targets a distinct device of one of the registries passed for the chat.
+fn validate_device_envelopes(envelopes: &[DeviceEnvelope], encrypted: bool, registries: &[&DeviceRegistry]) -> Result<()> {
+    if envelopes.is_empty() {
+        return Ok(());
     }
+    require!(encrypted && envelopes.len() <= MAX_DEVICE_ENVELOPES, ErrorCode::InvalidDeviceEnvelopes);
 
-    pub fn invite(ctx: Context<Invite>, _hash: [u8; 32], encrypted: bool, content: Vec<u8>) -> Result<()> {
-        let inviter = &mut ctx.accounts.payer;
-        let invitee = &mut ctx.accounts.invitee;
-        let inviter_descriptor = &mut ctx.accounts.payer_descriptor;
-        let invitee_descriptor = &mut ctx.accounts.invitee_descriptor;
+    for (i, envelope) in envelopes.iter().enumerate() {
+        require!(!envelope.envelope.is_empty() && envelope.envelope.len() <= DEVICE_ENVELOPE_MAX_LENGTH, ErrorCode::InvalidDeviceEnvelopes);
+        require!(envelopes[..i].iter().all(|e| e.device != envelope.device), ErrorCode::InvalidDeviceEnvelopes);
+        require!(registries.iter().any(|r| r.devices.iter().any(|d| d.pubkey == envelope.device)), ErrorCode::DeviceNotFound);
+    }
 
-        require!(inviter_descriptor.peers.iter().all(|p| p.wallet != invitee.key()), ErrorCode::AlreadyInvited);
-        require!(invitee_descriptor.peers.iter().all(|p| p.wallet != inviter.key()), ErrorCode::AlreadyInvited);
+    Ok(())
+}
 
-        let hash = get_hash(inviter.key(), invitee.key());
-        // msg!("Hash: {:?}", hash);
-        // msg!("Hash_: {:?}", _hash);
-        require!(hash == _hash, ErrorCode::InvalidHash);
+// Checks the envelopes of a private message against the device registries of the two wallets.
+fn validate_private_device_envelopes(envelopes: &[DeviceEnvelope], encrypted: bool, wallets: &[Pubkey; 2], registries: [&Option<Account<DeviceRegistry>>; 2]) -> Result<()> {
+    let registries: Vec<&DeviceRegistry> = registries.iter().filter_map(|r| r.as_deref()).collect();
+    require!(registries.iter().all(|r| wallets.contains(&r.wallet)), ErrorCode::InvalidDeviceEnvelopes);
+    validate_device_envelopes(envelopes, encrypted, &registries)
+}
 
+// Checks the envelopes of a group message against the (DeviceRegistry, GroupMember) pairs of the joined members they target.
+fn validate_group_device_envelopes<'info>(group: Pubkey, envelopes: &[DeviceEnvelope], encrypted: bool, device_accounts: &'info [AccountInfo<'info>]) -> Result<()> {
+    if envelopes.is_empty() {
+        return Ok(());
+    }
+    require!(device_accounts.len().is_multiple_of(2), ErrorCode::InvalidDeviceEnvelopes);
 
-        inviter_descriptor.peers.push(Peer {
-            wallet: invitee.key(),
-            state: PeerState::Invited,
-        });
-        invitee_descriptor.peers.push(Peer {
-            wallet: inviter.key(),
-            state: PeerState::Requested,
-        });
-        
-        let private_chat = &mut ctx.accounts.private_chat;
-        private_chat.wallets = [inviter.key(), invitee.key()];
-        private_chat.length = 0;
-        private_chat.messages = vec![];
+    let mut registries = Vec::with_capacity(device_accounts.len() / 2);
+    for pair in device_accounts.chunks(2) {
+        let registry = Account::<DeviceRegistry>::try_from(&pair[0]).map_err(|_| error!(ErrorCode::InvalidDeviceEnvelopes))?;
+        let member = Account::<GroupMember>::try_from(&pair[1]).map_err(|_| error!(ErrorCode::InvalidDeviceEnvelopes))?;
+        require!(member.group == group && member.wallet == registry.wallet, ErrorCode::InvalidDeviceEnvelopes);
+        require!(member.state == GroupPeerState::Joined, ErrorCode::InvalidDeviceEnvelopes);
+        registries.push(registry);
+    }
 
-        if content.len() > 0 {
-            private_chat.messages.push(Message {
-                sender: inviter.key(),
-                encrypted: encrypted,
-                content: content.clone(),
-                timestamp: Clock::get().unwrap().unix_timestamp,
-            });
+    let registries: Vec<&DeviceRegistry> = registries.iter().map(|r| &**r).collect();
+    validate_device_envelopes(envelopes, encrypted, &registries)
+}
 
-            let message_length = 32 + 4 + private_chat.messages.last().unwrap().content.len() as u32 + 8 + 1;
-            private_chat.length += message_length;
-        }
+// Index of the page the next message goes to: the current one until it uses MESSAGE_PAGE_SLOTS slots.
+fn next_page(current_page: u32, page_length: u32) -> u32 {
+    if page_length >= MESSAGE_PAGE_SLOTS {
+        current_page + 1
+    } else {
+        current_page
+    }
+}
 
-        msg!("PrivateInvite: sender={:?}, target={:?}, chat={:?}", 
-             inviter.key(), invitee.key(), get_hash(inviter.key(), invitee.key()));
+// Resizes a program owned account, topping up rent from the payer or refunding the excess to it.
+fn resize_account<'info>(account: &AccountInfo<'info>, payer: &AccountInfo<'info>, system_program: &AccountInfo<'info>, new_len: usize) -> Result<()> {
+    let required = Rent::get()?.minimum_balance(new_len);
+    let current = account.lamports();
 
-        Ok(())
+    if required > current {
+        transfer(CpiContext::new(system_program.clone(), Transfer {
+            from: payer.clone(),
+            to: account.clone(),
+        }), required - current)?;
+    } else if current > required {
+        **account.try_borrow_mut_lamports()? -= current - required;
+        **payer.try_borrow_mut_lamports()? += current - required;
     }
-    
-    pub fn accept(ctx: Context<Accept>) -> Result<()> {
-        let me = &mut ctx.accounts.payer;
-        let peer = &mut ctx.accounts.peer;
-        let me_descriptor = &mut ctx.accounts.payer_descriptor;
-        let peer_descriptor = &mut ctx.accounts.peer_descriptor;
 
-        require!(me_descriptor.peers.iter().find(|p| p.wallet == peer.key() && p.state == PeerState::Requested).is_some(), ErrorCode::NotRequested);
-        require!(peer_descriptor.peers.iter().find(|p| p.wallet == me.key() && p.state == PeerState::Invited).is_some(), ErrorCode::NotInvited);
-        
-        for p in me_descriptor.peers.iter_mut() {
-            if p.wallet == peer.key() {
-                p.state = PeerState::Accepted;
-                break;
-            }
-        }
-        for p in peer_descriptor.peers.iter_mut() {
-            if p.wallet == me.key() {
-                p.state = PeerState::Accepted;
-                break;
-            }
+    account.resize(new_len)?;
+
+    Ok(())
+}
+
+// Closes a program owned account, moving its lamports to the destination.
+fn close_account<'info>(account: &AccountInfo<'info>, destination: &AccountInfo<'info>) -> Result<()> {
+    move_lamports(account, destination, account.lamports())?;
+    account.assign(&System::id());
+    account.resize(0)?;
+
+    Ok(())
+}
+
+fn move_lamports<'info>(from: &AccountInfo<'info>, to: &AccountInfo<'info>, lamports: u64) -> Result<()> {
+    **from.try_borrow_mut_lamports()? -= lamports;
+    **to.try_borrow_mut_lamports()? += lamports;
+
+    Ok(())
+}
+
+// Creates a program owned PDA, like Anchor's `init` it also takes over an address that was already funded.
+fn create_pda_account<'info>(account: &AccountInfo<'info>, payer: &AccountInfo<'info>, system_program: &AccountInfo<'info>, space: usize, signer_seeds: &[&[u8]]) -> Result<()> {
+    let required = Rent::get()?.minimum_balance(space);
+    let current = account.lamports();
+
+    if current == 0 {
+        create_account(CpiContext::new_with_signer(system_program.clone(), CreateAccount {
+            from: payer.clone(),
+            to: account.clone(),
+        }, &[signer_seeds]), required, space as u64, &crate::ID)?;
+    } else {
+        if required > current {
+            transfer(CpiContext::new(system_program.clone(), Transfer {
+                from: payer.clone(),
+                to: account.clone(),
+            }), required - current)?;
         }
+        allocate(CpiContext::new_with_signer(system_program.clone(), Allocate {
+            account_to_allocate: account.clone(),
+        }, &[signer_seeds]), space as u64)?;
+        assign(CpiContext::new_with_signer(system_program.clone(), Assign {
+            account_to_assign: account.clone(),
+        }, &[signer_seeds]), &crate::ID)?;
+    }
 
-        msg!("PrivateAccept: accepter={:?}, inviter={:?}, chat={:?}", 
-             me.key(), peer.key(), get_hash(me.key(), peer.key()));
+    Ok(())
+}
 
-        Ok(())
+// Returns the page the next message is written to, creating it on its first message,
+// and the lamports it held before this instruction.
+fn open_message_page<'info>(page: &AccountInfo<'info>, chat: [u8; 32], index: u32, bump: u8, payer: &AccountInfo<'info>, system_program: &AccountInfo<'info>) -> Result<u64> {
+    if page.owner == &crate::ID {
+        let data = page.try_borrow_data()?;
+        require!(data.len() >= MESSAGE_PAGE_SIZE && &data[..8] == MessagePage::DISCRIMINATOR, ErrorCode::InvalidMessagePages);
+        return Ok(page.lamports());
     }
 
-    pub fn reject(ctx: Context<Reject>) -> Result<()> {
-        let me = &mut ctx.accounts.payer;
-        let peer = &mut ctx.accounts.peer;
-        let me_descriptor = &mut ctx.accounts.payer_descriptor;
-        let peer_descriptor = &mut ctx.accounts.peer_descriptor;
+    create_pda_account(page, payer, system_program, MESSAGE_PAGE_SIZE,
+        &[b"message_page", chat.as_ref(), index.to_le_bytes().as_ref(), MESSAGE_VERSION.as_ref(), &[bump]])?;
 
-        require!(me_descriptor.peers.iter().find(|p| p.wallet == peer.key() && p.state == PeerState::Requested).is_some(), ErrorCode::NotRequested);
-        require!(peer_descriptor.peers.iter().find(|p| p.wallet == me.key() && p.state == PeerState::Invited).is_some(), ErrorCode::NotInvited);
+    let mut data = page.try_borrow_mut_data()?;
+    data[..8].copy_from_slice(MessagePage::DISCRIMINATOR);
+    let (header, _) = message_page_mut(&mut data);
+    header.chat = chat;
+    header.index = index;
 
-        for p in me_descriptor.peers.iter_mut() {
-            if p.wallet == peer.key() {
-                p.state = PeerState::Rejected;
-                break;
-            }
-        }
-        for p in peer_descriptor.peers.iter_mut() {
-            if p.wallet == me.key() {
-                p.state = PeerState::Rejected;
-                break;
-            }
-        }
+    Ok(0)
+}
 
-        msg!("PrivateReject: rejecter={:?}, inviter={:?}, chat={:?}", 
-             me.key(), peer.key(), get_hash(me.key(), peer.key()));
+// Splits the data of a message page account into its header and the slots that follow it.
+fn message_page(data: &[u8]) -> (&MessagePage, &[u8]) {
+    let (header, slots) = data[8..].split_at(MESSAGE_PAGE_SIZE - 8);
+    (bytemuck::from_bytes(header), slots)
+}
 
-        Ok(())
+fn message_page_mut(data: &mut [u8]) -> (&mut MessagePage, &mut [u8]) {
+    let (header, slots) = data[8..].split_at_mut(MESSAGE_PAGE_SIZE - 8);
+    (bytemuck::from_bytes_mut(header), slots)
+}
+
+// Walks the headers of a page to the message with the given seq, returns its offset in the slots.
+fn find_message(slots: &[u8], seq: u64) -> Option<(usize, MessageHeader)> {
+    let mut offset = 0;
+    while offset + MESSAGE_HEADER_SIZE <= slots.len() {
+        let header: MessageHeader = bytemuck::pod_read_unaligned(&slots[offset..offset + MESSAGE_HEADER_SIZE]);
+        if header.seq == seq {
+            return Some((offset, header));
+        }
+        if header.seq > seq {
+            break;
+        }
+        offset += header.slots() * MESSAGE_SLOT_SIZE;
     }
 
-    pub fn sendmessage(ctx: Context<SendMessage>, _hash: [u8; 32], encrypted: bool, content: Vec<u8>) -> Result<()> {
-        let payer = &mut ctx.accounts.payer;
-        let private_chat = &mut ctx.accounts.private_chat;
+    None
+}
 
-        require!(private_chat.wallets.iter().find(|w| *w == &payer.key()).is_some(), ErrorCode::NotInChat);
+// Reads the message with the given seq, with its offset in the slots and the slots it spans.
+fn read_message(page: &AccountInfo, seq: u64) -> Result<(usize, usize, Message)> {
+    let data = page.try_borrow_data()?;
+    let (_, slots) = message_page(&data);
+    let (offset, header) = find_message(slots, seq).ok_or(ErrorCode::MessageNotFound)?;
+    let span = header.slots();
 
-        let current_timestamp = Clock::get().unwrap().unix_timestamp;
+    Ok((offset, span, Message::from_slots(&slots[offset..offset + span * MESSAGE_SLOT_SIZE])?))
+}
 
-        private_chat.messages.push(Message {
-            sender: payer.key(),
-            encrypted,
-            content,
-            timestamp: current_timestamp,
-        });
+// Appends a message to a page, the payer tops up the rent of the slots it takes. Returns the slots used.
+fn push_message<'info>(page: &AccountInfo<'info>, message: &Message, payer: &AccountInfo<'info>, system_program: &AccountInfo<'info>) -> Result<u32> {
+    let bytes = message.to_slots()?;
+    let offset = page.data_len();
+    resize_account(page, payer, system_program, offset + bytes.len())?;
 
-        let message_length = 32 + 4 + private_chat.messages.last().unwrap().content.len() as u32 + 8 + 1;
-        private_chat.length += message_length;
+    let mut data = page.try_borrow_mut_data()?;
+    data[offset..].copy_from_slice(&bytes);
+    let (header, _) = message_page_mut(&mut data);
+    header.length += message.slots() as u32;
+    header.count += 1;
 
-        // Find the receiver (the other wallet in the private chat)
-        let receiver = if private_chat.wallets[0] == payer.key() {
-            private_chat.wallets[1]
-        } else {
-            private_chat.wallets[0]
-        };
+    Ok(header.length)
+}
 
-        msg!("PrivateMessage: sender={:?}, receiver={:?}, chat={:?}", 
-             payer.key(), receiver, _hash);
+// Replaces the `span` slots at `offset` with `bytes`, shifting the messages after them and resizing the page.
+// Growth is paid by the payer, freed rent goes back to it.
+fn replace_message<'info>(page: &AccountInfo<'info>, offset: usize, span: usize, bytes: &[u8], payer: &AccountInfo<'info>, system_program: &AccountInfo<'info>) -> Result<()> {
+    let old_length = span * MESSAGE_SLOT_SIZE;
+    let data_len = page.data_len();
+    let start = MESSAGE_PAGE_SIZE + offset;
 
-        Ok(())
+    if bytes.len() > old_length {
+        resize_account(page, payer, system_program, data_len + bytes.len() - old_length)?;
+    }
+    {
+        let mut data = page.try_borrow_mut_data()?;
+        data.copy_within(start + old_length..data_len, start + bytes.len());
+        data[start..start + bytes.len()].copy_from_slice(bytes);
+        let (header, _) = message_page_mut(&mut data);
+        header.length = header.length + (bytes.len() / MESSAGE_SLOT_SIZE) as u32 - span as u32;
+    }
+    if bytes.len() < old_length {
+        resize_account(page, payer, system_program, data_len - (old_length - bytes.len()))?;
     }
 
-    pub fn create_group(ctx: Context<CreateGroup>, group_type: GroupType, title: Vec<u8>, description: Vec<u8>, image_url: Vec<u8>) -> Result<()> {
-        let payer = &mut ctx.accounts.payer;
-        let payer_descriptor = &mut ctx.accounts.payer_descriptor;
-        let group_descriptor = &mut ctx.accounts.group_descriptor;
-
-        require!(payer_descriptor.groups.iter().all(|g| g.account != payer.key()), ErrorCode::AlreadyInGroup);
-
-        group_descriptor.title = title;
-        group_descriptor.description = description;
-        group_descriptor.image_url = image_url;
-        group_descriptor.owner = payer.key();
-        group_descriptor.group_type = group_type;
-        group_descriptor.state = GroupState::Active;
-        group_descriptor.members.push(Group {
-            account: payer.key(),
-            state: GroupPeerState::Joined,
-        });
+    Ok(())
+}
 
-        payer_descriptor.groups.push(Group {
-            account: group_descriptor.key(),
-            state: GroupPeerState::Joined,
-        });
+fn group_metadata(group: &AccountInfo) -> Result<GroupMetadata> {
+    let data = group.try_borrow_data()?;
+    Ok(GroupMetadata::deserialize(&mut &data[GROUP_DESCRIPTOR_SIZE..])?)
+}
 
-        msg!("Create group: {:?}", group_descriptor.key());
+fn write_group_metadata(group: &AccountInfo, metadata: &GroupMetadata) -> Result<()> {
+    let mut data = group.try_borrow_mut_data()?;
+    metadata.serialize(&mut &mut data[GROUP_DESCRIPTOR_SIZE..])?;
+    Ok(())
+}
 
-        Ok(())
+// Header of the private chat a page belongs to, it can only be left out for the pages of the group passed along.
+fn page_chat<'a, 'info>(chat: [u8; 32], private_chat: &'a Option<AccountLoader<'info, PrivateChat>>, group_descriptor: &Option<AccountLoader<'info, GroupDescriptor>>) -> Result<Option<&'a AccountLoader<'info, PrivateChat>>> {
+    if private_chat.is_some() {
+        return Ok(private_chat.as_ref());
     }
+    let group_descriptor = group_descriptor.as_ref().ok_or(ErrorCode::ChatHeaderRequired)?;
+    require!(group_descriptor.key().to_bytes() == chat, ErrorCode::ChatHeaderRequired);
 
-    pub fn invite_to_group(ctx: Context<InviteToGroup>, invitee: Pubkey) -> Result<()> {
-        let payer = &mut ctx.accounts.payer;
-        let group_descriptor = &mut ctx.accounts.group_descriptor;
-        let invitee_descriptor = &mut ctx.accounts.invitee_descriptor;
+    Ok(None)
+}
 
-        require!(group_descriptor.state == GroupState::Active, ErrorCode::GroupIsNotActive);
-        require!(group_descriptor.owner == payer.key(), ErrorCode::YouAreNotOwner);
-        require!(group_descriptor.members.iter().all(|g| g.account != invitee), ErrorCode::AlreadyInGroup);
-        require!(invitee_descriptor.groups.iter().all(|g| g.account != group_descriptor.key()), ErrorCode::AlreadyInGroup);
+// Owner of a baseline or current group descriptor.
+fn group_owner(group: &AccountInfo) -> Result<Pubkey> {
+    require!(group.owner == &crate::ID, ErrorCode::InvalidMigration);
+    let data = group.try_borrow_data()?;
+    if data[..8] == LEGACY_GROUP_DESCRIPTOR_DISCRIMINATOR {
+        let mut owner = data.get(skip_metadata(&data, 8)?..).ok_or(ErrorCode::InvalidMigration)?;
+        return Ok(Pubkey::deserialize(&mut owner)?);
+    }
+    require!(data[..8] == GROUP_DESCRIPTOR_DISCRIMINATOR, ErrorCode::InvalidMigration);
 
-        invitee_descriptor.groups.push(Group {
-            account: group_descriptor.key(),
-            state: GroupPeerState::Invited,
-        });
+    Ok(bytemuck::from_bytes::<GroupDescriptor>(&data[8..GROUP_DESCRIPTOR_SIZE]).owner)
+}
 
-        group_descriptor.members.push(Group {
-            account: invitee,
-            state: GroupPeerState::Invited,
-        });
+// Legacy accounts are walked in place rather than deserialized, a large one would not fit the heap.
+// Offset past the Borsh Vec at `offset` whose elements take `item` bytes.
+fn skip_vec(data: &[u8], offset: usize, item: usize) -> Result<usize> {
+    let mut length = data.get(offset..).ok_or(ErrorCode::InvalidMigration)?;
+    let end = offset + 4 + u32::deserialize(&mut length)? as usize * item;
+    require!(end <= data.len(), ErrorCode::InvalidMigration);
 
-        msg!("Invite to group: {:?}", group_descriptor.key());
+    Ok(end)
+}
 
-        Ok(())
+// Offset past the title, description and image url at `offset`, laid out as GroupMetadata in both group layouts.
+fn skip_metadata(data: &[u8], offset: usize) -> Result<usize> {
+    skip_vec(data, skip_vec(data, skip_vec(data, offset, 1)?, 1)?, 1)
+}
+
+// Reads the legacy message at `offset` and returns it with the offset of the next one.
+fn read_legacy_message(account: &AccountInfo, offset: usize) -> Result<(LegacyMessage, usize)> {
+    let data = account.try_borrow_data()?;
+    let mut cursor = data.get(offset..).ok_or(ErrorCode::InvalidMigration)?;
+    let message = LegacyMessage::deserialize(&mut cursor)?;
+
+    Ok((message, data.len() - cursor.len()))
+}
+
+// Drops the bytes start..end of a legacy account and shrinks it. The rent of the dropped bytes goes back to
+// the wallets that paid for them, given as (wallet, bytes), and whatever rounding leaves stays in the account.
+fn truncate_legacy_account<'info>(account: &AccountInfo<'info>, start: usize, end: usize, refunds: &[(&AccountInfo<'info>, usize)]) -> Result<()> {
+    let length = account.data_len();
+    account.try_borrow_mut_data()?.copy_within(end..length, start);
+    account.resize(length - (end - start))?;
+
+    let rent = Rent::get()?;
+    for (wallet, bytes) in refunds {
+        let excess = account.lamports().saturating_sub(rent.minimum_balance(account.data_len()));
+        let lamports = (rent.minimum_balance(*bytes) - rent.minimum_balance(0)).min(excess);
+        move_lamports(account, wallet, lamports)?;
     }
 
-    
-    pub fn accept_invite_to_group(ctx: Context<AcceptInviteToGroup>) -> Result<()> {
-        let payer = &mut ctx.accounts.payer;
-        let group_descriptor = &mut ctx.accounts.group_descriptor;
-        let payer_descriptor = &mut ctx.accounts.payer_descriptor;
+    Ok(())
+}
 
-        require!(group_descriptor.state == GroupState::Active, ErrorCode::GroupIsNotActive);
-        require!(payer_descriptor.groups.iter().find(|g| g.account == group_descriptor.key() && g.state == GroupPeerState::Invited).is_some(), ErrorCode::NotInvited);
-        require!(group_descriptor.members.iter().find(|m| m.account == payer.key() && m.state == GroupPeerState::Invited).is_some(), ErrorCode::NotInvited);
+// Finds a refunded wallet among the remaining accounts.
+fn refund_account<'a, 'info>(accounts: &'a [AccountInfo<'info>], wallet: &Pubkey) -> Result<&'a AccountInfo<'info>> {
+    accounts.iter().find(|a| a.key == wallet && a.is_writable).ok_or(error!(ErrorCode::RefundAccountRequired))
+}
 
-        for g in payer_descriptor.groups.iter_mut() {
-            if g.account == group_descriptor.key() {
-                g.state = GroupPeerState::Joined;
-                break;
-            }
-        }
-        for m in group_descriptor.members.iter_mut() {
-            if m.account == payer.key() {
-                m.state = GroupPeerState::Joined;
-                break;
-            }
-        }
+// Rewrites a baseline group descriptor in place: the header takes the place of the owner, members and length
+// fields, the metadata keeps its layout and the legacy messages follow it. Only a member can start it.
+// The baseline does not record who paid for each member, the bytes freed go back to the owner with the rest.
+fn convert_legacy_group<'info>(group: &AccountInfo<'info>, payer: &AccountInfo<'info>, system_program: &AccountInfo<'info>, wallets: &[AccountInfo<'info>]) -> Result<()> {
+    let (header, metadata_end, messages_start) = {
+        let data = group.try_borrow_data()?;
+        let metadata_end = skip_metadata(&data, 8)?;
+        let mut fields = &data[metadata_end..];
+        let owner = Pubkey::deserialize(&mut fields)?;
+        let group_type = u8::deserialize(&mut fields)?;
+        let state = u8::deserialize(&mut fields)?;
+        let members_end = skip_vec(&data, metadata_end + 34, 33)?;
+        // LegacyGroup { account, state }
+        let joined = data[metadata_end + 38..members_end].chunks(33)
+            .filter(|m| m[32] == GroupPeerState::Joined as u8);
+        require!(owner == payer.key() || joined.clone().any(|m| m[..32] == payer.key().to_bytes()), ErrorCode::NotInGroup);
 
-        msg!("Accept invite to group: {:?}", group_descriptor.key());
+        let mut messages = data.get(members_end..).ok_or(ErrorCode::InvalidMigration)?;
+        u32::deserialize(&mut messages)?; // length
+        let mut header: GroupDescriptor = bytemuck::Zeroable::zeroed();
+        header.owner = owner;
+        header.member_count = joined.count() as u32;
+        header.group_type = group_type;
+        header.state = state;
+        header.mode = ChatMode::Paged as u8;
+        header.allowed_schemes = GROUP_DEFAULT_SCHEMES;
+        header.legacy_messages = u32::deserialize(&mut messages)?;
+        (header, metadata_end, members_end + 4)
+    };
 
-        Ok(())
+    let length = group.data_len();
+    let metadata_start = GROUP_DESCRIPTOR_SIZE;
+    let messages_target = metadata_start + metadata_end - 8;
+    if messages_target > messages_start {
+        // The header is larger than the fields it replaces, the payer tops up the rent before the data moves up.
+        resize_account(group, payer, system_program, length + messages_target - messages_start)?;
+        let mut data = group.try_borrow_mut_data()?;
+        data.copy_within(messages_start..length, messages_target);
+        data.copy_within(8..metadata_end, metadata_start);
+    } else {
+        group.try_borrow_mut_data()?.copy_within(8..metadata_end, metadata_start);
+        let owner = refund_account(wallets, &header.owner)?;
+        truncate_legacy_account(group, messages_target, messages_start, &[(owner, messages_start - messages_target)])?;
     }
-        
-    pub fn reject_invite_to_group(ctx: Context<RejectInviteToGroup>) -> Result<()> {
-        let payer = &mut ctx.accounts.payer;
-        let group_descriptor = &mut ctx.accounts.group_descriptor;
-        let payer_descriptor = &mut ctx.accounts.payer_descriptor;
 
-        require!(group_descriptor.state == GroupState::Active, ErrorCode::GroupIsNotActive);
-        require!(payer_descriptor.groups.iter().find(|g| g.account == payer.key() && g.state == GroupPeerState::Invited).is_some(), ErrorCode::NotInvited);
-        require!(group_descriptor.members.iter().find(|m| m.account == group_descriptor.key() && m.state == GroupPeerState::Invited).is_some(), ErrorCode::NotInvited);
+    let mut data = group.try_borrow_mut_data()?;
+    data[..8].copy_from_slice(&GROUP_DESCRIPTOR_DISCRIMINATOR);
+    data[8..GROUP_DESCRIPTOR_SIZE].copy_from_slice(bytemuck::bytes_of(&header));
 
-        for g in payer_descriptor.groups.iter_mut() {
-            if g.account == group_descriptor.key() {
-                g.state = GroupPeerState::Rejected;
-                break;
-            }
+    Ok(())
+}
+
+// Splits the data of a ring buffer account into its header and the slots that follow it.
+fn ring_buffer(data: &[u8]) -> (&RingBuffer, &[RingSlot]) {
+    let (header, slots) = data[8..].split_at(RING_BUFFER_SIZE - 8);
+    (bytemuck::from_bytes(header), bytemuck::cast_slice(slots))
+}
+
+fn ring_buffer_mut(data: &mut [u8]) -> (&mut RingBuffer, &mut [RingSlot]) {
+    let (header, slots) = data[8..].split_at_mut(RING_BUFFER_SIZE - 8);
+    (bytemuck::from_bytes_mut(header), bytemuck::cast_slice_mut(slots))
+}
+
+fn push_ring_message(ring_buffer: &AccountLoader<RingBuffer>, seq: u64, message: &Message) -> Result<()> {
+    let mut data = ring_buffer.as_ref().try_borrow_mut_data()?;
+    let (ring, slots) = ring_buffer_mut(&mut data);
+    ring.push(slots, seq, message)
+}
+
+// Expiry of a message sent at `timestamp` in a chat with the given TTL, 0 disables it.
+fn expires_at(timestamp: i64, ttl: i64) -> Result<Option<i64>> {
+    if ttl <= 0 {
+        return Ok(None);
+    }
+    Ok(Some(timestamp.checked_add(ttl).ok_or(ErrorCode::InvalidTtl)?))
+}
+
+// Checks the body matches the schema of its kind, plaintext bodies only as encrypted ones are opaque.
+fn validate_body(kind: MessageKind, content: &[u8], attachment: &Option<ContentRef>, encrypted: bool, max_text: usize) -> Result<()> {
+    if kind == MessageKind::Attachment {
+        require!(attachment.is_some(), ErrorCode::InvalidMessageBody);
+    }
+    if encrypted {
+        return Ok(());
+    }
+
+    // Borsh rejects invalid UTF-8 in strings and trailing bytes.
+    match kind {
+        MessageKind::Text => {
+            let body = TextBody::try_from_slice(content).map_err(|_| error!(ErrorCode::InvalidMessageBody))?;
+            require!(!body.text.is_empty() && body.text.len() <= max_text, ErrorCode::InvalidMessageBody);
         }
-        for m in group_descriptor.members.iter_mut() {
-            if m.account == payer.key() {
-                m.state = GroupPeerState::Rejected;
-                break;
-            }
+        MessageKind::System => {
+            let body = SystemBody::try_from_slice(content).map_err(|_| error!(ErrorCode::InvalidMessageBody))?;
+            require!(body.text.len() <= max_text, ErrorCode::InvalidMessageBody);
+        }
+        MessageKind::Attachment => {
+            let body = AttachmentBody::try_from_slice(content).map_err(|_| error!(ErrorCode::InvalidMessageBody))?;
+            require!(body.caption.len() <= max_text, ErrorCode::InvalidMessageBody);
         }
+        MessageKind::Payment => {
+            let body = PaymentBody::try_from_slice(content).map_err(|_| error!(ErrorCode::InvalidMessageBody))?;
+            require!(body.amount > 0, ErrorCode::InvalidMessageBody);
+            require!(body.memo.len() <= max_text, ErrorCode::InvalidMessageBody);
+        }
+        MessageKind::Poll => {
+            let body = PollBody::try_from_slice(content).map_err(|_| error!(ErrorCode::InvalidMessageBody))?;
+            require!(!body.question.is_empty() && body.question.len() <= max_text, ErrorCode::InvalidMessageBody);
+            require!(body.options.len() >= 2 && body.options.len() <= MAX_POLL_OPTIONS, ErrorCode::InvalidMessageBody);
+            require!(body.options.iter().all(|o| !o.is_empty() && o.len() <= MAX_POLL_OPTION_LENGTH), ErrorCode::InvalidMessageBody);
+        }
+    }
 
-        msg!("Reject invite to group: {:?}", group_descriptor.key());
+    Ok(())
+}
 
-        Ok(())
+// Checks every mentioned wallet is a distinct joined member, their GroupMember accounts are passed in order.
+fn validate_mentions<'info>(group: Pubkey, payer_member: &GroupMember, mentions: &[Pubkey], mention_all: bool, member_accounts: &'info [AccountInfo<'info>]) -> Result<()> {
+    require!(mentions.len() <= MAX_MENTIONS, ErrorCode::TooManyMentions);
+    require!(member_accounts.len() >= mentions.len(), ErrorCode::InvalidMention);
+    if mention_all {
+        require!(payer_member.role == GroupRole::Owner || payer_member.role == GroupRole::Admin, ErrorCode::InvalidRole);
     }
-            
-            
-    pub fn send_message_to_group(ctx: Context<SendMessageToGroup>, content: Vec<u8>) -> Result<()> {
-        let payer = &mut ctx.accounts.payer;
-        let group_descriptor = &mut ctx.accounts.group_descriptor;
 
-        require!(group_descriptor.state == GroupState::Active, ErrorCode::GroupIsNotActive);
-        require!(group_descriptor.members.iter().find(|m| m.account == payer.key() && m.state == GroupPeerState::Joined).is_some(), ErrorCode::NotInGroup);
+    for (i, (wallet, member_info)) in mentions.iter().zip(member_accounts).enumerate() {
+        require!(!mentions[..i].contains(wallet), ErrorCode::DuplicateMention);
+        let member = Account::<GroupMember>::try_from(member_info).map_err(|_| error!(ErrorCode::InvalidMention))?;
+        require!(member.group == group && member.wallet == *wallet, ErrorCode::InvalidMention);
+        require!(member.state == GroupPeerState::Joined, ErrorCode::InvalidMention);
+    }
 
-        let current_timestamp = Clock::get().unwrap().unix_timestamp;
+    Ok(())
+}
 
-        group_descriptor.messages.push(Message {
-            sender: payer.key(),
-            encrypted: false,
-            content,
-            timestamp: current_timestamp,
-        });
+// Checks the replied message exists in the page or ring buffer passed as proof.
+fn validate_reply(reply_to: &ReplyTo, chat: [u8; 32], reply_page: &Option<AccountLoader<MessagePage>>, reply_ring: &Option<AccountLoader<RingBuffer>>) -> Result<()> {
+    require!(message_exists(reply_to.chat.unwrap_or(chat), reply_to.seq, reply_page, reply_ring)?, ErrorCode::ReplyNotFound);
+    Ok(())
+}
 
-        let message_length = 32 + 4 + 1 + group_descriptor.messages.last().unwrap().content.len() as u32 + 8;
-        group_descriptor.length += message_length;
+// Whether the message is stored and not deleted in the page or ring buffer passed as proof.
+fn message_exists(chat: [u8; 32], seq: u64, page: &Option<AccountLoader<MessagePage>>, ring: &Option<AccountLoader<RingBuffer>>) -> Result<bool> {
+    if let Some(page) = page {
+        let data = page.as_ref().try_borrow_data()?;
+        let (page, slots) = message_page(&data);
+        Ok(page.chat == chat && find_message(slots, seq).is_some_and(|(_, header)| !header.is_deleted()))
+    } else if let Some(ring) = ring {
+        let data = ring.as_ref().try_borrow_data()?;
+        let (ring, slots) = ring_buffer(&data);
+        Ok(ring.chat == chat && slots.iter().any(|slot| slot.seq == seq && slot.sender != Pubkey::default()))
+    } else {
+        Ok(false)
+    }
+}
 
-        // Get all active group members for notifications (exclude sender)
-        let active_members: Vec<String> = group_descriptor.members
-            .iter()
-            .filter(|m| m.state == GroupPeerState::Joined && m.account != payer.key())
-            .map(|m| m.account.to_string())
-            .collect();
+#[program]
+pub mod cherry_chat {
+    use super::*;
+    pub fn register(ctx: Context<Register>, public_key: [u8; 32]) -> Result<()> {   
+        let descriptor = &mut ctx.accounts.wallet_descriptor;
+        let payer = &mut ctx.accounts.payer;
 
-        msg!("GroupMessage: sender={:?}, group={:?}, recipients={}", 
-             payer.key(), group_descriptor.key(), active_members.join(","));
+        verify_key_possession(&ctx.accounts.instructions, payer.key(), public_key)?;
+
+        descriptor.pubkey = public_key;
+        descriptor.peer_count = 0;
+        descriptor.group_count = 0;
+        descriptor.created_groups = 0;
+        descriptor.key_history = vec![];
+        descriptor.record_key(public_key)?;
+
+        msg!("Register: {:?} with public key {:?}", payer.key(), public_key);
 
         Ok(())
     }
-                
 
-    pub fn leave_group(ctx: Context<LeaveGroup>) -> Result<()> {
+    // Moves a baseline wallet descriptor to the current one: its peers become Contact accounts and its groups
+    // GroupMember accounts, at most `count` per call. The remaining accounts are the contact of each peer, then
+    // the descriptor and the member account of each group, in the order of the legacy vectors.
+    pub fn migrate_wallet<'info>(ctx: Context<'_, '_, 'info, 'info, MigrateWallet<'info>>, count: u32) -> Result<()> {
         let payer = &mut ctx.accounts.payer;
-        let group_descriptor = &mut ctx.accounts.group_descriptor;
-        let payer_descriptor = &mut ctx.accounts.payer_descriptor;
+        let legacy_descriptor = &mut ctx.accounts.legacy_descriptor;
+        let descriptor = &mut ctx.accounts.wallet_descriptor;
+        let payer_info = payer.to_account_info();
+        let system_info = ctx.accounts.system_program.to_account_info();
 
-        require!(group_descriptor.state == GroupState::Active, ErrorCode::GroupIsNotActive);
-        require!(group_descriptor.owner != payer.key(), ErrorCode::OwnerCannotLeave);
-        require!(payer_descriptor.groups.iter().find(|g| g.account == group_descriptor.key() && g.state == GroupPeerState::Joined).is_some(), ErrorCode::NotInGroup);
-        require!(group_descriptor.members.iter().find(|m| m.account == payer.key() && m.state == GroupPeerState::Joined).is_some(), ErrorCode::NotInGroup);
+        // Baseline keys were registered without a proof, the first batch carries one.
+        if descriptor.key_history.is_empty() {
+            verify_key_possession(&ctx.accounts.instructions, payer.key(), legacy_descriptor.pubkey)?;
+            descriptor.pubkey = legacy_descriptor.pubkey;
+            descriptor.record_key(legacy_descriptor.pubkey)?;
+        }
+        // Baseline groups were seeded by the length of the groups vector, which only grew.
+        descriptor.created_groups = descriptor.created_groups.max(legacy_descriptor.groups.len() as u64);
 
-        for g in payer_descriptor.groups.iter_mut() {
-            if g.account == group_descriptor.key() {
-                g.state = GroupPeerState::Left;
-                break;
+        let mut accounts = ctx.remaining_accounts.iter();
+        let mut peers = 0;
+        while peers < count as usize && peers < legacy_descriptor.peers.len() {
+            let peer = &legacy_descriptor.peers[peers];
+            let contact_info = accounts.next().ok_or(ErrorCode::InvalidMigration)?;
+            let seeds: &[&[u8]] = &[b"contact", payer.key.as_ref(), peer.wallet.as_ref(), CONTACT_VERSION.as_ref()];
+            let (address, bump) = Pubkey::find_program_address(seeds, &crate::ID);
+            require!(contact_info.key() == address, ErrorCode::InvalidMigration);
+
+            // The contact already exists when the two wallets invited each other again since.
+            if contact_info.owner != &crate::ID {
+                create_pda_account(contact_info, &payer_info, &system_info, CONTACT_SIZE, &[seeds, &[&[bump]]].concat())?;
+                Contact {
+                    wallet: payer.key(),
+                    peer: peer.wallet,
+                    state: peer.state.clone(),
+                }.try_serialize(&mut &mut contact_info.try_borrow_mut_data()?[..])?;
+                descriptor.peer_count += 1;
             }
+            peers += 1;
         }
-        for m in group_descriptor.members.iter_mut() {
-            if m.account == payer.key() {
-                m.state = GroupPeerState::Left;
-                break;
+
+        let mut groups = 0;
+        while peers + groups < count as usize && groups < legacy_descriptor.groups.len() {
+            let group = &legacy_descriptor.groups[groups];
+            let group_info = accounts.next().ok_or(ErrorCode::InvalidMigration)?;
+            let member_info = accounts.next().ok_or(ErrorCode::InvalidMigration)?;
+            let seeds: &[&[u8]] = &[b"group_member", group.account.as_ref(), payer.key.as_ref(), GROUP_MEMBER_VERSION.as_ref()];
+            let (address, bump) = Pubkey::find_program_address(seeds, &crate::ID);
+            require!(group_info.key() == group.account && member_info.key() == address, ErrorCode::InvalidMigration);
+
+            if member_info.owner != &crate::ID {
+                let role = if group_owner(group_info)? == payer.key() { GroupRole::Owner } else { GroupRole::Member };
+                create_pda_account(member_info, &payer_info, &system_info, GROUP_MEMBER_SIZE, &[seeds, &[&[bump]]].concat())?;
+                GroupMember {
+                    group: group.account,
+                    wallet: payer.key(),
+                    state: group.state.clone(),
+                    role,
+                    joined_at: 0, // baseline memberships did not record it
+                }.try_serialize(&mut &mut member_info.try_borrow_mut_data()?[..])?;
+                if group.state == GroupPeerState::Invited || group.state == GroupPeerState::Joined {
+                    descriptor.group_count += 1;
+                }
             }
+            groups += 1;
+        }
+        legacy_descriptor.peers.drain(..peers);
+        legacy_descriptor.groups.drain(..groups);
+
+        // The legacy account shrinks with every batch and is closed with the last one, its rent goes back to the payer.
+        if legacy_descriptor.peers.is_empty() && legacy_descriptor.groups.is_empty() {
+            legacy_descriptor.close(payer_info)?;
+        } else {
+            let space = 8 + legacy_descriptor.try_to_vec()?.len();
+            resize_account(&legacy_descriptor.to_account_info(), &payer_info, &system_info, space)?;
         }
 
-        msg!("Leave group: {:?}", group_descriptor.key());
+        msg!("WalletMigrate: {:?}, peers={}, groups={}", payer.key(), peers, groups);
 
         Ok(())
     }
-    
-    pub fn kick_from_group(ctx: Context<KickFromGroup>, target: Pubkey) -> Result<()> {
+
+    pub fn update_pubkey(ctx: Context<UpdatePubkey>, public_key: [u8; 32]) -> Result<()> {
+        let descriptor = &mut ctx.accounts.wallet_descriptor;
         let payer = &mut ctx.accounts.payer;
-        let group_descriptor = &mut ctx.accounts.group_descriptor;
-        let target_descriptor = &mut ctx.accounts.target_descriptor;
 
-        require!(group_descriptor.owner == payer.key(), ErrorCode::YouAreNotOwner);
-        require!(group_descriptor.state == GroupState::Active, ErrorCode::GroupIsNotActive);
-        require!(target_descriptor.groups.iter().find(|g| g.account == group_descriptor.key() && g.state == GroupPeerState::Joined).is_some(), ErrorCode::NotInGroup);
-        require!(group_descriptor.members.iter().find(|m| m.account == target && m.state == GroupPeerState::Joined).is_some(), ErrorCode::NotInGroup); 
-
-        for m in group_descriptor.members.iter_mut() {
-            if m.account == target {
-                m.state = GroupPeerState::Kicked;
-                break;
-            }
-        }
+        require!(descriptor.pubkey != public_key, ErrorCode::PubkeyUnchanged);
+        verify_key_possession(&ctx.accounts.instructions, payer.key(), public_key)?;
 
-        for g in target_descriptor.groups.iter_mut() {
-            if g.account == group_descriptor.key() {
-                g.state = GroupPeerState::Kicked;
-                break;
-            }
+        let previous = descriptor.pubkey;
+        descriptor.pubkey = public_key;
+        let record = descriptor.record_key(public_key)?;
+
+        // The signed prekey was signed with the previous key, the bundle is unusable until a new one is set.
+        let bundle_info = ctx.accounts.prekey_bundle.to_account_info();
+        if bundle_info.owner == &crate::ID {
+            let mut data = bundle_info.try_borrow_mut_data()?;
+            let mut bundle = PrekeyBundle::try_deserialize(&mut &data[..])?;
+            bundle.signed_prekey = [0u8; 32];
+            bundle.signed_prekey_signature = [0u8; 64];
+            bundle.try_serialize(&mut &mut data[..])?;
         }
 
-        msg!("Kick from group: {:?}", group_descriptor.key());
+        emit!(PubkeyUpdateEvent {
+            wallet: payer.key(),
+            pubkey: public_key,
+            previous,
+            valid_from: record.valid_from,
+            valid_from_slot: record.valid_from_slot,
+        });
 
         Ok(())
     }
 
-    pub fn rename_group(ctx: Context<RenameGroup>, title: Vec<u8>) -> Result<()> {
+    pub fn set_signed_prekey(ctx: Context<SetSignedPrekey>, prekey_id: u32, prekey: [u8; 32]) -> Result<()> {
         let payer = &mut ctx.accounts.payer;
-        let group_descriptor = &mut ctx.accounts.group_descriptor;
+        let descriptor = &ctx.accounts.wallet_descriptor;
+        let bundle = &mut ctx.accounts.prekey_bundle;
 
-        require!(group_descriptor.owner == payer.key(), ErrorCode::YouAreNotOwner);
-        require!(group_descriptor.state == GroupState::Active, ErrorCode::GroupIsNotActive);
+        let signature = verify_xeddsa_statement(&ctx.accounts.instructions, descriptor.pubkey,
+            &prekey_statement(payer.key(), prekey_id, prekey))?;
 
-        group_descriptor.title = title;
+        bundle.wallet = payer.key();
+        bundle.identity_key = descriptor.pubkey;
+        bundle.signed_prekey_id = prekey_id;
+        bundle.signed_prekey = prekey;
+        bundle.signed_prekey_signature = signature;
+        bundle.updated_at = Clock::get()?.unix_timestamp;
+
+        msg!("SetSignedPrekey: wallet={:?}, prekey_id={}", payer.key(), prekey_id);
 
         Ok(())
     }
 
-    pub fn close_group(ctx: Context<CloseGroup>) -> Result<()> {
+    pub fn add_one_time_prekeys(ctx: Context<AddOneTimePrekeys>, prekeys: Vec<[u8; 32]>) -> Result<()> {
         let payer = &mut ctx.accounts.payer;
-        let group_descriptor = &mut ctx.accounts.group_descriptor;
+        let bundle = &mut ctx.accounts.prekey_bundle;
 
-        require!(group_descriptor.owner == payer.key(), ErrorCode::YouAreNotOwner);
-        require!(group_descriptor.state == GroupState::Active, ErrorCode::GroupIsNotActive);
+        require!(bundle.one_time_prekeys.len() + prekeys.len() <= MAX_ONE_TIME_PREKEYS, ErrorCode::TooManyPrekeys);
+
+        for key in prekeys.iter() {
+            let id = bundle.next_prekey_id;
+            bundle.one_time_prekeys.push(OneTimePrekey { id, key: *key });
+            bundle.next_prekey_id = id.wrapping_add(1);
+        }
 
-        group_descriptor.state = GroupState::Closed;
+        msg!("AddOneTimePrekeys: wallet={:?}, added={}, available={}", 
+             payer.key(), prekeys.len(), bundle.one_time_prekeys.len());
 
         Ok(())
     }
-    
-    pub fn join_group(ctx: Context<JoinGroup>) -> Result<()> {
-        let payer = &mut ctx.accounts.payer;
-        let group_descriptor = &mut ctx.accounts.group_descriptor;
-        let payer_descriptor = &mut ctx.accounts.payer_descriptor;
 
-        require!(group_descriptor.state == GroupState::Active, ErrorCode::GroupIsNotActive);
-        require!(group_descriptor.group_type == GroupType::Public, ErrorCode::GroupIsNotPublic);
+    pub fn add_device(ctx: Context<AddDevice>, pubkey: [u8; 32]) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let registry = &mut ctx.accounts.device_registry;
 
-        require!(payer_descriptor.groups.iter().all(|g| g.account != group_descriptor.key()), ErrorCode::AlreadyInGroup);
-        require!(group_descriptor.members.iter().all(|m| m.account != payer.key()), ErrorCode::AlreadyInGroup);
+        require!(registry.devices.iter().all(|d| d.pubkey != pubkey), ErrorCode::DeviceAlreadyRegistered);
+        require!(registry.devices.len() < MAX_DEVICES, ErrorCode::TooManyDevices);
+        verify_key_possession(&ctx.accounts.instructions, payer.key(), pubkey)?;
 
-        group_descriptor.members.push(Group {
-            account: payer.key(),
-            state: GroupPeerState::Joined,
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        registry.wallet = payer.key();
+        registry.devices.push(Device {
+            pubkey,
+            added_at: current_timestamp,
         });
 
-        payer_descriptor.groups.push(Group {
-            account: group_descriptor.key(),
-            state: GroupPeerState::Joined,
+        emit!(DeviceEvent {
+            wallet: payer.key(),
+            pubkey,
+            added: true,
+            timestamp: current_timestamp,
         });
 
         Ok(())
     }
-}
 
-#[derive(Accounts)]
-pub struct Register<'info> {
-    #[account(init, payer = payer, space = 8 + 4 + 32 + (0) + 4 + (0), seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
-    pub wallet_descriptor: Account<'info, WalletDescriptor>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    pub system_program: Program<'info, System>,
-}
+    pub fn revoke_device(ctx: Context<RevokeDevice>, pubkey: [u8; 32]) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let registry = &mut ctx.accounts.device_registry;
 
-#[derive(Accounts)]
-#[instruction(_hash: [u8; 32], encrypted: bool, content: Vec<u8>)]
-pub struct Invite<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    /// CHECK: invitee is a public key
-    pub invitee: AccountInfo<'info>,
-    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump, realloc = 8 + 4 + 32 + (payer_descriptor.peers.len() + 1)*(32 + 1) + 4 + (payer_descriptor.groups.len()) * ( 32 +1 ), realloc::payer = payer, realloc::zero = true)]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
-    #[account(mut, seeds = [b"wallet_descriptor", invitee.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump, realloc = 8 + 4 + 32 + (invitee_descriptor.peers.len() + 1)*(32 + 1) + 4 + (invitee_descriptor.groups.len()) * ( 32 +1 ), realloc::payer = payer, realloc::zero = true)]
-    pub invitee_descriptor: Account<'info, WalletDescriptor>,
-    #[account(init, payer = payer, 
-        space = 8 
-        + 32*2 // wallets
-        + 4 // messages full length
-        + 4 // messages count
-        + (32 + 4 + 1 + content.len() + 8) * (if content.len() > 0 { 1 } else { 0 }), 
-        seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
-    pub private_chat: Account<'info, PrivateChat>,
-    pub system_program: Program<'info, System>,
-}
+        let index = registry.devices.iter().position(|d| d.pubkey == pubkey).ok_or(ErrorCode::DeviceNotFound)?;
+        registry.devices.remove(index);
 
-#[derive(Accounts)]
-pub struct Reject<'info> {
-    #[account(mut)]
+        emit!(DeviceEvent {
+            wallet: payer.key(),
+            pubkey,
+            added: false,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    pub fn invite(ctx: Context<Invite>, _hash: [u8; 32], scheme: EncryptionScheme, nonce: Vec<u8>, content: Vec<u8>, kind: MessageKind, device_envelopes: Vec<DeviceEnvelope>) -> Result<()> {
+        let inviter = &mut ctx.accounts.payer;
+        let invitee = &mut ctx.accounts.invitee;
+        let inviter_descriptor = &mut ctx.accounts.payer_descriptor;
+        let invitee_descriptor = &mut ctx.accounts.invitee_descriptor;
+        let inviter_contact = &mut ctx.accounts.payer_contact;
+        let invitee_contact = &mut ctx.accounts.invitee_contact;
+
+        let hash = get_hash(inviter.key(), invitee.key());
+        // msg!("Hash: {:?}", hash);
+        // msg!("Hash_: {:?}", _hash);
+        require!(hash == _hash, ErrorCode::InvalidHash);
+
+
+        inviter_contact.wallet = inviter.key();
+        inviter_contact.peer = invitee.key();
+        inviter_contact.state = PeerState::Invited;
+        inviter_descriptor.peer_count += 1;
+
+        invitee_contact.wallet = invitee.key();
+        invitee_contact.peer = inviter.key();
+        invitee_contact.state = PeerState::Requested;
+        invitee_descriptor.peer_count += 1;
+
+        if let Some(prekey_bundle) = ctx.accounts.prekey_bundle.as_mut() {
+            consume_prekey(prekey_bundle, inviter.key())?;
+        }
+        
+        let mut private_chat = ctx.accounts.private_chat.load_init()?;
+        let page_info = ctx.accounts.message_page.to_account_info();
+        private_chat.wallets = [inviter.key(), invitee.key()];
+        private_chat.message_count = 0;
+        private_chat.current_page = 0;
+        private_chat.page_length = 0;
+        private_chat.mode = ChatMode::Paged as u8;
+        private_chat.message_ttl = 0;
+        private_chat.allowed_schemes = PRIVATE_CHAT_DEFAULT_SCHEMES;
+        if ctx.accounts.prekey_bundle.is_some() {
+            private_chat.prekey_sessions = private_chat.vote_bit(inviter.key());
+        }
+        // A chat invited again after a close gets a new generation, the accounts of the old one are left behind.
+        private_chat.generation = Clock::get()?.slot;
+
+        {
+            let mut message_page = ctx.accounts.message_page.load_init()?;
+            message_page.chat = hash;
+            message_page.index = 0;
+        }
+
+        if !content.is_empty() {
+            validate_scheme(scheme, &nonce, private_chat.allowed_schemes)?;
+            validate_body(kind, &content, &None, scheme.is_encrypted(), MAX_TEXT_LENGTH)?;
+            validate_private_device_envelopes(&device_envelopes, scheme.is_encrypted(), &private_chat.wallets,
+                [&ctx.accounts.sender_devices, &ctx.accounts.receiver_devices])?;
+
+            private_chat.page_length = push_message(&page_info, &Message {
+                seq: 0,
+                sender: inviter.key(),
+                scheme,
+                nonce,
+                kind,
+                kind_version: MESSAGE_KIND_VERSION,
+                key_epoch: None,
+                device_envelopes,
+                content,
+                attachment: None,
+                reply_to: None,
+                timestamp: Clock::get()?.unix_timestamp,
+                expires_at: None,
+                edited_at: None,
+                previous_hash: None,
+                deleted: false,
+            }, &inviter.to_account_info(), &ctx.accounts.system_program.to_account_info())?;
+
+            private_chat.message_count += 1;
+        }
+
+        // The inviter paid for the chat, the first page and both contacts.
+        private_chat.close_votes = 0;
+        private_chat.deposits = [0; 2];
+        private_chat.deposit(inviter.key(), ctx.accounts.private_chat.to_account_info().lamports()
+            + page_info.lamports()
+            + inviter_contact.to_account_info().lamports()
+            + invitee_contact.to_account_info().lamports());
+
+        msg!("PrivateInvite: sender={:?}, target={:?}, chat={:?}", 
+             inviter.key(), invitee.key(), get_hash(inviter.key(), invitee.key()));
+
+        Ok(())
+    }
+    
+    pub fn accept(ctx: Context<Accept>) -> Result<()> {
+        let me = &mut ctx.accounts.payer;
+        let peer = &mut ctx.accounts.peer;
+        let me_contact = &mut ctx.accounts.payer_contact;
+        let peer_contact = &mut ctx.accounts.peer_contact;
+
+        require!(me_contact.state == PeerState::Requested, ErrorCode::NotRequested);
+        require!(peer_contact.state == PeerState::Invited, ErrorCode::NotInvited);
+
+        me_contact.state = PeerState::Accepted;
+        peer_contact.state = PeerState::Accepted;
+
+        msg!("PrivateAccept: accepter={:?}, inviter={:?}, chat={:?}", 
+             me.key(), peer.key(), get_hash(me.key(), peer.key()));
+
+        Ok(())
+    }
+
+    pub fn reject(ctx: Context<Reject>) -> Result<()> {
+        let me = &mut ctx.accounts.payer;
+        let peer = &mut ctx.accounts.peer;
+        let me_contact = &mut ctx.accounts.payer_contact;
+        let peer_contact = &mut ctx.accounts.peer_contact;
+
+        require!(me_contact.state == PeerState::Requested, ErrorCode::NotRequested);
+        require!(peer_contact.state == PeerState::Invited, ErrorCode::NotInvited);
+
+        me_contact.state = PeerState::Rejected;
+        peer_contact.state = PeerState::Rejected;
+
+        msg!("PrivateReject: rejecter={:?}, inviter={:?}, chat={:?}", 
+             me.key(), peer.key(), get_hash(me.key(), peer.key()));
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn sendmessage(ctx: Context<SendMessage>, _hash: [u8; 32], scheme: EncryptionScheme, nonce: Vec<u8>, content: Vec<u8>, attachment: Option<ContentRef>, reply_to: Option<ReplyTo>, kind: MessageKind, device_envelopes: Vec<DeviceEnvelope>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut private_chat = ctx.accounts.private_chat.load_mut()?;
+
+        require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+        require!(private_chat.legacy_messages == 0, ErrorCode::MigrationPending);
+        require!(private_chat.closing == 0, ErrorCode::ChatClosing);
+        if let Some(attachment) = &attachment {
+            attachment.validate()?;
+        }
+        validate_scheme(scheme, &nonce, private_chat.allowed_schemes())?;
+        validate_body(kind, &content, &attachment, scheme.is_encrypted(), MAX_TEXT_LENGTH)?;
+        if let Some(reply_to) = &reply_to {
+            validate_reply(reply_to, _hash, &ctx.accounts.reply_page, &ctx.accounts.reply_ring)?;
+        }
+        validate_private_device_envelopes(&device_envelopes, scheme.is_encrypted(), &private_chat.wallets,
+            [&ctx.accounts.sender_devices, &ctx.accounts.receiver_devices])?;
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        let seq = private_chat.message_count;
+        let message = Message {
+            seq,
+            sender: payer.key(),
+            scheme,
+            nonce,
+            kind,
+            kind_version: MESSAGE_KIND_VERSION,
+            key_epoch: None,
+            device_envelopes,
+            content,
+            attachment,
+            reply_to,
+            timestamp: current_timestamp,
+            expires_at: expires_at(current_timestamp, private_chat.message_ttl)?,
+            edited_at: None,
+            previous_hash: None,
+            deleted: false,
+        };
+
+        if private_chat.mode == ChatMode::Ring as u8 {
+            let ring_buffer = ctx.accounts.ring_buffer.as_ref().ok_or(ErrorCode::RingBufferRequired)?;
+            push_ring_message(ring_buffer, seq, &message)?;
+        } else {
+            let page_info = ctx.accounts.message_page.as_ref().ok_or(ErrorCode::MessagePageRequired)?.to_account_info();
+            let payer_info = payer.to_account_info();
+            let system_info = ctx.accounts.system_program.to_account_info();
+            let page_index = private_chat.next_page();
+            // A page created by this instruction was paid for by the sender as well.
+            let page_lamports = open_message_page(&page_info, _hash, page_index, ctx.bumps.message_page.unwrap_or_default(), &payer_info, &system_info)?;
+
+            private_chat.page_length = push_message(&page_info, &message, &payer_info, &system_info)?;
+            private_chat.current_page = page_index;
+            private_chat.deposit(payer.key(), page_info.lamports() - page_lamports);
+        }
+        private_chat.message_count += 1;
+
+        // Find the receiver (the other wallet in the private chat)
+        let receiver = if private_chat.wallets[0] == payer.key() {
+            private_chat.wallets[1]
+        } else {
+            private_chat.wallets[0]
+        };
+
+        // Each wallet starts its session once per chat, so the receiver's pool is not drained by every message.
+        if let Some(prekey_bundle) = ctx.accounts.prekey_bundle.as_mut() {
+            require!(prekey_bundle.wallet == receiver, ErrorCode::InvalidPrekeyBundle);
+            let session_bit = private_chat.vote_bit(payer.key());
+            require!(private_chat.prekey_sessions & session_bit == 0, ErrorCode::SessionEstablished);
+            private_chat.prekey_sessions |= session_bit;
+            consume_prekey(prekey_bundle, payer.key())?;
+        }
+
+        msg!("PrivateMessage: sender={:?}, receiver={:?}, chat={:?}, seq={}", 
+             payer.key(), receiver, _hash, seq);
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn emit_message(ctx: Context<EmitMessage>, _hash: [u8; 32], scheme: EncryptionScheme, nonce: Vec<u8>, content: Vec<u8>, attachment: Option<ContentRef>, kind: MessageKind, device_envelopes: Vec<DeviceEnvelope>) -> Result<()> {
+        let payer = &ctx.accounts.payer;
+        let mut private_chat = ctx.accounts.private_chat.load_mut()?;
+
+        require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+        require!(private_chat.legacy_messages == 0, ErrorCode::MigrationPending);
+        require!(private_chat.closing == 0, ErrorCode::ChatClosing);
+        if let Some(attachment) = &attachment {
+            attachment.validate()?;
+        }
+        validate_scheme(scheme, &nonce, private_chat.allowed_schemes())?;
+        validate_body(kind, &content, &attachment, scheme.is_encrypted(), MAX_TEXT_LENGTH)?;
+        validate_private_device_envelopes(&device_envelopes, scheme.is_encrypted(), &private_chat.wallets,
+            [&ctx.accounts.sender_devices, &ctx.accounts.receiver_devices])?;
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        let seq = private_chat.event_count;
+        private_chat.event_count += 1;
+
+        let receiver = if private_chat.wallets[0] == payer.key() {
+            private_chat.wallets[1]
+        } else {
+            private_chat.wallets[0]
+        };
+        drop(private_chat);
+
+        emit_cpi!(PrivateMessageEvent {
+            chat: _hash,
+            sender: payer.key(),
+            receiver,
+            seq,
+            scheme,
+            nonce,
+            kind,
+            device_envelopes,
+            content,
+            attachment,
+            timestamp: current_timestamp,
+        });
+
+        Ok(())
+    }
+
+    pub fn enable_ring_buffer(ctx: Context<EnableRingBuffer>, _hash: [u8; 32], capacity: u16) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut private_chat = ctx.accounts.private_chat.load_mut()?;
+        let mut ring_buffer = ctx.accounts.ring_buffer.load_init()?;
+
+        require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+        require!(private_chat.mode == ChatMode::Paged as u8, ErrorCode::RingBufferAlreadyEnabled);
+        require!(capacity > 0 && capacity <= MAX_RING_BUFFER_CAPACITY, ErrorCode::InvalidRingBufferCapacity);
+        require!(private_chat.message_ttl == 0, ErrorCode::TtlUnsupportedInRingBuffer);
+        require!(private_chat.legacy_messages == 0, ErrorCode::MigrationPending);
+        require!(private_chat.closing == 0, ErrorCode::ChatClosing);
+
+        ring_buffer.chat = _hash;
+        ring_buffer.head = 0;
+        ring_buffer.capacity = capacity;
+        ring_buffer.seq = private_chat.message_count;
+        private_chat.mode = ChatMode::Ring as u8;
+        private_chat.deposit(payer.key(), ctx.accounts.ring_buffer.to_account_info().lamports());
+
+        msg!("PrivateRingBuffer: sender={:?}, chat={:?}, capacity={}", 
+             payer.key(), _hash, capacity);
+
+        Ok(())
+    }
+
+    // Moves the messages of a baseline chat into pages, oldest first, at most `count` per call and within the
+    // current page. The first call creates the chat header, or merges into the chat an invite created since:
+    // the legacy messages then follow its messages. The legacy account is walked in place and shrinks with
+    // every batch, the rent of each message goes back to its sender who paid for it, and the last call closes it.
+    pub fn migrate_private_chat(ctx: Context<MigratePrivateChat>, _hash: [u8; 32], page_index: u32, count: u32) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let peer = &ctx.accounts.peer;
+        let legacy_info = ctx.accounts.legacy_chat.to_account_info();
+        let chat_info = ctx.accounts.private_chat.to_account_info();
+        let page_info = ctx.accounts.message_page.to_account_info();
+        let payer_info = payer.to_account_info();
+        let peer_info = peer.to_account_info();
+        let system_info = ctx.accounts.system_program.to_account_info();
+
+        let (wallets, pending) = {
+            let data = legacy_info.try_borrow_data()?;
+            require!(data.len() >= LEGACY_MESSAGES_OFFSET + 4 && &data[..8] == PrivateChat::DISCRIMINATOR, ErrorCode::InvalidMigration);
+            (<[Pubkey; 2]>::deserialize(&mut &data[8..])?, u32::deserialize(&mut &data[LEGACY_MESSAGES_OFFSET..])?)
+        };
+        require!(wallets.contains(&payer.key()), ErrorCode::NotInChat);
+        require!(wallets.contains(&peer.key()) && peer.key() != payer.key(), ErrorCode::NotInChat);
+
+        let fresh = chat_info.try_borrow_data()?[..8] == [0; 8];
+        let mut private_chat = if fresh { ctx.accounts.private_chat.load_init()? } else { ctx.accounts.private_chat.load_mut()? };
+        if fresh {
+            private_chat.wallets = wallets;
+            private_chat.message_count = 0;
+            private_chat.current_page = 0;
+            private_chat.page_length = 0;
+            private_chat.mode = ChatMode::Paged as u8;
+            private_chat.close_votes = 0;
+            private_chat.deposits = [0; 2];
+            private_chat.message_ttl = 0;
+            private_chat.allowed_schemes = PRIVATE_CHAT_DEFAULT_SCHEMES;
+            private_chat.legacy_messages = pending;
+            private_chat.generation = Clock::get()?.slot;
+            private_chat.deposit(payer.key(), chat_info.lamports());
+        } else if private_chat.legacy_messages == 0 {
+            // A ring buffer keeps no history to merge the legacy messages into.
+            require!(private_chat.mode == ChatMode::Paged as u8, ErrorCode::InvalidMigration);
+            private_chat.legacy_messages = pending;
+        }
+        require!(private_chat.legacy_messages == pending, ErrorCode::InvalidMigration);
+
+        require!(private_chat.closing == 0, ErrorCode::ChatClosing);
+
+        // The page is opened even for a chat without messages, closing it expects every page up to the current one.
+        require!(page_index == private_chat.next_page(), ErrorCode::InvalidMessagePages);
+        let page_lamports = open_message_page(&page_info, _hash, page_index, ctx.bumps.message_page, &payer_info, &system_info)?;
+
+        let mut offset = LEGACY_MESSAGES_OFFSET + 4;
+        let (mut payer_bytes, mut peer_bytes) = (0, 0);
+        let mut moved = 0;
+        while moved < count && moved < pending && private_chat.next_page() == page_index {
+            let (legacy, end) = read_legacy_message(&legacy_info, offset)?;
+            let message = Message {
+                seq: private_chat.message_count,
+                sender: legacy.sender,
+                scheme: if legacy.encrypted { EncryptionScheme::X25519AesCbc } else { EncryptionScheme::None },
+                nonce: vec![],
+                kind: MessageKind::Text,
+                kind_version: 0, // legacy content is the raw text, not a TextBody
+                key_epoch: None,
+                device_envelopes: vec![],
+                content: legacy.content,
+                attachment: None,
+                reply_to: None,
+                timestamp: legacy.timestamp,
+                expires_at: None,
+                edited_at: None,
+                previous_hash: None,
+                deleted: false,
+            };
+
+            private_chat.page_length = push_message(&page_info, &message, &payer_info, &system_info)?;
+            private_chat.current_page = page_index;
+            private_chat.message_count += 1;
+            if legacy.sender == peer.key() {
+                peer_bytes += end - offset;
+            } else {
+                payer_bytes += end - offset;
+            }
+            offset = end;
+            moved += 1;
+        }
+        private_chat.deposit(payer.key(), page_info.lamports() - page_lamports);
+        private_chat.legacy_messages = pending - moved;
+
+        {
+            let mut data = legacy_info.try_borrow_mut_data()?;
+            let remaining = private_chat.legacy_messages.to_le_bytes();
+            data[LEGACY_MESSAGES_OFFSET - 4..LEGACY_MESSAGES_OFFSET].copy_from_slice(&remaining); // length
+            data[LEGACY_MESSAGES_OFFSET..LEGACY_MESSAGES_OFFSET + 4].copy_from_slice(&remaining);
+        }
+        truncate_legacy_account(&legacy_info, LEGACY_MESSAGES_OFFSET + 4, offset, &[(&payer_info, payer_bytes), (&peer_info, peer_bytes)])?;
+
+        // The legacy header does not record which participant created it, its rent is split between them.
+        if private_chat.legacy_messages == 0 {
+            move_lamports(&legacy_info, &peer_info, legacy_info.lamports() / 2)?;
+            close_account(&legacy_info, &payer_info)?;
+        }
+
+        msg!("PrivateMigrate: sender={:?}, chat={:?}, messages={}, pending={}", 
+             payer.key(), _hash, moved, private_chat.legacy_messages);
+
+        Ok(())
+    }
+
+    // Closing takes several calls: the close request (a vote of both participants, or a rejection), then
+    // batches of pages closed top-down into the chat header, then the contacts, the ring buffer and the header.
+    // Remaining accounts are the next pages to close, highest index first.
+    pub fn close_private_chat<'info>(ctx: Context<'_, '_, 'info, 'info, ClosePrivateChat<'info>>, _hash: [u8; 32], refund_to: Option<Pubkey>) -> Result<()> {
+        let payer = &ctx.accounts.payer;
+        let peer = &ctx.accounts.peer;
+        let payer_contact = &ctx.accounts.payer_contact;
+        let peer_contact = &ctx.accounts.peer_contact;
+        let chat_info = ctx.accounts.private_chat.to_account_info();
+
+        let (wallets, deposits, refund_key, mode, open_pages) = {
+            let mut private_chat = ctx.accounts.private_chat.load_mut()?;
+            require!(get_hash(payer.key(), peer.key()) == _hash, ErrorCode::InvalidHash);
+            require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+
+            if private_chat.closing == 0 {
+                // After a rejection the chat is closed right away and always split pro-rata,
+                // otherwise both participants have to request the close with the same refund.
+                let rejected = payer_contact.state == PeerState::Rejected || peer_contact.state == PeerState::Rejected;
+                if rejected {
+                    private_chat.close_refund_to = Pubkey::default();
+                } else {
+                    let vote = private_chat.vote_bit(payer.key());
+                    let refund_key = refund_to.unwrap_or_default();
+
+                    if private_chat.close_votes & !vote == 0 {
+                        private_chat.close_votes = vote;
+                        private_chat.close_refund_to = refund_key;
+
+                        msg!("PrivateCloseRequest: sender={:?}, chat={:?}, refund_to={:?}", 
+                             payer.key(), _hash, refund_to);
+
+                        return Ok(());
+                    }
+                    require!(private_chat.close_refund_to == refund_key, ErrorCode::CloseRefundMismatch);
+                }
+                require!(private_chat.legacy_messages == 0, ErrorCode::MigrationPending);
+                private_chat.closing = 1;
+                private_chat.open_pages = private_chat.current_page + 1;
+            }
+
+            // Collect the rent of the pages into the header, the last page first.
+            require!(ctx.remaining_accounts.len() <= private_chat.open_pages as usize, ErrorCode::InvalidMessagePages);
+            for page_info in ctx.remaining_accounts.iter() {
+                let index = private_chat.open_pages - 1;
+                require!(page_info.owner == &crate::ID && page_info.is_writable, ErrorCode::InvalidMessagePages);
+                {
+                    let data = page_info.try_borrow_data()?;
+                    require!(data.len() >= MESSAGE_PAGE_SIZE, ErrorCode::InvalidMessagePages);
+                    require!(&data[..8] == MessagePage::DISCRIMINATOR, ErrorCode::InvalidMessagePages);
+                    require!(data[8..40] == _hash, ErrorCode::InvalidMessagePages);
+                    require!(data[40..44] == index.to_le_bytes(), ErrorCode::InvalidMessagePages);
+                }
+                close_account(page_info, &chat_info)?;
+                private_chat.open_pages = index;
+            }
+
+            (private_chat.wallets, private_chat.deposits, private_chat.close_refund_to, private_chat.mode, private_chat.open_pages)
+        };
+
+        if open_pages > 0 {
+            msg!("PrivateClosePages: sender={:?}, chat={:?}, pending={}", payer.key(), _hash, open_pages);
+
+            return Ok(());
+        }
+
+        payer_contact.close(chat_info.clone())?;
+        peer_contact.close(chat_info.clone())?;
+        if mode == ChatMode::Ring as u8 {
+            let ring_buffer = ctx.accounts.ring_buffer.as_ref().ok_or(ErrorCode::RingBufferRequired)?;
+            ring_buffer.close(chat_info.clone())?;
+        }
+
+        let payer_info = payer.to_account_info();
+        let peer_info = peer.to_account_info();
+        let wallet_info = |wallet: Pubkey| if wallet == payer.key() { &payer_info } else { &peer_info };
+        let total = chat_info.lamports();
+
+        if refund_key == payer.key() || refund_key == peer.key() {
+            close_account(&chat_info, wallet_info(refund_key))?;
+        } else if refund_key != Pubkey::default() {
+            let recipient_info = ctx.accounts.refund_recipient.as_ref().ok_or(ErrorCode::InvalidRefundRecipient)?;
+            require!(recipient_info.key() == refund_key, ErrorCode::InvalidRefundRecipient);
+            close_account(&chat_info, recipient_info)?;
+        } else {
+            let deposited = deposits[0] as u128 + deposits[1] as u128;
+            let first_share = (total as u128 * deposits[0] as u128)
+                .checked_div(deposited)
+                .map_or(total / 2, |share| share as u64);
+            move_lamports(&chat_info, wallet_info(wallets[0]), first_share)?;
+            close_account(&chat_info, wallet_info(wallets[1]))?;
+        }
+
+        for descriptor in [&mut ctx.accounts.payer_descriptor, &mut ctx.accounts.peer_descriptor] {
+            descriptor.peer_count = descriptor.peer_count.checked_sub(1).ok_or(ErrorCode::CounterUnderflow)?;
+        }
+
+        msg!("PrivateClose: sender={:?}, peer={:?}, chat={:?}, refunded={}", 
+             payer.key(), peer.key(), _hash, total);
+
+        Ok(())
+    }
+
+    // Reactions, read markers and upload buffers are seeded by the chat generation and outlive it. They are
+    // stale once their private chat is closing, closed or invited again, once their group is closed, and for
+    // reactions once their message is deleted in the page passed along. Anyone can close stale accounts,
+    // remaining accounts are pairs of such an account and the wallet recorded in it, which gets the rent back.
+    pub fn close_chat_accounts<'info>(ctx: Context<'_, '_, 'info, 'info, CloseChatAccounts<'info>>, chat: [u8; 32]) -> Result<()> {
+        let payer = &ctx.accounts.payer;
+
+        // Generation whose accounts are still in use, None once the chat is going away.
+        let generation = if let Some(private_chat) = &ctx.accounts.private_chat {
+            let data = private_chat.try_borrow_data()?;
+            if private_chat.owner == &crate::ID && data.len() >= PRIVATE_CHAT_SIZE && &data[..8] == PrivateChat::DISCRIMINATOR {
+                let header: &PrivateChat = bytemuck::from_bytes(&data[8..PRIVATE_CHAT_SIZE]);
+                (header.closing == 0).then_some(header.generation)
+            } else {
+                None
+            }
+        } else {
+            let group_descriptor = ctx.accounts.group_descriptor.as_ref().ok_or(ErrorCode::ChatHeaderRequired)?;
+            require!(group_descriptor.key().to_bytes() == chat, ErrorCode::ChatHeaderRequired);
+            group_descriptor.load()?.is_active().then_some(0)
+        };
+        let deleted = |seq: u64| -> Result<bool> {
+            let Some(page) = &ctx.accounts.message_page else { return Ok(false) };
+            let data = page.as_ref().try_borrow_data()?;
+            let (page, slots) = message_page(&data);
+            Ok(page.chat == chat && find_message(slots, seq).is_some_and(|(_, header)| header.is_deleted()))
+        };
+
+        require!(ctx.remaining_accounts.len().is_multiple_of(2), ErrorCode::InvalidChatAccount);
+        for pair in ctx.remaining_accounts.chunks(2) {
+            let (account, wallet) = (&pair[0], &pair[1]);
+            require!(account.owner == &crate::ID && account.is_writable && wallet.is_writable, ErrorCode::InvalidChatAccount);
+
+            let (account_chat, account_wallet, account_generation, seq) = {
+                let data = account.try_borrow_data()?;
+                let discriminator = data.get(..8).ok_or(ErrorCode::InvalidChatAccount)?;
+                if discriminator == MessageReactions::DISCRIMINATOR {
+                    let reactions = MessageReactions::try_deserialize(&mut &data[..])?;
+                    (reactions.chat, reactions.payer, reactions.generation, Some(reactions.seq))
+                } else if discriminator == Reaction::DISCRIMINATOR {
+                    let reaction = Reaction::try_deserialize(&mut &data[..])?;
+                    (reaction.chat, reaction.wallet, reaction.generation, Some(reaction.seq))
+                } else if discriminator == ReadMarker::DISCRIMINATOR {
+                    let read_marker = ReadMarker::try_deserialize(&mut &data[..])?;
+                    (read_marker.chat, read_marker.wallet, read_marker.generation, None)
+                } else if discriminator == UploadBuffer::DISCRIMINATOR {
+                    let upload_buffer = UploadBuffer::try_deserialize(&mut &data[..])?;
+                    (upload_buffer.chat, upload_buffer.owner, upload_buffer.generation, None)
+                } else {
+                    return err!(ErrorCode::InvalidChatAccount);
+                }
+            };
+            require!(account_chat == chat && account_wallet == wallet.key(), ErrorCode::InvalidChatAccount);
+
+            let stale = generation != Some(account_generation) || seq.map(deleted).transpose()?.unwrap_or(false);
+            require!(stale, ErrorCode::ChatAccountInUse);
+            close_account(account, wallet)?;
+        }
+
+        msg!("ChatAccountsClose: cranker={:?}, chat={:?}, closed={}", 
+             payer.key(), chat, ctx.remaining_accounts.len() / 2);
+
+        Ok(())
+    }
+
+    pub fn create_group(ctx: Context<CreateGroup>, group_type: GroupType, title: Vec<u8>, description: Vec<u8>, image_url: Vec<u8>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let payer_descriptor = &mut ctx.accounts.payer_descriptor;
+        let group_key = ctx.accounts.group_descriptor.key();
+        let payer_member = &mut ctx.accounts.payer_member;
+
+        {
+            let mut group_descriptor = ctx.accounts.group_descriptor.load_init()?;
+            group_descriptor.owner = payer.key();
+            group_descriptor.group_type = group_type as u8;
+            group_descriptor.state = GroupState::Active as u8;
+            group_descriptor.member_count = 1;
+            group_descriptor.message_count = 0;
+            group_descriptor.current_page = 0;
+            group_descriptor.page_length = 0;
+            group_descriptor.mode = ChatMode::Paged as u8;
+            group_descriptor.pinned_count = 0;
+            group_descriptor.message_ttl = 0;
+            group_descriptor.key_epoch = 0;
+            group_descriptor.allowed_schemes = GROUP_DEFAULT_SCHEMES;
+        }
+        write_group_metadata(&ctx.accounts.group_descriptor.to_account_info(), &GroupMetadata {
+            title,
+            description,
+            image_url,
+        })?;
+
+        payer_member.group = group_key;
+        payer_member.wallet = payer.key();
+        payer_member.state = GroupPeerState::Joined;
+        payer_member.role = GroupRole::Owner;
+        payer_member.joined_at = Clock::get()?.unix_timestamp;
+
+        payer_descriptor.created_groups += 1;
+        payer_descriptor.group_count += 1;
+
+        msg!("Create group: {:?}", group_key);
+
+        Ok(())
+    }
+
+    pub fn invite_to_group(ctx: Context<InviteToGroup>, invitee: Pubkey) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let group_descriptor = ctx.accounts.group_descriptor.load()?;
+        let invitee_descriptor = &mut ctx.accounts.invitee_descriptor;
+        let invitee_member = &mut ctx.accounts.invitee_member;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(group_descriptor.owner == payer.key(), ErrorCode::YouAreNotOwner);
+        // The membership is kept once created, wallets that left, were kicked or rejected can be invited again.
+        if invitee_member.group != Pubkey::default() {
+            match invitee_member.state {
+                GroupPeerState::Invited => return err!(ErrorCode::AlreadyInvited),
+                GroupPeerState::Joined => return err!(ErrorCode::AlreadyInGroup),
+                GroupPeerState::Rejected | GroupPeerState::Left | GroupPeerState::Kicked => {}
+            }
+        }
+
+        invitee_member.group = ctx.accounts.group_descriptor.key();
+        invitee_member.wallet = invitee;
+        invitee_member.state = GroupPeerState::Invited;
+        invitee_member.role = GroupRole::Member;
+        invitee_member.joined_at = 0;
+        invitee_descriptor.group_count += 1;
+
+        msg!("Invite to group: {:?}", ctx.accounts.group_descriptor.key());
+
+        Ok(())
+    }
+
+    
+    pub fn accept_invite_to_group(ctx: Context<AcceptInviteToGroup>) -> Result<()> {
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+        let payer_member = &mut ctx.accounts.payer_member;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(payer_member.state == GroupPeerState::Invited, ErrorCode::NotInvited);
+
+        payer_member.state = GroupPeerState::Joined;
+        payer_member.joined_at = Clock::get()?.unix_timestamp;
+        group_descriptor.member_count += 1;
+
+        msg!("Accept invite to group: {:?}", ctx.accounts.group_descriptor.key());
+
+        Ok(())
+    }
+        
+    pub fn reject_invite_to_group(ctx: Context<RejectInviteToGroup>) -> Result<()> {
+        let group_descriptor = ctx.accounts.group_descriptor.load()?;
+        let payer_descriptor = &mut ctx.accounts.payer_descriptor;
+        let payer_member = &mut ctx.accounts.payer_member;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(payer_member.state == GroupPeerState::Invited, ErrorCode::NotInvited);
+
+        payer_member.state = GroupPeerState::Rejected;
+        payer_descriptor.group_count = payer_descriptor.group_count.checked_sub(1).ok_or(ErrorCode::CounterUnderflow)?;
+
+        msg!("Reject invite to group: {:?}", ctx.accounts.group_descriptor.key());
+
+        Ok(())
+    }
+            
+            
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_message_to_group<'info>(ctx: Context<'_, '_, 'info, 'info, SendMessageToGroup<'info>>, content: Vec<u8>, attachment: Option<ContentRef>, reply_to: Option<ReplyTo>, kind: MessageKind, mentions: Vec<Pubkey>, mention_all: bool, scheme: EncryptionScheme, nonce: Vec<u8>, device_envelopes: Vec<DeviceEnvelope>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let group_key = ctx.accounts.group_descriptor.key();
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+        let payer_member = &ctx.accounts.payer_member;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+        require!(group_descriptor.legacy_messages == 0, ErrorCode::MigrationPending);
+        if let Some(attachment) = &attachment {
+            attachment.validate()?;
+        }
+        validate_scheme(scheme, &nonce, group_descriptor.allowed_schemes)?;
+        validate_body(kind, &content, &attachment, scheme.is_encrypted(), MAX_TEXT_LENGTH)?;
+        // Remaining accounts are the GroupMember of each mention, then the device accounts of the envelopes.
+        validate_mentions(group_key, payer_member, &mentions, mention_all, ctx.remaining_accounts)?;
+        validate_group_device_envelopes(group_key, &device_envelopes, scheme.is_encrypted(), &ctx.remaining_accounts[mentions.len()..])?;
+        if let Some(reply_to) = &reply_to {
+            validate_reply(reply_to, group_key.to_bytes(), &ctx.accounts.reply_page, &ctx.accounts.reply_ring)?;
+        }
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        let seq = group_descriptor.message_count;
+        let message = Message {
+            seq,
+            sender: payer.key(),
+            scheme,
+            nonce,
+            kind,
+            kind_version: MESSAGE_KIND_VERSION,
+            key_epoch: (scheme == EncryptionScheme::GroupEpochAead).then_some(group_descriptor.key_epoch),
+            device_envelopes,
+            content,
+            attachment,
+            reply_to,
+            timestamp: current_timestamp,
+            expires_at: expires_at(current_timestamp, group_descriptor.message_ttl)?,
+            edited_at: None,
+            previous_hash: None,
+            deleted: false,
+        };
+
+        if group_descriptor.mode == ChatMode::Ring as u8 {
+            let ring_buffer = ctx.accounts.ring_buffer.as_ref().ok_or(ErrorCode::RingBufferRequired)?;
+            push_ring_message(ring_buffer, seq, &message)?;
+        } else {
+            let page_info = ctx.accounts.message_page.as_ref().ok_or(ErrorCode::MessagePageRequired)?.to_account_info();
+            let payer_info = payer.to_account_info();
+            let system_info = ctx.accounts.system_program.to_account_info();
+            let page_index = group_descriptor.next_page();
+
+            open_message_page(&page_info, group_key.to_bytes(), page_index, ctx.bumps.message_page.unwrap_or_default(), &payer_info, &system_info)?;
+
+            group_descriptor.page_length = push_message(&page_info, &message, &payer_info, &system_info)?;
+            group_descriptor.current_page = page_index;
+        }
+        group_descriptor.message_count += 1;
+
+        if mention_all || !mentions.is_empty() {
+            emit!(MentionEvent {
+                group: group_key,
+                sender: payer.key(),
+                seq,
+                event_only: false,
+                mentions,
+                all: mention_all,
+            });
+        }
+
+        msg!("GroupMessage: sender={:?}, group={:?}, seq={}", 
+             payer.key(), group_key, seq);
+
+        Ok(())
+    }
+                
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn emit_message_to_group<'info>(ctx: Context<'_, '_, 'info, 'info, EmitMessageToGroup<'info>>, content: Vec<u8>, attachment: Option<ContentRef>, kind: MessageKind, mentions: Vec<Pubkey>, mention_all: bool, scheme: EncryptionScheme, nonce: Vec<u8>, device_envelopes: Vec<DeviceEnvelope>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let group_key = ctx.accounts.group_descriptor.key();
+        let payer_member = &ctx.accounts.payer_member;
+
+        let (seq, key_epoch) = {
+            let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+            require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+            require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+            require!(group_descriptor.legacy_messages == 0, ErrorCode::MigrationPending);
+            if let Some(attachment) = &attachment {
+                attachment.validate()?;
+            }
+            validate_scheme(scheme, &nonce, group_descriptor.allowed_schemes)?;
+            validate_body(kind, &content, &attachment, scheme.is_encrypted(), MAX_TEXT_LENGTH)?;
+            validate_mentions(group_key, payer_member, &mentions, mention_all, ctx.remaining_accounts)?;
+            validate_group_device_envelopes(group_key, &device_envelopes, scheme.is_encrypted(), &ctx.remaining_accounts[mentions.len()..])?;
+
+            let seq = group_descriptor.event_count;
+            group_descriptor.event_count += 1;
+            (seq, group_descriptor.key_epoch)
+        };
+        let current_timestamp = Clock::get()?.unix_timestamp;
+
+        emit_cpi!(GroupMessageEvent {
+            group: group_key,
+            sender: payer.key(),
+            seq,
+            scheme,
+            nonce,
+            key_epoch: (scheme == EncryptionScheme::GroupEpochAead).then_some(key_epoch),
+            kind,
+            device_envelopes,
+            content,
+            attachment,
+            timestamp: current_timestamp,
+        });
+
+        if mention_all || !mentions.is_empty() {
+            emit_cpi!(MentionEvent {
+                group: group_key,
+                sender: payer.key(),
+                seq,
+                event_only: true,
+                mentions,
+                all: mention_all,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn leave_group(ctx: Context<LeaveGroup>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+        let payer_descriptor = &mut ctx.accounts.payer_descriptor;
+        let payer_member = &mut ctx.accounts.payer_member;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(group_descriptor.owner != payer.key(), ErrorCode::OwnerCannotLeave);
+        require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+
+        payer_member.state = GroupPeerState::Left;
+        group_descriptor.member_count = group_descriptor.member_count.checked_sub(1).ok_or(ErrorCode::CounterUnderflow)?;
+        payer_descriptor.group_count = payer_descriptor.group_count.checked_sub(1).ok_or(ErrorCode::CounterUnderflow)?;
+        group_descriptor.key_epoch += 1;
+
+        emit!(GroupKeyEpochEvent {
+            group: ctx.accounts.group_descriptor.key(),
+            epoch: group_descriptor.key_epoch,
+        });
+
+        msg!("Leave group: {:?}", ctx.accounts.group_descriptor.key());
+
+        Ok(())
+    }
+    
+    pub fn kick_from_group(ctx: Context<KickFromGroup>, target: Pubkey) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+        let target_descriptor = &mut ctx.accounts.target_descriptor;
+        let target_member = &mut ctx.accounts.target_member;
+
+        require!(group_descriptor.owner == payer.key(), ErrorCode::YouAreNotOwner);
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(target_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup); 
+
+        target_member.state = GroupPeerState::Kicked;
+        group_descriptor.member_count = group_descriptor.member_count.checked_sub(1).ok_or(ErrorCode::CounterUnderflow)?;
+        target_descriptor.group_count = target_descriptor.group_count.checked_sub(1).ok_or(ErrorCode::CounterUnderflow)?;
+        group_descriptor.key_epoch += 1;
+
+        emit!(GroupKeyEpochEvent {
+            group: ctx.accounts.group_descriptor.key(),
+            epoch: group_descriptor.key_epoch,
+        });
+
+        msg!("Kick from group: {:?}, member={:?}", ctx.accounts.group_descriptor.key(), target);
+
+        Ok(())
+    }
+
+    pub fn rename_group(ctx: Context<RenameGroup>, title: Vec<u8>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let group_info = ctx.accounts.group_descriptor.to_account_info();
+
+        {
+            let group_descriptor = ctx.accounts.group_descriptor.load()?;
+            require!(group_descriptor.owner == payer.key(), ErrorCode::YouAreNotOwner);
+            require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+            require!(group_descriptor.legacy_messages == 0, ErrorCode::MigrationPending);
+        }
+
+        // Only the metadata tail is resized, the header stays in place.
+        let mut metadata = group_metadata(&group_info)?;
+        resize_account(&group_info, &payer.to_account_info(), &ctx.accounts.system_program.to_account_info(),
+            group_rename_gd_realloc!(metadata, title))?;
+        metadata.title = title;
+        write_group_metadata(&group_info, &metadata)?;
+
+        Ok(())
+    }
+
+    pub fn close_group(ctx: Context<CloseGroup>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+
+        require!(group_descriptor.owner == payer.key(), ErrorCode::YouAreNotOwner);
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+
+        group_descriptor.state = GroupState::Closed as u8;
+
+        Ok(())
+    }
+    
+    pub fn join_group(ctx: Context<JoinGroup>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+        let payer_descriptor = &mut ctx.accounts.payer_descriptor;
+        let payer_member = &mut ctx.accounts.payer_member;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(group_descriptor.group_type == GroupType::Public as u8, ErrorCode::GroupIsNotPublic);
+        // Wallets that left or rejected join again, kicked ones only come back through an invite.
+        // A pending invite already counted the group for the wallet.
+        let invited = payer_member.group != Pubkey::default() && match payer_member.state {
+            GroupPeerState::Invited => true,
+            GroupPeerState::Joined => return err!(ErrorCode::AlreadyInGroup),
+            GroupPeerState::Kicked => return err!(ErrorCode::NotInvited),
+            GroupPeerState::Rejected | GroupPeerState::Left => false,
+        };
+
+        payer_member.group = ctx.accounts.group_descriptor.key();
+        payer_member.wallet = payer.key();
+        payer_member.state = GroupPeerState::Joined;
+        payer_member.role = GroupRole::Member;
+        payer_member.joined_at = Clock::get()?.unix_timestamp;
+        group_descriptor.member_count += 1;
+        if !invited {
+            payer_descriptor.group_count += 1;
+        }
+
+        Ok(())
+    }
+
+    pub fn enable_group_ring_buffer(ctx: Context<EnableGroupRingBuffer>, capacity: u16) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+        let mut ring_buffer = ctx.accounts.ring_buffer.load_init()?;
+
+        require!(group_descriptor.owner == payer.key(), ErrorCode::YouAreNotOwner);
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(group_descriptor.mode == ChatMode::Paged as u8, ErrorCode::RingBufferAlreadyEnabled);
+        require!(capacity > 0 && capacity <= MAX_RING_BUFFER_CAPACITY, ErrorCode::InvalidRingBufferCapacity);
+        require!(group_descriptor.message_ttl == 0, ErrorCode::TtlUnsupportedInRingBuffer);
+        require!(group_descriptor.legacy_messages == 0, ErrorCode::MigrationPending);
+
+        ring_buffer.chat = ctx.accounts.group_descriptor.key().to_bytes();
+        ring_buffer.head = 0;
+        ring_buffer.capacity = capacity;
+        ring_buffer.seq = group_descriptor.message_count;
+        group_descriptor.mode = ChatMode::Ring as u8;
+
+        msg!("GroupRingBuffer: group={:?}, capacity={}", ctx.accounts.group_descriptor.key(), capacity);
+
+        Ok(())
+    }
+
+    pub fn set_group_role(ctx: Context<SetGroupRole>, target: Pubkey, role: GroupRole) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let group_descriptor = ctx.accounts.group_descriptor.load()?;
+        let target_member = &mut ctx.accounts.target_member;
+
+        require!(group_descriptor.owner == payer.key(), ErrorCode::YouAreNotOwner);
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(target_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+        require!(target_member.role != GroupRole::Owner && role != GroupRole::Owner, ErrorCode::InvalidRole);
+
+        target_member.role = role;
+
+        msg!("Set group role: group={:?}, member={:?}", ctx.accounts.group_descriptor.key(), target);
+
+        Ok(())
+    }
+
+    // Rewrites a baseline group descriptor in place into the header, its metadata and the legacy messages,
+    // then moves those messages into pages, oldest first, at most `count` per call and within the current page.
+    // Only a member can start the migration, the calls after it only move messages and anyone can crank them.
+    // The descriptor shrinks with every batch, the rent it frees goes back to the wallets that paid for it:
+    // the owner for the baseline fields and the senders for their messages. Remaining accounts are those wallets.
+    pub fn migrate_group<'info>(ctx: Context<'_, '_, 'info, 'info, MigrateGroup<'info>>, page_index: u32, count: u32) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let group_info = ctx.accounts.group_descriptor.to_account_info();
+        let page_info = ctx.accounts.message_page.to_account_info();
+        let payer_info = payer.to_account_info();
+        let system_info = ctx.accounts.system_program.to_account_info();
+        let group_key = group_info.key();
+
+        let legacy = group_info.try_borrow_data()?[..8] == LEGACY_GROUP_DESCRIPTOR_DISCRIMINATOR;
+        if legacy {
+            convert_legacy_group(&group_info, &payer_info, &system_info, ctx.remaining_accounts)?;
+        }
+
+        let (mut header, messages_offset) = {
+            let data = group_info.try_borrow_data()?;
+            require!(data[..8] == GROUP_DESCRIPTOR_DISCRIMINATOR, ErrorCode::InvalidMigration);
+            let header: GroupDescriptor = *bytemuck::from_bytes(&data[8..GROUP_DESCRIPTOR_SIZE]);
+            require!(legacy || header.legacy_messages > 0, ErrorCode::InvalidMigration);
+            (header, skip_metadata(&data, GROUP_DESCRIPTOR_SIZE)?)
+        };
+
+        let mut offset = messages_offset + 4;
+        let mut refunds: Vec<(Pubkey, usize)> = vec![];
+        let mut moved = 0;
+        if header.legacy_messages > 0 {
+            require!(page_index == header.next_page(), ErrorCode::InvalidMessagePages);
+            open_message_page(&page_info, group_key.to_bytes(), page_index, ctx.bumps.message_page, &payer_info, &system_info)?;
+
+            while moved < count && moved < header.legacy_messages && header.next_page() == page_index {
+                let (legacy, end) = read_legacy_message(&group_info, offset)?;
+                let message = Message {
+                    seq: header.message_count,
+                    sender: legacy.sender,
+                    scheme: if legacy.encrypted { EncryptionScheme::X25519AesCbc } else { EncryptionScheme::None },
+                    nonce: vec![],
+                    kind: MessageKind::Text,
+                    kind_version: 0, // legacy content is the raw text, not a TextBody
+                    key_epoch: None,
+                    device_envelopes: vec![],
+                    content: legacy.content,
+                    attachment: None,
+                    reply_to: None,
+                    timestamp: legacy.timestamp,
+                    expires_at: None,
+                    edited_at: None,
+                    previous_hash: None,
+                    deleted: false,
+                };
+
+                header.page_length = push_message(&page_info, &message, &payer_info, &system_info)?;
+                header.current_page = page_index;
+                header.message_count += 1;
+                match refunds.iter_mut().find(|(wallet, _)| *wallet == legacy.sender) {
+                    Some((_, bytes)) => *bytes += end - offset,
+                    None => refunds.push((legacy.sender, end - offset)),
+                }
+                offset = end;
+                moved += 1;
+            }
+        }
+        header.legacy_messages -= moved;
+
+        {
+            let mut data = group_info.try_borrow_mut_data()?;
+            data[8..GROUP_DESCRIPTOR_SIZE].copy_from_slice(bytemuck::bytes_of(&header));
+            data[messages_offset..messages_offset + 4].copy_from_slice(&header.legacy_messages.to_le_bytes());
+        }
+        // The count in front of the legacy messages goes with the last of them.
+        let start = if header.legacy_messages == 0 { messages_offset } else { messages_offset + 4 };
+        let refunds = refunds.iter()
+            .map(|(wallet, bytes)| Ok((refund_account(ctx.remaining_accounts, wallet)?, *bytes)))
+            .collect::<Result<Vec<_>>>()?;
+        truncate_legacy_account(&group_info, start, offset, &refunds)?;
+
+        msg!("GroupMigrate: sender={:?}, group={:?}, messages={}, pending={}", 
+             payer.key(), group_key, moved, header.legacy_messages);
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_upload_buffer(ctx: Context<OpenUploadBuffer>, chat: [u8; 32], generation: u64, length: u32, scheme: EncryptionScheme, nonce: Vec<u8>, content_hash: [u8; 32], kind: MessageKind) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let upload_buffer = &mut ctx.accounts.upload_buffer;
+
+        require!(length > 0 && length as usize <= UPLOAD_BUFFER_MAX_LENGTH, ErrorCode::InvalidUploadLength);
+        // Only members can stage a body, in a scheme the chat accepts, so the rent is not locked in a buffer that cannot commit.
+        if let Some(private_chat) = &ctx.accounts.private_chat {
+            let private_chat = private_chat.load()?;
+            require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+            require!(private_chat.closing == 0, ErrorCode::ChatClosing);
+            require!(generation == private_chat.generation, ErrorCode::InvalidGeneration);
+            validate_scheme(scheme, &nonce, private_chat.allowed_schemes())?;
+        } else {
+            let group_descriptor = ctx.accounts.group_descriptor.as_ref().ok_or(ErrorCode::ChatHeaderRequired)?;
+            require!(group_descriptor.key().to_bytes() == chat, ErrorCode::ChatHeaderRequired);
+            let group_descriptor = group_descriptor.load()?;
+            let payer_member = ctx.accounts.payer_member.as_ref().ok_or(ErrorCode::NotInGroup)?;
+            require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+            require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+            require!(generation == 0, ErrorCode::InvalidGeneration);
+            validate_scheme(scheme, &nonce, group_descriptor.allowed_schemes)?;
+        }
+
+        upload_buffer.owner = payer.key();
+        upload_buffer.chat = chat;
+        upload_buffer.generation = generation;
+        upload_buffer.content_hash = content_hash;
+        upload_buffer.scheme = scheme;
+        upload_buffer.nonce = nonce;
+        upload_buffer.kind = kind;
+        upload_buffer.content = vec![0; length as usize];
+
+        msg!("UploadOpen: sender={:?}, chat={:?}, length={}", payer.key(), chat, length);
+
+        Ok(())
+    }
+
+    pub fn write_upload_chunk(ctx: Context<WriteUploadChunk>, offset: u32, data: Vec<u8>) -> Result<()> {
+        let upload_buffer = &mut ctx.accounts.upload_buffer;
+
+        let start = offset as usize;
+        let end = start.checked_add(data.len()).ok_or(ErrorCode::UploadOutOfBounds)?;
+        require!(end <= upload_buffer.content.len(), ErrorCode::UploadOutOfBounds);
+
+        upload_buffer.content[start..end].copy_from_slice(&data);
+
+        Ok(())
+    }
+
+    pub fn commit_upload(ctx: Context<CommitUpload>, _hash: [u8; 32], attachment: Option<ContentRef>, device_envelopes: Vec<DeviceEnvelope>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let upload_buffer = &mut ctx.accounts.upload_buffer;
+        let mut private_chat = ctx.accounts.private_chat.load_mut()?;
+
+        require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+        require!(private_chat.legacy_messages == 0, ErrorCode::MigrationPending);
+        require!(private_chat.closing == 0, ErrorCode::ChatClosing);
+        require!(hash(&upload_buffer.content).to_bytes() == upload_buffer.content_hash, ErrorCode::UploadHashMismatch);
+        if let Some(attachment) = &attachment {
+            attachment.validate()?;
+        }
+        validate_scheme(upload_buffer.scheme, &upload_buffer.nonce, private_chat.allowed_schemes())?;
+        validate_body(upload_buffer.kind, &upload_buffer.content, &attachment, upload_buffer.scheme.is_encrypted(), UPLOAD_BUFFER_MAX_LENGTH)?;
+        validate_private_device_envelopes(&device_envelopes, upload_buffer.scheme.is_encrypted(), &private_chat.wallets,
+            [&ctx.accounts.sender_devices, &ctx.accounts.receiver_devices])?;
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        let seq = private_chat.message_count;
+        let message = Message {
+            seq,
+            sender: payer.key(),
+            scheme: upload_buffer.scheme,
+            nonce: std::mem::take(&mut upload_buffer.nonce),
+            kind: upload_buffer.kind,
+            kind_version: MESSAGE_KIND_VERSION,
+            key_epoch: None,
+            device_envelopes,
+            content: std::mem::take(&mut upload_buffer.content),
+            attachment,
+            reply_to: None,
+            timestamp: current_timestamp,
+            expires_at: expires_at(current_timestamp, private_chat.message_ttl)?,
+            edited_at: None,
+            previous_hash: None,
+            deleted: false,
+        };
+
+        if private_chat.mode == ChatMode::Ring as u8 {
+            let ring_buffer = ctx.accounts.ring_buffer.as_ref().ok_or(ErrorCode::RingBufferRequired)?;
+            push_ring_message(ring_buffer, seq, &message)?;
+        } else {
+            let page_info = ctx.accounts.message_page.as_ref().ok_or(ErrorCode::MessagePageRequired)?.to_account_info();
+            let payer_info = payer.to_account_info();
+            let system_info = ctx.accounts.system_program.to_account_info();
+            let page_index = private_chat.next_page();
+            // A page created by this instruction was paid for by the sender as well.
+            let page_lamports = open_message_page(&page_info, _hash, page_index, ctx.bumps.message_page.unwrap_or_default(), &payer_info, &system_info)?;
+
+            private_chat.page_length = push_message(&page_info, &message, &payer_info, &system_info)?;
+            private_chat.current_page = page_index;
+            private_chat.deposit(payer.key(), page_info.lamports() - page_lamports);
+        }
+        private_chat.message_count += 1;
+
+        msg!("PrivateMessage: sender={:?}, chat={:?}, seq={}, upload={:?}", 
+             payer.key(), _hash, seq, upload_buffer.key());
+
+        Ok(())
+    }
+
+    pub fn commit_upload_to_group<'info>(ctx: Context<'_, '_, 'info, 'info, CommitUploadToGroup<'info>>, attachment: Option<ContentRef>, device_envelopes: Vec<DeviceEnvelope>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let group_key = ctx.accounts.group_descriptor.key();
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+        let payer_member = &ctx.accounts.payer_member;
+        let upload_buffer = &mut ctx.accounts.upload_buffer;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+        require!(group_descriptor.legacy_messages == 0, ErrorCode::MigrationPending);
+        require!(hash(&upload_buffer.content).to_bytes() == upload_buffer.content_hash, ErrorCode::UploadHashMismatch);
+        if let Some(attachment) = &attachment {
+            attachment.validate()?;
+        }
+        validate_scheme(upload_buffer.scheme, &upload_buffer.nonce, group_descriptor.allowed_schemes)?;
+        validate_body(upload_buffer.kind, &upload_buffer.content, &attachment, upload_buffer.scheme.is_encrypted(), UPLOAD_BUFFER_MAX_LENGTH)?;
+        validate_group_device_envelopes(group_key, &device_envelopes, upload_buffer.scheme.is_encrypted(), ctx.remaining_accounts)?;
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        let seq = group_descriptor.message_count;
+        let message = Message {
+            seq,
+            sender: payer.key(),
+            scheme: upload_buffer.scheme,
+            nonce: std::mem::take(&mut upload_buffer.nonce),
+            kind: upload_buffer.kind,
+            kind_version: MESSAGE_KIND_VERSION,
+            key_epoch: (upload_buffer.scheme == EncryptionScheme::GroupEpochAead).then_some(group_descriptor.key_epoch),
+            device_envelopes,
+            content: std::mem::take(&mut upload_buffer.content),
+            attachment,
+            reply_to: None,
+            timestamp: current_timestamp,
+            expires_at: expires_at(current_timestamp, group_descriptor.message_ttl)?,
+            edited_at: None,
+            previous_hash: None,
+            deleted: false,
+        };
+
+        if group_descriptor.mode == ChatMode::Ring as u8 {
+            let ring_buffer = ctx.accounts.ring_buffer.as_ref().ok_or(ErrorCode::RingBufferRequired)?;
+            push_ring_message(ring_buffer, seq, &message)?;
+        } else {
+            let page_info = ctx.accounts.message_page.as_ref().ok_or(ErrorCode::MessagePageRequired)?.to_account_info();
+            let payer_info = payer.to_account_info();
+            let system_info = ctx.accounts.system_program.to_account_info();
+            let page_index = group_descriptor.next_page();
+
+            open_message_page(&page_info, group_key.to_bytes(), page_index, ctx.bumps.message_page.unwrap_or_default(), &payer_info, &system_info)?;
+
+            group_descriptor.page_length = push_message(&page_info, &message, &payer_info, &system_info)?;
+            group_descriptor.current_page = page_index;
+        }
+        group_descriptor.message_count += 1;
+
+        msg!("GroupMessage: sender={:?}, group={:?}, seq={}, upload={:?}", 
+             payer.key(), group_key, seq, upload_buffer.key());
+
+        Ok(())
+    }
+
+    pub fn edit_message(ctx: Context<EditMessage>, _page_index: u32, seq: u64, content: Vec<u8>, nonce: Vec<u8>, keep_previous_hash: bool) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let page_info = ctx.accounts.message_page.to_account_info();
+
+        let (offset, span, message) = read_message(&page_info, seq)?;
+        require!(message.sender == payer.key(), ErrorCode::NotMessageSender);
+        require!(!message.deleted, ErrorCode::MessageDeleted);
+        // The edit is encrypted under the scheme of the message, with a fresh nonce.
+        require!(nonce.len() == message.scheme.nonce_length(), ErrorCode::InvalidNonce);
+        validate_body(message.kind, &content, &message.attachment, message.scheme.is_encrypted(), MAX_TEXT_LENGTH)?;
+
+        let chat = ctx.accounts.message_page.load()?.chat;
+        let private_chat = page_chat(chat, &ctx.accounts.private_chat, &ctx.accounts.group_descriptor)?;
+        match private_chat {
+            Some(private_chat) => require!(private_chat.load()?.closing == 0, ErrorCode::ChatClosing),
+            None => {
+                // Only joined members edit in a group, the sender may have left or been kicked since.
+                let group_descriptor = ctx.accounts.group_descriptor.as_ref().ok_or(ErrorCode::ChatHeaderRequired)?;
+                let payer_member = ctx.accounts.payer_member.as_ref().ok_or(ErrorCode::NotInGroup)?;
+                require!(group_descriptor.load()?.is_active(), ErrorCode::GroupIsNotActive);
+                require!(payer_member.group == group_descriptor.key() && payer_member.wallet == payer.key(), ErrorCode::NotInGroup);
+                require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+            }
+        }
+
+        // The edit is a body of the current schema of its kind, also for migrated raw text messages.
+        let mut edited = message.clone();
+        edited.previous_hash = if keep_previous_hash { Some(hash(&message.content).to_bytes()) } else { None };
+        edited.content = content;
+        edited.nonce = nonce;
+        edited.kind_version = MESSAGE_KIND_VERSION;
+        edited.edited_at = Some(Clock::get()?.unix_timestamp);
+
+        let page_lamports = page_info.lamports();
+        replace_message(&page_info, offset, span, &edited.to_slots()?, &payer.to_account_info(), &ctx.accounts.system_program.to_account_info())?;
+
+        // The editor pays for a longer message and gets the rent of a shorter one back.
+        if let Some(private_chat) = private_chat {
+            let mut private_chat = private_chat.load_mut()?;
+            if page_info.lamports() > page_lamports {
+                private_chat.deposit(payer.key(), page_info.lamports() - page_lamports);
+            } else {
+                private_chat.withdraw(payer.key(), page_lamports - page_info.lamports());
+            }
+        }
+
+        msg!("MessageEdit: sender={:?}, chat={:?}, seq={}", payer.key(), chat, seq);
+
+        Ok(())
+    }
+
+    pub fn redact_message(ctx: Context<RedactMessage>, _page_index: u32, seq: u64) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let sender = &ctx.accounts.sender;
+        let page_info = ctx.accounts.message_page.to_account_info();
+        let chat = ctx.accounts.message_page.load()?.chat;
+
+        let (offset, mut header) = {
+            let data = page_info.try_borrow_data()?;
+            let (_, slots) = message_page(&data);
+            find_message(slots, seq).ok_or(ErrorCode::MessageNotFound)?
+        };
+        require!(header.sender == sender.key(), ErrorCode::NotMessageSender);
+        require!(!header.is_deleted(), ErrorCode::MessageDeleted);
+
+        // Group owners and admins can redact any message of their group.
+        if header.sender != payer.key() {
+            let group_descriptor = ctx.accounts.group_descriptor.as_ref().ok_or(ErrorCode::NotAllowedToRedact)?;
+            let payer_member = ctx.accounts.payer_member.as_ref().ok_or(ErrorCode::NotAllowedToRedact)?;
+
+            require!(chat == group_descriptor.key().to_bytes(), ErrorCode::NotAllowedToRedact);
+            require!(payer_member.group == group_descriptor.key() && payer_member.wallet == payer.key(), ErrorCode::NotAllowedToRedact);
+            require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+            require!(payer_member.role == GroupRole::Owner || payer_member.role == GroupRole::Admin, ErrorCode::NotAllowedToRedact);
+        }
+
+        let private_chat = page_chat(chat, &ctx.accounts.private_chat, &ctx.accounts.group_descriptor)?;
+        let span = header.slots();
+        header.redact();
+
+        // The freed rent goes back to the sender, who paid for the message.
+        let page_lamports = page_info.lamports();
+        replace_message(&page_info, offset, span, &header.to_slots(), &sender.to_account_info(), &ctx.accounts.system_program.to_account_info())?;
+        if let Some(private_chat) = private_chat {
+            private_chat.load_mut()?.withdraw(sender.key(), page_lamports - page_info.lamports());
+        }
+
+        msg!("MessageRedact: sender={:?}, redactor={:?}, chat={:?}, seq={}", 
+             sender.key(), payer.key(), chat, seq);
+
+        Ok(())
+    }
+
+    pub fn abort_upload(ctx: Context<AbortUpload>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let upload_buffer = &ctx.accounts.upload_buffer;
+
+        msg!("UploadAbort: sender={:?}, chat={:?}", payer.key(), upload_buffer.chat);
+
+        Ok(())
+    }
+
+    pub fn add_reaction(ctx: Context<AddReaction>, _hash: [u8; 32], seq: u64, code: u32) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let private_chat = ctx.accounts.private_chat.load()?;
+        let message_reactions = &mut ctx.accounts.message_reactions;
+        let reaction = &mut ctx.accounts.reaction;
+
+        require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+        require!(private_chat.closing == 0, ErrorCode::ChatClosing);
+        require!(message_exists(_hash, seq, &ctx.accounts.message_page, &ctx.accounts.message_ring)?, ErrorCode::MessageNotFound);
+
+        // The reactions of a closed chat or a deleted message are closed by close_chat_accounts.
+        if message_reactions.payer == Pubkey::default() {
+            message_reactions.payer = payer.key();
+        }
+        message_reactions.chat = _hash;
+        message_reactions.generation = private_chat.generation;
+        message_reactions.seq = seq;
+        message_reactions.add(code)?;
+
+        reaction.chat = _hash;
+        reaction.generation = private_chat.generation;
+        reaction.seq = seq;
+        reaction.wallet = payer.key();
+        reaction.code = code;
+
+        msg!("Reaction: sender={:?}, chat={:?}, seq={}, code={}", payer.key(), _hash, seq, code);
+
+        Ok(())
+    }
+
+    pub fn remove_reaction(ctx: Context<RemoveReaction>, _chat: [u8; 32], _generation: u64, seq: u64, code: u32) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let message_reactions = &mut ctx.accounts.message_reactions;
+
+        message_reactions.remove(code);
+
+        msg!("ReactionRemove: sender={:?}, chat={:?}, seq={}, code={}", payer.key(), _chat, seq, code);
+
+        Ok(())
+    }
+
+    pub fn add_group_reaction(ctx: Context<AddGroupReaction>, seq: u64, code: u32) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let group_descriptor = ctx.accounts.group_descriptor.load()?;
+        let payer_member = &ctx.accounts.payer_member;
+        let message_reactions = &mut ctx.accounts.message_reactions;
+        let reaction = &mut ctx.accounts.reaction;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+        require!(message_exists(ctx.accounts.group_descriptor.key().to_bytes(), seq, &ctx.accounts.message_page, &ctx.accounts.message_ring)?,
+            ErrorCode::MessageNotFound);
+
+        if message_reactions.payer == Pubkey::default() {
+            message_reactions.payer = payer.key();
+        }
+        message_reactions.chat = ctx.accounts.group_descriptor.key().to_bytes();
+        message_reactions.generation = 0;
+        message_reactions.seq = seq;
+        message_reactions.add(code)?;
+
+        reaction.chat = ctx.accounts.group_descriptor.key().to_bytes();
+        reaction.generation = 0;
+        reaction.seq = seq;
+        reaction.wallet = payer.key();
+        reaction.code = code;
+
+        msg!("GroupReaction: sender={:?}, group={:?}, seq={}, code={}", payer.key(), ctx.accounts.group_descriptor.key(), seq, code);
+
+        Ok(())
+    }
+
+    pub fn mark_read(ctx: Context<MarkRead>, _hash: [u8; 32], last_read_seq: u64) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let private_chat = ctx.accounts.private_chat.load()?;
+        let read_marker = &mut ctx.accounts.read_marker;
+
+        require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+        require!(last_read_seq < private_chat.message_count, ErrorCode::MessageNotFound);
+
+        read_marker.chat = _hash;
+        read_marker.generation = private_chat.generation;
+        read_marker.wallet = payer.key();
+        read_marker.last_read_seq = last_read_seq;
+        read_marker.updated_at = Clock::get()?.unix_timestamp;
+
+        msg!("PrivateRead: reader={:?}, chat={:?}, seq={}", payer.key(), _hash, last_read_seq);
+
+        Ok(())
+    }
+
+    pub fn mark_group_read(ctx: Context<MarkGroupRead>, last_read_seq: u64) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let group_descriptor = ctx.accounts.group_descriptor.load()?;
+        let payer_member = &ctx.accounts.payer_member;
+        let read_marker = &mut ctx.accounts.read_marker;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+        require!(last_read_seq < group_descriptor.message_count, ErrorCode::MessageNotFound);
+
+        read_marker.chat = ctx.accounts.group_descriptor.key().to_bytes();
+        read_marker.generation = 0;
+        read_marker.wallet = payer.key();
+        read_marker.last_read_seq = last_read_seq;
+        read_marker.updated_at = Clock::get()?.unix_timestamp;
+
+        msg!("GroupRead: reader={:?}, group={:?}, seq={}", payer.key(), ctx.accounts.group_descriptor.key(), last_read_seq);
+
+        Ok(())
+    }
+
+    pub fn set_message_ttl(ctx: Context<SetMessageTtl>, _hash: [u8; 32], ttl: i64) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut private_chat = ctx.accounts.private_chat.load_mut()?;
+
+        require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+        require!((0..=MAX_MESSAGE_TTL).contains(&ttl), ErrorCode::InvalidTtl);
+        require!(ttl == 0 || private_chat.mode != ChatMode::Ring as u8, ErrorCode::TtlUnsupportedInRingBuffer);
+
+        private_chat.message_ttl = ttl;
+
+        msg!("PrivateTtl: sender={:?}, chat={:?}, ttl={}", payer.key(), _hash, ttl);
+
+        Ok(())
+    }
+
+    pub fn set_group_message_ttl(ctx: Context<SetGroupMessageTtl>, ttl: i64) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+        let payer_member = &ctx.accounts.payer_member;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+        require!(payer_member.role == GroupRole::Owner || payer_member.role == GroupRole::Admin, ErrorCode::InvalidRole);
+        require!((0..=MAX_MESSAGE_TTL).contains(&ttl), ErrorCode::InvalidTtl);
+        require!(ttl == 0 || group_descriptor.mode != ChatMode::Ring as u8, ErrorCode::TtlUnsupportedInRingBuffer);
+
+        group_descriptor.message_ttl = ttl;
+
+        msg!("GroupTtl: sender={:?}, group={:?}, ttl={}", payer.key(), ctx.accounts.group_descriptor.key(), ttl);
+
+        Ok(())
+    }
+
+    pub fn set_allowed_schemes(ctx: Context<SetAllowedSchemes>, _hash: [u8; 32], schemes: u8) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut private_chat = ctx.accounts.private_chat.load_mut()?;
+
+        require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+        require!(private_chat.closing == 0, ErrorCode::ChatClosing);
+        require!(schemes != 0 && schemes & !PRIVATE_CHAT_SCHEMES == 0, ErrorCode::SchemeNotAllowed);
+
+        private_chat.allowed_schemes = schemes;
+
+        msg!("PrivateSchemes: sender={:?}, chat={:?}, schemes={:#04x}", payer.key(), _hash, schemes);
+
+        Ok(())
+    }
+
+    pub fn set_group_allowed_schemes(ctx: Context<SetGroupAllowedSchemes>, schemes: u8) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+        let payer_member = &ctx.accounts.payer_member;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+        require!(payer_member.role == GroupRole::Owner || payer_member.role == GroupRole::Admin, ErrorCode::InvalidRole);
+        require!(schemes != 0 && schemes & !GROUP_SCHEMES == 0, ErrorCode::SchemeNotAllowed);
+
+        group_descriptor.allowed_schemes = schemes;
+
+        msg!("GroupSchemes: sender={:?}, group={:?}, schemes={:#04x}", payer.key(), ctx.accounts.group_descriptor.key(), schemes);
+
+        Ok(())
+    }
+
+    // Anyone can strip expired messages from a page, the freed rent goes back to the senders
+    // minus a bounty for the cranker. Senders are passed as remaining accounts, expired messages
+    // of senders that are not passed are left in the page.
+    pub fn purge_expired<'info>(ctx: Context<'_, '_, 'info, 'info, PurgeExpired<'info>>, _page_index: u32) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let page_info = ctx.accounts.message_page.to_account_info();
+        let now = Clock::get()?.unix_timestamp;
+
+        // Expired messages are cut down to their header and the page is compacted in place.
+        let mut freed: usize = 0;
+        let mut freed_by_sender: Vec<(Pubkey, usize)> = vec![];
+        let (chat, index, length) = {
+            let mut data = page_info.try_borrow_mut_data()?;
+            let (page, slots) = message_page_mut(&mut data);
+            let (mut read, mut write) = (0, 0);
+            while read < slots.len() {
+                let mut header: MessageHeader = bytemuck::pod_read_unaligned(&slots[read..read + MESSAGE_HEADER_SIZE]);
+                let span = header.slots() * MESSAGE_SLOT_SIZE;
+                let purge = !header.is_deleted() && header.is_expired(now)
+                    && ctx.remaining_accounts.iter().any(|a| a.key() == header.sender && a.is_writable);
+
+                if purge {
+                    header.redact();
+                    let bytes = header.to_slots();
+                    slots[write..write + bytes.len()].copy_from_slice(&bytes);
+                    write += bytes.len();
+
+                    let bytes = span - bytes.len();
+                    freed += bytes;
+                    match freed_by_sender.iter_mut().find(|(sender, _)| sender == &header.sender) {
+                        Some((_, sender_bytes)) => *sender_bytes += bytes,
+                        None => freed_by_sender.push((header.sender, bytes)),
+                    }
+                } else {
+                    slots.copy_within(read..read + span, write);
+                    write += span;
+                }
+                read += span;
+            }
+            page.length = (write / MESSAGE_SLOT_SIZE) as u32;
+            (page.chat, page.index, page.length)
+        };
+        require!(freed > 0, ErrorCode::NothingToPurge);
+
+        // Only the rent of the freed bytes is paid out, lamports sent to the page on top of its rent stay.
+        let rent = Rent::get()?;
+        let new_len = page_info.data_len() - freed;
+        let lamports = rent.minimum_balance(page_info.data_len()) - rent.minimum_balance(new_len);
+        page_info.resize(new_len)?;
+
+        let bounty = lamports * PURGE_BOUNTY_BPS / 10_000;
+        move_lamports(&page_info, &payer.to_account_info(), bounty)?;
+
+        // The bounty is taken from the deposits of the senders along with their refunds.
+        let refund = lamports - bounty;
+        let private_chat = page_chat(chat, &ctx.accounts.private_chat, &ctx.accounts.group_descriptor)?;
+        let mut private_chat = private_chat.map(|c| c.load_mut()).transpose()?;
+        // Sends append after the last used slot of the current page, which moved up with the compaction.
+        if let Some(private_chat) = private_chat.as_mut() {
+            if private_chat.current_page == index {
+                private_chat.page_length = length;
+            }
+        } else if let Some(group_descriptor) = &ctx.accounts.group_descriptor {
+            let mut group_descriptor = group_descriptor.load_mut()?;
+            if group_descriptor.current_page == index {
+                group_descriptor.page_length = length;
+            }
+        }
+        for (sender, bytes) in freed_by_sender {
+            let sender_info = ctx.remaining_accounts.iter()
+                .find(|a| a.key() == sender && a.is_writable)
+                .ok_or(ErrorCode::InvalidRefundRecipient)?;
+            move_lamports(&page_info, sender_info, (refund as u128 * bytes as u128 / freed as u128) as u64)?;
+            if let Some(private_chat) = private_chat.as_mut() {
+                private_chat.withdraw(sender, (lamports as u128 * bytes as u128 / freed as u128) as u64);
+            }
+        }
+
+        msg!("MessagePurge: cranker={:?}, chat={:?}, page={}, bytes={}, bounty={}", 
+             payer.key(), chat, index, freed, bounty);
+
+        Ok(())
+    }
+
+    pub fn post_group_key(ctx: Context<PostGroupKey>, member: Pubkey, epoch: u32, envelope: Vec<u8>) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let group_descriptor = ctx.accounts.group_descriptor.load()?;
+        let payer_member = &ctx.accounts.payer_member;
+        let target_member = &ctx.accounts.target_member;
+        let group_key = &mut ctx.accounts.group_key;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+        require!(payer_member.role == GroupRole::Owner || payer_member.role == GroupRole::Admin, ErrorCode::InvalidRole);
+        require!(target_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+        require!(epoch == group_descriptor.key_epoch, ErrorCode::InvalidKeyEpoch);
+        require!(!envelope.is_empty() && envelope.len() <= GROUP_KEY_MAX_ENVELOPE, ErrorCode::InvalidKeyEnvelope);
+
+        group_key.group = ctx.accounts.group_descriptor.key();
+        group_key.wallet = member;
+        group_key.epoch = epoch;
+        group_key.posted_by = payer.key();
+        group_key.envelope = envelope;
+
+        msg!("GroupKey: group={:?}, member={:?}, epoch={}, posted_by={:?}", 
+             ctx.accounts.group_descriptor.key(), member, epoch, payer.key());
+
+        Ok(())
+    }
+
+    pub fn pin_message(ctx: Context<PinMessage>, _hash: [u8; 32], seq: u64) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut private_chat = ctx.accounts.private_chat.load_mut()?;
+
+        require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+        require!(seq < private_chat.message_count, ErrorCode::MessageNotFound);
+
+        private_chat.pin(seq)?;
+
+        emit!(MessagePinEvent {
+            chat: _hash,
+            seq,
+            wallet: payer.key(),
+            pinned: true,
+        });
+
+        Ok(())
+    }
+
+    pub fn unpin_message(ctx: Context<PinMessage>, _hash: [u8; 32], seq: u64) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut private_chat = ctx.accounts.private_chat.load_mut()?;
+
+        require!(private_chat.wallets.iter().any(|w| w == &payer.key()), ErrorCode::NotInChat);
+
+        private_chat.unpin(seq)?;
+
+        emit!(MessagePinEvent {
+            chat: _hash,
+            seq,
+            wallet: payer.key(),
+            pinned: false,
+        });
+
+        Ok(())
+    }
+
+    pub fn pin_group_message(ctx: Context<PinGroupMessage>, seq: u64) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+        let payer_member = &ctx.accounts.payer_member;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+        require!(payer_member.role == GroupRole::Owner || payer_member.role == GroupRole::Admin, ErrorCode::InvalidRole);
+        require!(seq < group_descriptor.message_count, ErrorCode::MessageNotFound);
+
+        group_descriptor.pin(seq)?;
+
+        emit!(MessagePinEvent {
+            chat: ctx.accounts.group_descriptor.key().to_bytes(),
+            seq,
+            wallet: payer.key(),
+            pinned: true,
+        });
+
+        Ok(())
+    }
+
+    pub fn unpin_group_message(ctx: Context<PinGroupMessage>, seq: u64) -> Result<()> {
+        let payer = &mut ctx.accounts.payer;
+        let mut group_descriptor = ctx.accounts.group_descriptor.load_mut()?;
+        let payer_member = &ctx.accounts.payer_member;
+
+        require!(group_descriptor.is_active(), ErrorCode::GroupIsNotActive);
+        require!(payer_member.state == GroupPeerState::Joined, ErrorCode::NotInGroup);
+        require!(payer_member.role == GroupRole::Owner || payer_member.role == GroupRole::Admin, ErrorCode::InvalidRole);
+
+        group_descriptor.unpin(seq)?;
+
+        emit!(MessagePinEvent {
+            chat: ctx.accounts.group_descriptor.key().to_bytes(),
+            seq,
+            wallet: payer.key(),
+            pinned: false,
+        });
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Register<'info> {
+    #[account(init, payer = payer, space = WALLET_DESCRIPTOR_SIZE, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub wallet_descriptor: Account<'info, WalletDescriptor>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    /// CHECK: instructions sysvar, holds the Ed25519 proof of possession
+    #[account(address = instructions_sysvar::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateWallet<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), LEGACY_WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub legacy_descriptor: Account<'info, LegacyWalletDescriptor>,
+    #[account(init_if_needed, payer = payer, space = WALLET_DESCRIPTOR_SIZE,
+        seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub wallet_descriptor: Account<'info, WalletDescriptor>,
+    pub system_program: Program<'info, System>,
+    /// CHECK: instructions sysvar, holds the Ed25519 proof of possession of the legacy key
+    #[account(address = instructions_sysvar::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePubkey<'info> {
+    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub wallet_descriptor: Account<'info, WalletDescriptor>,
+    pub payer: Signer<'info>,
+    /// CHECK: prekey bundle of the wallet, its signed prekey is cleared when it exists
+    #[account(mut, seeds = [b"prekey_bundle", payer.key().as_ref(), PREKEY_BUNDLE_VERSION.as_ref()], bump)]
+    pub prekey_bundle: UncheckedAccount<'info>,
+    /// CHECK: instructions sysvar, holds the Ed25519 proof of possession
+    #[account(address = instructions_sysvar::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetSignedPrekey<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub wallet_descriptor: Account<'info, WalletDescriptor>,
+    #[account(init_if_needed, payer = payer, space = PREKEY_BUNDLE_SIZE,
+        seeds = [b"prekey_bundle", payer.key().as_ref(), PREKEY_BUNDLE_VERSION.as_ref()], bump)]
+    pub prekey_bundle: Account<'info, PrekeyBundle>,
+    pub system_program: Program<'info, System>,
+    /// CHECK: instructions sysvar, holds the Ed25519 signature of the prekey
+    #[account(address = instructions_sysvar::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AddOneTimePrekeys<'info> {
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"prekey_bundle", payer.key().as_ref(), PREKEY_BUNDLE_VERSION.as_ref()], bump)]
+    pub prekey_bundle: Account<'info, PrekeyBundle>,
+}
+
+#[derive(Accounts)]
+pub struct AddDevice<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(init_if_needed, payer = payer, space = DEVICE_REGISTRY_SIZE,
+        seeds = [b"device_registry", payer.key().as_ref(), DEVICE_REGISTRY_VERSION.as_ref()], bump)]
+    pub device_registry: Account<'info, DeviceRegistry>,
+    pub system_program: Program<'info, System>,
+    /// CHECK: instructions sysvar, holds the Ed25519 proof of possession
+    #[account(address = instructions_sysvar::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeDevice<'info> {
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"device_registry", payer.key().as_ref(), DEVICE_REGISTRY_VERSION.as_ref()], bump)]
+    pub device_registry: Account<'info, DeviceRegistry>,
+}
+
+#[derive(Accounts)]
+#[instruction(_hash: [u8; 32])]
+pub struct Invite<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: invitee is a public key
+    pub invitee: AccountInfo<'info>,
+    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub payer_descriptor: Account<'info, WalletDescriptor>,
+    #[account(mut, seeds = [b"wallet_descriptor", invitee.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub invitee_descriptor: Account<'info, WalletDescriptor>,
+    #[account(init, payer = payer, space = CONTACT_SIZE,
+        seeds = [b"contact", payer.key().as_ref(), invitee.key().as_ref(), CONTACT_VERSION.as_ref()], bump)]
+    pub payer_contact: Account<'info, Contact>,
+    #[account(init, payer = payer, space = CONTACT_SIZE,
+        seeds = [b"contact", invitee.key().as_ref(), payer.key().as_ref(), CONTACT_VERSION.as_ref()], bump)]
+    pub invitee_contact: Account<'info, Contact>,
+    #[account(init, payer = payer, space = PRIVATE_CHAT_SIZE,
+        seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+    #[account(init, payer = payer, space = MESSAGE_PAGE_SIZE,
+        seeds = [b"message_page", _hash.as_ref(), 0u32.to_le_bytes().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub message_page: AccountLoader<'info, MessagePage>,
+    pub system_program: Program<'info, System>,
+    // device registries of the two wallets, required when the message carries device envelopes
+    pub sender_devices: Option<Account<'info, DeviceRegistry>>,
+    pub receiver_devices: Option<Account<'info, DeviceRegistry>>,
+    // prekey bundle of the invitee, passed when the invite starts an X3DH session
+    #[account(mut, seeds = [b"prekey_bundle", invitee.key().as_ref(), PREKEY_BUNDLE_VERSION.as_ref()], bump)]
+    pub prekey_bundle: Option<Account<'info, PrekeyBundle>>,
+}
+
+#[derive(Accounts)]
+pub struct Reject<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: invitee is a public key
+    pub peer: AccountInfo<'info>,
+    #[account(mut, seeds = [b"contact", payer.key().as_ref(), peer.key().as_ref(), CONTACT_VERSION.as_ref()], bump)]
+    pub payer_contact: Account<'info, Contact>,
+    #[account(mut, seeds = [b"contact", peer.key().as_ref(), payer.key().as_ref(), CONTACT_VERSION.as_ref()], bump)]
+    pub peer_contact: Account<'info, Contact>,
+}
+
+#[derive(Accounts)]
+pub struct Accept<'info> {
+    #[account(mut)]
     pub payer: Signer<'info>,
     /// CHECK: invitee is a public key
     pub peer: AccountInfo<'info>,
+    #[account(mut, seeds = [b"contact", payer.key().as_ref(), peer.key().as_ref(), CONTACT_VERSION.as_ref()], bump)]
+    pub payer_contact: Account<'info, Contact>,
+    #[account(mut, seeds = [b"contact", peer.key().as_ref(), payer.key().as_ref(), CONTACT_VERSION.as_ref()], bump)]
+    pub peer_contact: Account<'info, Contact>
+}
+
+#[derive(Accounts)]
+#[instruction(_hash: [u8; 32])]
+pub struct SendMessage<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+    /// CHECK: current page of the chat, created by the handler when it does not exist yet
+    #[account(mut,
+        seeds = [b"message_page", _hash.as_ref(), private_chat.load()?.next_page().to_le_bytes().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub message_page: Option<UncheckedAccount<'info>>,
+    pub system_program: Program<'info, System>,
+    #[account(mut, seeds = [b"ring_buffer", _hash.as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub ring_buffer: Option<AccountLoader<'info, RingBuffer>>,
+    // page or ring buffer holding the replied message
+    pub reply_page: Option<AccountLoader<'info, MessagePage>>,
+    pub reply_ring: Option<AccountLoader<'info, RingBuffer>>,
+    // device registries of the two wallets, required when the message carries device envelopes
+    pub sender_devices: Option<Account<'info, DeviceRegistry>>,
+    pub receiver_devices: Option<Account<'info, DeviceRegistry>>,
+    // prekey bundle of the receiver, passed when the message starts an X3DH session
+    #[account(mut, seeds = [b"prekey_bundle", prekey_bundle.wallet.as_ref(), PREKEY_BUNDLE_VERSION.as_ref()], bump)]
+    pub prekey_bundle: Option<Account<'info, PrekeyBundle>>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(_hash: [u8; 32])]
+pub struct EmitMessage<'info> {
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+    // device registries of the two wallets, required when the message carries device envelopes
+    pub sender_devices: Option<Account<'info, DeviceRegistry>>,
+    pub receiver_devices: Option<Account<'info, DeviceRegistry>>,
+}
+
+#[derive(Accounts)]
+#[instruction(_hash: [u8; 32], capacity: u16)]
+pub struct EnableRingBuffer<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+    #[account(init, payer = payer, space = RING_BUFFER_SIZE + capacity as usize * RING_SLOT_SPACE,
+        seeds = [b"ring_buffer", _hash.as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub ring_buffer: AccountLoader<'info, RingBuffer>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(_hash: [u8; 32], page_index: u32)]
+pub struct MigratePrivateChat<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: the other participant, it gets back the rent of the legacy messages it sent
+    #[account(mut)]
+    pub peer: AccountInfo<'info>,
+    /// CHECK: baseline chat with the LegacyPrivateChat layout, walked in place by the handler
+    #[account(mut, owner = crate::ID, seeds = [b"privite_chat", _hash.as_ref(), LEGACY_PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub legacy_chat: UncheckedAccount<'info>,
+    #[account(init_if_needed, payer = payer, space = PRIVATE_CHAT_SIZE,
+        seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+    /// CHECK: page the legacy messages are moved to, created by the handler when it does not exist yet
+    #[account(mut,
+        seeds = [b"message_page", _hash.as_ref(), page_index.to_le_bytes().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub message_page: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(_hash: [u8; 32])]
+pub struct ClosePrivateChat<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: peer is a public key, it receives its share of the refund
+    #[account(mut)]
+    pub peer: AccountInfo<'info>,
     #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
     pub payer_descriptor: Account<'info, WalletDescriptor>,
     #[account(mut, seeds = [b"wallet_descriptor", peer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
     pub peer_descriptor: Account<'info, WalletDescriptor>,
+    #[account(mut, seeds = [b"contact", payer.key().as_ref(), peer.key().as_ref(), CONTACT_VERSION.as_ref()], bump)]
+    pub payer_contact: Account<'info, Contact>,
+    #[account(mut, seeds = [b"contact", peer.key().as_ref(), payer.key().as_ref(), CONTACT_VERSION.as_ref()], bump)]
+    pub peer_contact: Account<'info, Contact>,
+    #[account(mut, seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+    #[account(mut, seeds = [b"ring_buffer", _hash.as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub ring_buffer: Option<AccountLoader<'info, RingBuffer>>,
+    /// CHECK: receives the whole refund when a third party is chosen, checked against `refund_to`
+    #[account(mut)]
+    pub refund_recipient: Option<AccountInfo<'info>>,
+}
+
+#[derive(Accounts)]
+#[instruction(chat: [u8; 32])]
+pub struct CloseChatAccounts<'info> {
+    pub payer: Signer<'info>,
+    /// CHECK: header of the private chat, it may be closed already
+    #[account(seeds = [b"privite_chat", chat.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: Option<UncheckedAccount<'info>>,
+    pub group_descriptor: Option<AccountLoader<'info, GroupDescriptor>>,
+    // page of the messages whose reactions are closed because the message is deleted
+    pub message_page: Option<AccountLoader<'info, MessagePage>>,
+}
+
+#[derive(Accounts)]
+#[instruction(group_type: GroupType, title: Vec<u8>, description: Vec<u8>, image_url: Vec<u8>)]
+pub struct CreateGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub payer_descriptor: Account<'info, WalletDescriptor>,
+    #[account(init, 
+        payer = payer, 
+        space = group_create_gd_realloc!(title, description, image_url),
+        seeds = [b"group_descriptor", payer.key().as_ref(), payer_descriptor.created_groups.to_le_bytes().as_ref()],
+        bump)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(init, payer = payer, space = GROUP_MEMBER_SIZE,
+        seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(invitee: Pubkey)]
+pub struct InviteToGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+
+    #[account(mut, seeds = [b"wallet_descriptor", invitee.as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub invitee_descriptor: Account<'info, WalletDescriptor>,
+    #[account(init_if_needed, payer = payer, space = GROUP_MEMBER_SIZE,
+        seeds = [b"group_member", group_descriptor.key().as_ref(), invitee.as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub invitee_member: Account<'info, GroupMember>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptInviteToGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(mut, seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RejectInviteToGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub payer_descriptor: Account<'info, WalletDescriptor>,
+    #[account(mut, seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(content: Vec<u8>)]
+pub struct SendMessageToGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+    /// CHECK: current page of the group, created by the handler when it does not exist yet
+    #[account(mut,
+        seeds = [b"message_page", group_descriptor.key().as_ref(), group_descriptor.load()?.next_page().to_le_bytes().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub message_page: Option<UncheckedAccount<'info>>,
+    pub system_program: Program<'info, System>,
+    #[account(mut, seeds = [b"ring_buffer", group_descriptor.key().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub ring_buffer: Option<AccountLoader<'info, RingBuffer>>,
+    // page or ring buffer holding the replied message
+    pub reply_page: Option<AccountLoader<'info, MessagePage>>,
+    pub reply_ring: Option<AccountLoader<'info, RingBuffer>>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct EmitMessageToGroup<'info> {
+    pub payer: Signer<'info>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+}
+
+#[derive(Accounts)]
+#[instruction(capacity: u16)]
+pub struct EnableGroupRingBuffer<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(init, payer = payer, space = RING_BUFFER_SIZE + capacity as usize * RING_SLOT_SPACE,
+        seeds = [b"ring_buffer", group_descriptor.key().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub ring_buffer: AccountLoader<'info, RingBuffer>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct LeaveGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub payer_descriptor: Account<'info, WalletDescriptor>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(mut, seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(target: Pubkey)]
+pub struct KickFromGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"wallet_descriptor", target.as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub target_descriptor: Account<'info, WalletDescriptor>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(mut, seeds = [b"group_member", group_descriptor.key().as_ref(), target.as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub target_member: Account<'info, GroupMember>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(title: Vec<u8>)]
+pub struct RenameGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct JoinGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
+    pub payer_descriptor: Account<'info, WalletDescriptor>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(init_if_needed, payer = payer, space = GROUP_MEMBER_SIZE,
+        seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(target: Pubkey)]
+pub struct SetGroupRole<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(mut, seeds = [b"group_member", group_descriptor.key().as_ref(), target.as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub target_member: Account<'info, GroupMember>,
+}
+
+#[derive(Accounts)]
+#[instruction(page_index: u32)]
+pub struct MigrateGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: baseline or partly migrated group descriptor, its discriminator is checked by the handler
+    #[account(mut, owner = crate::ID)]
+    pub group_descriptor: UncheckedAccount<'info>,
+    /// CHECK: page the legacy messages are moved to, created by the handler when it does not exist yet
+    #[account(mut,
+        seeds = [b"message_page", group_descriptor.key().as_ref(), page_index.to_le_bytes().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub message_page: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(chat: [u8; 32], generation: u64, length: u32)]
+pub struct OpenUploadBuffer<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(init, payer = payer, space = UPLOAD_BUFFER_SIZE + length as usize,
+        seeds = [b"upload_buffer", payer.key().as_ref(), chat.as_ref(), generation.to_le_bytes().as_ref(), UPLOAD_BUFFER_VERSION.as_ref()], bump)]
+    pub upload_buffer: Account<'info, UploadBuffer>,
+    pub system_program: Program<'info, System>,
+    // header of the chat the buffer is opened for, a group passes its descriptor and the payer's membership instead
+    #[account(seeds = [b"privite_chat", chat.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: Option<AccountLoader<'info, PrivateChat>>,
+    pub group_descriptor: Option<AccountLoader<'info, GroupDescriptor>>,
+    #[account(seeds = [b"group_member", chat.as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Option<Account<'info, GroupMember>>,
+}
+
+#[derive(Accounts)]
+pub struct WriteUploadChunk<'info> {
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"upload_buffer", payer.key().as_ref(), upload_buffer.chat.as_ref(), upload_buffer.generation.to_le_bytes().as_ref(), UPLOAD_BUFFER_VERSION.as_ref()], bump)]
+    pub upload_buffer: Account<'info, UploadBuffer>,
+}
+
+#[derive(Accounts)]
+#[instruction(_hash: [u8; 32])]
+pub struct CommitUpload<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+    #[account(mut, close = payer,
+        seeds = [b"upload_buffer", payer.key().as_ref(), _hash.as_ref(), private_chat.load()?.generation.to_le_bytes().as_ref(), UPLOAD_BUFFER_VERSION.as_ref()], bump)]
+    pub upload_buffer: Account<'info, UploadBuffer>,
+    /// CHECK: current page of the chat, created by the handler when it does not exist yet
+    #[account(mut,
+        seeds = [b"message_page", _hash.as_ref(), private_chat.load()?.next_page().to_le_bytes().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub message_page: Option<UncheckedAccount<'info>>,
+    pub system_program: Program<'info, System>,
+    #[account(mut, seeds = [b"ring_buffer", _hash.as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub ring_buffer: Option<AccountLoader<'info, RingBuffer>>,
+    // device registries of the two wallets, required when the message carries device envelopes
+    pub sender_devices: Option<Account<'info, DeviceRegistry>>,
+    pub receiver_devices: Option<Account<'info, DeviceRegistry>>,
+}
+
+#[derive(Accounts)]
+pub struct CommitUploadToGroup<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+    #[account(mut, close = payer,
+        seeds = [b"upload_buffer", payer.key().as_ref(), group_descriptor.key().as_ref(), 0u64.to_le_bytes().as_ref(), UPLOAD_BUFFER_VERSION.as_ref()], bump)]
+    pub upload_buffer: Account<'info, UploadBuffer>,
+    /// CHECK: current page of the group, created by the handler when it does not exist yet
+    #[account(mut,
+        seeds = [b"message_page", group_descriptor.key().as_ref(), group_descriptor.load()?.next_page().to_le_bytes().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub message_page: Option<UncheckedAccount<'info>>,
+    pub system_program: Program<'info, System>,
+    #[account(mut, seeds = [b"ring_buffer", group_descriptor.key().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub ring_buffer: Option<AccountLoader<'info, RingBuffer>>,
+}
+
+#[derive(Accounts)]
+#[instruction(_page_index: u32)]
+pub struct EditMessage<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"message_page", message_page.load()?.chat.as_ref(), _page_index.to_le_bytes().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub message_page: AccountLoader<'info, MessagePage>,
+    // header of the chat the page belongs to, a group page passes its group instead
+    #[account(mut, seeds = [b"privite_chat", message_page.load()?.chat.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: Option<AccountLoader<'info, PrivateChat>>,
+    pub group_descriptor: Option<AccountLoader<'info, GroupDescriptor>>,
+    // membership of the editor, required for group pages
+    pub payer_member: Option<Account<'info, GroupMember>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(_page_index: u32)]
+pub struct RedactMessage<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: sender of the message, receives the freed rent
+    #[account(mut)]
+    pub sender: AccountInfo<'info>,
+    #[account(mut, seeds = [b"message_page", message_page.load()?.chat.as_ref(), _page_index.to_le_bytes().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub message_page: AccountLoader<'info, MessagePage>,
+    // header of the chat the page belongs to, a group page passes its group instead
+    #[account(mut, seeds = [b"privite_chat", message_page.load()?.chat.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: Option<AccountLoader<'info, PrivateChat>>,
+    pub group_descriptor: Option<AccountLoader<'info, GroupDescriptor>>,
+    pub payer_member: Option<Account<'info, GroupMember>>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct Accept<'info> {
+pub struct AbortUpload<'info> {
     #[account(mut)]
     pub payer: Signer<'info>,
-    /// CHECK: invitee is a public key
-    pub peer: AccountInfo<'info>,
-    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
-    #[account(mut, seeds = [b"wallet_descriptor", peer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
-    pub peer_descriptor: Account<'info, WalletDescriptor>
+    #[account(mut, close = payer,
+        seeds = [b"upload_buffer", payer.key().as_ref(), upload_buffer.chat.as_ref(), upload_buffer.generation.to_le_bytes().as_ref(), UPLOAD_BUFFER_VERSION.as_ref()], bump)]
+    pub upload_buffer: Account<'info, UploadBuffer>,
 }
 
 #[derive(Accounts)]
-#[instruction(_hash: [u8; 32], encrypted: bool, content: Vec<u8>)]
-pub struct SendMessage<'info> {
+#[instruction(_hash: [u8; 32], seq: u64, code: u32)]
+pub struct AddReaction<'info> {
     #[account(mut)]
     pub payer: Signer<'info>,
-    #[account(mut, seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump, 
-        realloc = 8 
-        + 32 * 2 // wallets 
-        + 4 // messages full length
-        + 4 // messages count
-        + (private_chat.length as usize) // messages length
-        + 32 + 4 + 1 + content.len() + 8, realloc::payer = payer, realloc::zero = true)]
-    pub private_chat: Account<'info, PrivateChat>,
+    #[account(seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+    #[account(init_if_needed, payer = payer, space = MESSAGE_REACTIONS_SIZE,
+        seeds = [b"message_reactions", _hash.as_ref(), private_chat.load()?.generation.to_le_bytes().as_ref(), seq.to_le_bytes().as_ref(), REACTION_VERSION.as_ref()], bump)]
+    pub message_reactions: Account<'info, MessageReactions>,
+    #[account(init, payer = payer, space = REACTION_SIZE,
+        seeds = [b"reaction", _hash.as_ref(), private_chat.load()?.generation.to_le_bytes().as_ref(), seq.to_le_bytes().as_ref(), payer.key().as_ref(), code.to_le_bytes().as_ref(), REACTION_VERSION.as_ref()], bump)]
+    pub reaction: Account<'info, Reaction>,
     pub system_program: Program<'info, System>,
+    // page or ring buffer holding the message
+    pub message_page: Option<AccountLoader<'info, MessagePage>>,
+    pub message_ring: Option<AccountLoader<'info, RingBuffer>>,
 }
 
+// Removal works for private chats and groups, the reaction PDA proves who reacted.
 #[derive(Accounts)]
-#[instruction(group_type: GroupType, title: Vec<u8>, description: Vec<u8>, image_url: Vec<u8>)]
-pub struct CreateGroup<'info> {
+#[instruction(_chat: [u8; 32], _generation: u64, seq: u64, code: u32)]
+pub struct RemoveReaction<'info> {
     #[account(mut)]
     pub payer: Signer<'info>,
-    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump, realloc = 8 + 32 + 4 + (payer_descriptor.peers.len())*(32 + 1) + 4 + (payer_descriptor.groups.len() + 1) * ( 32 +1 ), realloc::payer = payer, realloc::zero = true)]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
-    #[account(init, 
-        payer = payer, 
-        space = group_create_gd_realloc!(title, description, image_url),
-        seeds = [b"group_descriptor", payer.key().as_ref(), payer_descriptor.groups.len().to_le_bytes().as_ref()],
-        bump)]
-    pub group_descriptor: Account<'info, GroupDescriptor>,
+    #[account(mut, seeds = [b"message_reactions", _chat.as_ref(), _generation.to_le_bytes().as_ref(), seq.to_le_bytes().as_ref(), REACTION_VERSION.as_ref()], bump)]
+    pub message_reactions: Account<'info, MessageReactions>,
+    #[account(mut, close = payer,
+        seeds = [b"reaction", _chat.as_ref(), _generation.to_le_bytes().as_ref(), seq.to_le_bytes().as_ref(), payer.key().as_ref(), code.to_le_bytes().as_ref(), REACTION_VERSION.as_ref()], bump)]
+    pub reaction: Account<'info, Reaction>,
+}
+
+#[derive(Accounts)]
+#[instruction(seq: u64, code: u32)]
+pub struct AddGroupReaction<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+    #[account(init_if_needed, payer = payer, space = MESSAGE_REACTIONS_SIZE,
+        seeds = [b"message_reactions", group_descriptor.key().as_ref(), 0u64.to_le_bytes().as_ref(), seq.to_le_bytes().as_ref(), REACTION_VERSION.as_ref()], bump)]
+    pub message_reactions: Account<'info, MessageReactions>,
+    #[account(init, payer = payer, space = REACTION_SIZE,
+        seeds = [b"reaction", group_descriptor.key().as_ref(), 0u64.to_le_bytes().as_ref(), seq.to_le_bytes().as_ref(), payer.key().as_ref(), code.to_le_bytes().as_ref(), REACTION_VERSION.as_ref()], bump)]
+    pub reaction: Account<'info, Reaction>,
     pub system_program: Program<'info, System>,
+    // page or ring buffer holding the message
+    pub message_page: Option<AccountLoader<'info, MessagePage>>,
+    pub message_ring: Option<AccountLoader<'info, RingBuffer>>,
 }
 
 #[derive(Accounts)]
-#[instruction(invitee: Pubkey)]
-pub struct InviteToGroup<'info> {
+#[instruction(_hash: [u8; 32])]
+pub struct MarkRead<'info> {
     #[account(mut)]
     pub payer: Signer<'info>,
+    #[account(seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+    #[account(init_if_needed, payer = payer, space = READ_MARKER_SIZE,
+        seeds = [b"read_marker", _hash.as_ref(), private_chat.load()?.generation.to_le_bytes().as_ref(), payer.key().as_ref(), READ_MARKER_VERSION.as_ref()], bump)]
+    pub read_marker: Account<'info, ReadMarker>,
+    pub system_program: Program<'info, System>,
+}
 
-    #[account(mut,
-        realloc = group_invite_gd_realloc!(group_descriptor), realloc::payer = payer, realloc::zero = true)]
-    pub group_descriptor: Account<'info, GroupDescriptor>,
-
-    #[account(mut, seeds = [b"wallet_descriptor", invitee.as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump, 
-        realloc = 8 // discriminator
-        + 32 // pubkey
-        + 4 + (invitee_descriptor.peers.len())*(32 + 1) 
-        + 4 + (invitee_descriptor.groups.len() + 1) * ( 32 + 1 )
-    , realloc::payer = payer, realloc::zero = true)]
-    pub invitee_descriptor: Account<'info, WalletDescriptor>,
+#[derive(Accounts)]
+pub struct MarkGroupRead<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+    #[account(init_if_needed, payer = payer, space = READ_MARKER_SIZE,
+        seeds = [b"read_marker", group_descriptor.key().as_ref(), 0u64.to_le_bytes().as_ref(), payer.key().as_ref(), READ_MARKER_VERSION.as_ref()], bump)]
+    pub read_marker: Account<'info, ReadMarker>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct AcceptInviteToGroup<'info> {
+#[instruction(_hash: [u8; 32])]
+pub struct SetMessageTtl<'info> {
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+}
+
+#[derive(Accounts)]
+pub struct SetGroupMessageTtl<'info> {
+    pub payer: Signer<'info>,
     #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+}
+
+#[derive(Accounts)]
+#[instruction(_hash: [u8; 32])]
+pub struct SetAllowedSchemes<'info> {
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+}
+
+#[derive(Accounts)]
+pub struct SetGroupAllowedSchemes<'info> {
     pub payer: Signer<'info>,
     #[account(mut)]
-    pub group_descriptor: Account<'info, GroupDescriptor>,
-    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+}
+
+#[derive(Accounts)]
+#[instruction(_page_index: u32)]
+pub struct PurgeExpired<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"message_page", message_page.load()?.chat.as_ref(), _page_index.to_le_bytes().as_ref(), MESSAGE_VERSION.as_ref()], bump)]
+    pub message_page: AccountLoader<'info, MessagePage>,
+    // header of the chat the page belongs to, a group page passes its group instead
+    #[account(mut, seeds = [b"privite_chat", message_page.load()?.chat.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: Option<AccountLoader<'info, PrivateChat>>,
+    #[account(mut)]
+    pub group_descriptor: Option<AccountLoader<'info, GroupDescriptor>>,
+}
+
+#[derive(Accounts)]
+#[instruction(member: Pubkey, epoch: u32)]
+pub struct PostGroupKey<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+    #[account(seeds = [b"group_member", group_descriptor.key().as_ref(), member.as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub target_member: Account<'info, GroupMember>,
+    #[account(init, payer = payer, space = GROUP_KEY_SIZE,
+        seeds = [b"group_key", group_descriptor.key().as_ref(), member.as_ref(), epoch.to_le_bytes().as_ref(), GROUP_KEY_VERSION.as_ref()], bump)]
+    pub group_key: Account<'info, GroupKeyEnvelope>,
     pub system_program: Program<'info, System>,
 }
 
-#[derive(Accounts)]
-pub struct RejectInviteToGroup<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    #[account(mut)]
-    pub group_descriptor: Account<'info, GroupDescriptor>,
-    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
-    pub system_program: Program<'info, System>,
+#[derive(Accounts)]
+#[instruction(_hash: [u8; 32])]
+pub struct PinMessage<'info> {
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"privite_chat", _hash.as_ref(), PRIVATE_CHAT_VERSION.as_ref()], bump)]
+    pub private_chat: AccountLoader<'info, PrivateChat>,
+}
+
+#[derive(Accounts)]
+pub struct PinGroupMessage<'info> {
+    pub payer: Signer<'info>,
+    #[account(mut)]
+    pub group_descriptor: AccountLoader<'info, GroupDescriptor>,
+    #[account(seeds = [b"group_member", group_descriptor.key().as_ref(), payer.key().as_ref(), GROUP_MEMBER_VERSION.as_ref()], bump)]
+    pub payer_member: Account<'info, GroupMember>,
+}
+
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum PeerState{
+    Invited = 0,
+    Requested = 1,
+    Accepted = 2,
+    Rejected = 3,
+}
+
+const CONTACT_VERSION: [u8; 1] = [1];
+const CONTACT_SIZE: usize = 8 // discriminator
+    + 32 // wallet
+    + 32 // peer
+    + 1; // state
+
+// Contact is a wallet's relationship with a peer, seeded by the wallet and the peer.
+// A wallet's peers are listed by filtering on `wallet`.
+#[account]
+pub struct Contact {
+    pub wallet: Pubkey,
+    pub peer: Pubkey,
+    pub state: PeerState,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum GroupPeerState{
+    Invited = 0,
+    Joined = 1,
+    Rejected = 2,
+    Left = 3,
+    Kicked = 4,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum GroupRole{
+    Member = 0,
+    Admin = 1,
+    Owner = 2,
+}
+
+const GROUP_MEMBER_VERSION: [u8; 1] = [1];
+const GROUP_MEMBER_SIZE: usize = 8 // discriminator
+    + 32 // group
+    + 32 // wallet
+    + 1 // state
+    + 1 // role
+    + 8; // joined_at
+
+// GroupMember is a wallet's membership in a group, seeded by the group and the wallet.
+// It is also the wallet side record: a wallet's groups are listed by filtering on `wallet`.
+#[account]
+pub struct GroupMember {
+    pub group: Pubkey,
+    pub wallet: Pubkey,
+    pub state: GroupPeerState,
+    pub role: GroupRole,
+    pub joined_at: i64,
+}
+
+const WALLET_DESCRIPTOR_VERSION: [u8; 1] = [2];
+const WALLET_DESCRIPTOR_SIZE: usize = 8 // discriminator
+    + 32 // pubkey
+    + 4 // peers count
+    + 4 // groups count
+    + 8 // created groups, seeds the next group descriptor
+    + 4 + MAX_KEY_HISTORY * (32 + 8 + 8); // key history
+const MAX_KEY_HISTORY: usize = 8;
+const KEY_STATEMENT_DOMAIN: &[u8] = b"cherry-chat:pubkey:v1:";
+
+const LEGACY_WALLET_DESCRIPTOR_VERSION: [u8; 1] = [1];
+// LegacyPeer is a peer of a baseline wallet.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct LegacyPeer {
+    pub wallet: Pubkey,
+    pub state: PeerState,
+}
+
+// LegacyWalletDescriptor is the Borsh layout of baseline wallets, kept to migrate them with migrate_wallet.
+#[account(discriminator = WalletDescriptor::DISCRIMINATOR)]
+pub struct LegacyWalletDescriptor {
+    pub pubkey: [u8; 32],
+    pub peers: Vec<LegacyPeer>,
+    pub groups: Vec<LegacyGroup>,
+}
+
+// KeyRecord is a public key of a wallet and the time it took effect.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct KeyRecord {
+    pub pubkey: [u8; 32],
+    pub valid_from: i64,
+    pub valid_from_slot: u64,
+}
+
+// WalletDescriptor is a descriptor for a wallet, its peers and groups live in Contact and GroupMember accounts.
+#[account]
+pub struct WalletDescriptor {
+    pub pubkey: [u8; 32],
+    pub peer_count: u32,
+    pub group_count: u32,
+    pub created_groups: u64,
+    pub key_history: Vec<KeyRecord>, // last MAX_KEY_HISTORY keys, the current one last
+}
+
+impl WalletDescriptor {
+    // Records the key taking effect now, dropping the oldest record when the history is full.
+    pub fn record_key(&mut self, pubkey: [u8; 32]) -> Result<KeyRecord> {
+        let clock = Clock::get()?;
+        let record = KeyRecord {
+            pubkey,
+            valid_from: clock.unix_timestamp,
+            valid_from_slot: clock.slot,
+        };
+
+        if self.key_history.len() >= MAX_KEY_HISTORY {
+            self.key_history.remove(0);
+        }
+        self.key_history.push(record.clone());
+
+        Ok(record)
+    }
+}
+
+const DEVICE_REGISTRY_VERSION: [u8; 1] = [1];
+const MAX_DEVICES: usize = 8;
+const DEVICE_REGISTRY_SIZE: usize = 8 // discriminator
+    + 32 // wallet
+    + 4 + MAX_DEVICES * (32 + 8); // devices
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct Device {
+    pub pubkey: [u8; 32], // x25519 key of the device
+    pub added_at: i64,
+}
+
+// DeviceRegistry lists the encryption keys of a wallet's devices, seeded by the wallet.
+#[account]
+pub struct DeviceRegistry {
+    pub wallet: Pubkey,
+    pub devices: Vec<Device>,
+}
+
+const PREKEY_BUNDLE_VERSION: [u8; 1] = [1];
+const MAX_ONE_TIME_PREKEYS: usize = 32;
+const PREKEY_STATEMENT_DOMAIN: &[u8] = b"cherry-chat:prekey:v1:";
+const PREKEY_BUNDLE_SIZE: usize = 8 // discriminator
+    + 32 // wallet
+    + 32 // identity key the signed prekey was signed with
+    + 4 // signed prekey id
+    + 32 // signed prekey
+    + 64 // signed prekey signature
+    + 8 // updated at
+    + 4 // next one-time prekey id
+    + 4 + MAX_ONE_TIME_PREKEYS * (4 + 32); // one-time prekeys
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct OneTimePrekey {
+    pub id: u32,
+    pub key: [u8; 32],
+}
+
+// PrekeyBundle holds a wallet's X3DH signed prekey and its pool of one-time prekeys, seeded by the wallet.
+#[account]
+pub struct PrekeyBundle {
+    pub wallet: Pubkey,
+    pub identity_key: [u8; 32],
+    pub signed_prekey_id: u32,
+    pub signed_prekey: [u8; 32],
+    pub signed_prekey_signature: [u8; 64],
+    pub updated_at: i64,
+    pub next_prekey_id: u32,
+    pub one_time_prekeys: Vec<OneTimePrekey>, // consumed oldest first
+}
+
+const MESSAGE_VERSION: [u8; 1] = [1];
+// Message is a stored message, it is written to page slots as a MessageHeader followed by its MessageBody.
+#[derive(Clone)]
+pub struct Message{
+    pub seq: u64, // per-chat sequence number, stable across edits
+    pub sender: Pubkey,
+    pub scheme: EncryptionScheme, // how the content was encrypted, legacy encrypted messages are X25519AesCbc
+    pub nonce: Vec<u8>, // nonce of the scheme, empty for schemes that embed it in the content
+    pub kind: MessageKind,
+    pub kind_version: u8, // MESSAGE_KIND_VERSION the body was written with
+    pub key_epoch: Option<u32>, // group key epoch an encrypted group message was encrypted under
+    pub device_envelopes: Vec<DeviceEnvelope>, // message key encrypted to each device of the two wallets
+    pub content: Vec<u8>, // Borsh encoded body of the kind, or its ciphertext
+    pub attachment: Option<ContentRef>,
+    pub reply_to: Option<ReplyTo>,
+    pub timestamp: i64,
+    pub expires_at: Option<i64>,
+    pub edited_at: Option<i64>,
+    pub previous_hash: Option<[u8; 32]>, // hash of the content replaced by the last edit, if kept
+    pub deleted: bool,
+}
+
+impl Message {
+    // Length of the Borsh MessageBody, deleted messages keep only their header.
+    pub fn body_space(&self) -> usize {
+        if self.deleted {
+            return 0;
+        }
+
+        4 + self.nonce.len() // nonce
+        + 4 + self.device_envelopes.iter().map(|e| 32 + 4 + e.envelope.len()).sum::<usize>() // device envelopes
+        + 4 + self.content.len() // content length + content
+        + 1 + self.attachment.as_ref().map_or(0, |a| a.space()) // attachment
+        + 1 + self.reply_to.as_ref().map_or(0, |r| r.space()) // reply to
+    }
+
+    pub fn space(&self) -> usize {
+        MESSAGE_HEADER_SIZE + self.body_space()
+    }
+
+    pub fn slots(&self) -> usize {
+        self.space().div_ceil(MESSAGE_SLOT_SIZE)
+    }
+
+    pub fn header(&self) -> MessageHeader {
+        let mut flags = 0;
+        if self.key_epoch.is_some() { flags |= MESSAGE_KEY_EPOCH; }
+        if self.expires_at.is_some() { flags |= MESSAGE_EXPIRES; }
+        if self.edited_at.is_some() { flags |= MESSAGE_EDITED; }
+        if self.previous_hash.is_some() { flags |= MESSAGE_PREVIOUS_HASH; }
+        if self.deleted { flags |= MESSAGE_DELETED; }
+
+        MessageHeader {
+            seq: self.seq,
+            timestamp: self.timestamp,
+            expires_at: self.expires_at.unwrap_or_default(),
+            edited_at: self.edited_at.unwrap_or_default(),
+            sender: self.sender,
+            previous_hash: self.previous_hash.unwrap_or_default(),
+            key_epoch: self.key_epoch.unwrap_or_default(),
+            body_length: self.body_space() as u32,
+            flags,
+            scheme: self.scheme as u8,
+            kind: self.kind as u8,
+            kind_version: self.kind_version,
+            _padding: [0; 4],
+        }
+    }
+
+    // Encodes the message into the slots it spans, zero padded.
+    pub fn to_slots(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.slots() * MESSAGE_SLOT_SIZE);
+        data.extend_from_slice(bytemuck::bytes_of(&self.header()));
+        if !self.deleted {
+            // Same encoding as MessageBody, without cloning the fields into one.
+            self.nonce.serialize(&mut data)?;
+            self.device_envelopes.serialize(&mut data)?;
+            self.content.serialize(&mut data)?;
+            self.attachment.serialize(&mut data)?;
+            self.reply_to.serialize(&mut data)?;
+        }
+        data.resize(self.slots() * MESSAGE_SLOT_SIZE, 0);
+
+        Ok(data)
+    }
+
+    pub fn from_slots(data: &[u8]) -> Result<Message> {
+        let header: MessageHeader = bytemuck::pod_read_unaligned(&data[..MESSAGE_HEADER_SIZE]);
+        let body = match header.body_length as usize {
+            0 => MessageBody::default(),
+            length => MessageBody::try_from_slice(&data[MESSAGE_HEADER_SIZE..MESSAGE_HEADER_SIZE + length])?,
+        };
+        let flag = |flag: u8| header.flags & flag != 0;
+
+        Ok(Message {
+            seq: header.seq,
+            sender: header.sender,
+            scheme: EncryptionScheme::try_from_slice(&[header.scheme])?,
+            nonce: body.nonce,
+            kind: MessageKind::try_from_slice(&[header.kind])?,
+            kind_version: header.kind_version,
+            key_epoch: flag(MESSAGE_KEY_EPOCH).then_some(header.key_epoch),
+            device_envelopes: body.device_envelopes,
+            content: body.content,
+            attachment: body.attachment,
+            reply_to: body.reply_to,
+            timestamp: header.timestamp,
+            expires_at: flag(MESSAGE_EXPIRES).then_some(header.expires_at),
+            edited_at: flag(MESSAGE_EDITED).then_some(header.edited_at),
+            previous_hash: flag(MESSAGE_PREVIOUS_HASH).then_some(header.previous_hash),
+            deleted: flag(MESSAGE_DELETED),
+        })
+    }
+}
+
+const MESSAGE_SLOT_SIZE: usize = 256;
+const MESSAGE_HEADER_SIZE: usize = 8 // seq
+    + 8 // timestamp
+    + 8 // expires at
+    + 8 // edited at
+    + 32 // sender
+    + 32 // previous hash
+    + 4 // key epoch
+    + 4 // body length
+    + 1 // flags
+    + 1 // scheme
+    + 1 // kind
+    + 1 // kind version
+    + 4; // padding
+// Flags of stored messages marking the optional fields that are set.
+const MESSAGE_KEY_EPOCH: u8 = 1;
+const MESSAGE_EXPIRES: u8 = 1 << 1;
+const MESSAGE_EDITED: u8 = 1 << 2;
+const MESSAGE_PREVIOUS_HASH: u8 = 1 << 3;
+const MESSAGE_DELETED: u8 = 1 << 4;
+
+// MessageHeader is the fixed part of a stored message, read in place to find a message by seq.
+// A message spans the slots holding its header and body_length bytes of MessageBody.
+#[zero_copy]
+pub struct MessageHeader {
+    pub seq: u64,
+    pub timestamp: i64,
+    pub expires_at: i64, // set when flags has MESSAGE_EXPIRES
+    pub edited_at: i64, // set when flags has MESSAGE_EDITED
+    pub sender: Pubkey,
+    pub previous_hash: [u8; 32], // set when flags has MESSAGE_PREVIOUS_HASH
+    pub key_epoch: u32, // set when flags has MESSAGE_KEY_EPOCH
+    pub body_length: u32, // 0 once deleted
+    pub flags: u8,
+    pub scheme: u8, // EncryptionScheme
+    pub kind: u8, // MessageKind
+    pub kind_version: u8,
+    pub _padding: [u8; 4],
+}
+
+impl MessageHeader {
+    pub fn slots(&self) -> usize {
+        (MESSAGE_HEADER_SIZE + self.body_length as usize).div_ceil(MESSAGE_SLOT_SIZE)
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.flags & MESSAGE_DELETED != 0
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.flags & MESSAGE_EXPIRES != 0 && self.expires_at <= now
+    }
+
+    // Drops the body and the hash of the edited content, the rest of the header stays.
+    pub fn redact(&mut self) {
+        self.body_length = 0;
+        self.previous_hash = [0; 32];
+        self.flags = (self.flags | MESSAGE_DELETED) & !MESSAGE_PREVIOUS_HASH;
+    }
+
+    // Encodes the header alone into one slot, as stored for deleted messages.
+    pub fn to_slots(&self) -> Vec<u8> {
+        let mut data = bytemuck::bytes_of(self).to_vec();
+        data.resize(self.slots() * MESSAGE_SLOT_SIZE, 0);
+        data
+    }
+}
+
+// MessageBody is the variable part of a stored message, Borsh encoded after its header.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct MessageBody {
+    pub nonce: Vec<u8>,
+    pub device_envelopes: Vec<DeviceEnvelope>,
+    pub content: Vec<u8>,
+    pub attachment: Option<ContentRef>,
+    pub reply_to: Option<ReplyTo>,
+}
+
+const MAX_NONCE_LENGTH: usize = 24;
+const PRIVATE_CHAT_SCHEMES: u8 = 0b0111; // None, X25519AesCbc, X25519XChaCha20Poly1305
+const GROUP_SCHEMES: u8 = 0b1001; // None, GroupEpochAead
+const PRIVATE_CHAT_DEFAULT_SCHEMES: u8 = PRIVATE_CHAT_SCHEMES;
+const GROUP_DEFAULT_SCHEMES: u8 = GROUP_SCHEMES;
+// EncryptionScheme tells how the content was encrypted, chats allow a set of them as a bitmask.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EncryptionScheme {
+    None = 0,
+    X25519AesCbc = 1, // legacy CryptoJS AES-CBC over the raw x25519 secret, the IV is inside the content
+    X25519XChaCha20Poly1305 = 2,
+    GroupEpochAead = 3, // XChaCha20-Poly1305 under the group key of the message key epoch
+}
+
+impl EncryptionScheme {
+    pub fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        *self != EncryptionScheme::None
+    }
+
+    pub fn nonce_length(&self) -> usize {
+        match self {
+            EncryptionScheme::None | EncryptionScheme::X25519AesCbc => 0,
+            EncryptionScheme::X25519XChaCha20Poly1305 | EncryptionScheme::GroupEpochAead => MAX_NONCE_LENGTH,
+        }
+    }
+}
+
+const MAX_DEVICE_ENVELOPES: usize = 2 * MAX_DEVICES;
+const DEVICE_ENVELOPE_MAX_LENGTH: usize = 96;
+// DeviceEnvelope is the message key encrypted to one registered device.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct DeviceEnvelope {
+    pub device: [u8; 32],
+    pub envelope: Vec<u8>,
+}
+
+const MESSAGE_KIND_VERSION: u8 = 1;
+const MAX_TEXT_LENGTH: usize = 1024;
+const MAX_POLL_OPTIONS: usize = 10;
+const MAX_POLL_OPTION_LENGTH: usize = 100;
+// MessageKind tells how the content is encoded, each kind has a Borsh body schema below.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Text = 0,
+    System = 1,
+    Attachment = 2,
+    Payment = 3,
+    Poll = 4,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct TextBody {
+    pub text: String,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SystemBody {
+    pub code: u16,
+    pub text: String,
+}
+
+// The blob itself is described by the message attachment.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct AttachmentBody {
+    pub caption: String,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PaymentBody {
+    pub mint: Option<Pubkey>, // None for SOL
+    pub amount: u64,
+    pub recipient: Pubkey,
+    pub signature: Option<[u8; 64]>, // transaction that made the payment, None for a request
+    pub memo: String,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PollBody {
+    pub question: String,
+    pub options: Vec<String>,
+    pub multiple_choice: bool,
+}
+
+// ReplyTo references the replied message, in another chat or group when `chat` is set.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ReplyTo {
+    pub seq: u64,
+    pub chat: Option<[u8; 32]>, // private chat hash or group descriptor key
+}
+
+impl ReplyTo {
+    pub fn space(&self) -> usize {
+        8 // seq
+        + 1 + self.chat.map_or(0, |_| 32) // chat
+    }
+}
+
+const CONTENT_REF_MAX_MIME_TYPE: usize = 64;
+const CONTENT_REF_MAX_URI: usize = 256;
+const CONTENT_REF_MAX_KEY_ENVELOPE: usize = 128;
+// ContentRef points to a blob stored off-chain (Arweave/IPFS/HTTP), clients verify it against the hash.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ContentRef {
+    pub hash: [u8; 32], // sha256 of the blob as stored
+    pub length: u64,
+    pub mime_type: Vec<u8>,
+    pub uri: Vec<u8>,
+    pub key_envelope: Option<Vec<u8>>, // blob key encrypted for the chat, if the blob is encrypted
+}
+
+impl ContentRef {
+    pub fn space(&self) -> usize {
+        32 // hash
+        + 8 // length
+        + 4 + self.mime_type.len() // mime type length + mime type
+        + 4 + self.uri.len() // uri length + uri
+        + 1 + self.key_envelope.as_ref().map_or(0, |k| 4 + k.len()) // key envelope
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        require!(self.length > 0, ErrorCode::InvalidAttachment);
+        require!(!self.mime_type.is_empty() && self.mime_type.len() <= CONTENT_REF_MAX_MIME_TYPE, ErrorCode::InvalidAttachment);
+        require!(!self.uri.is_empty() && self.uri.len() <= CONTENT_REF_MAX_URI, ErrorCode::InvalidAttachment);
+        if let Some(key_envelope) = &self.key_envelope {
+            require!(!key_envelope.is_empty() && key_envelope.len() <= CONTENT_REF_MAX_KEY_ENVELOPE, ErrorCode::InvalidAttachment);
+        }
+
+        Ok(())
+    }
+}
+
+// Share of the rent freed by purge_expired paid to the cranker, in basis points.
+const PURGE_BOUNTY_BPS: u64 = 1_000;
+// Longest message TTL a chat can set, a year.
+const MAX_MESSAGE_TTL: i64 = 365 * 24 * 60 * 60;
+// Messages are appended to the current page until it uses this many slots.
+const MESSAGE_PAGE_SLOTS: u32 = 32;
+const MESSAGE_PAGE_SIZE: usize = 8 // discriminator
+    + 32 // chat
+    + 4 // index
+    + 4 // slots used
+    + 4 // messages count
+    + 4; // padding
+
+// MessagePage is a bounded slice of a chat history, seeded by the chat and the page index.
+// It is zero-copy, the `length` slots of MESSAGE_SLOT_SIZE bytes holding the messages follow the header
+// and the account grows by the slots of each message.
+#[account(zero_copy)]
+pub struct MessagePage {
+    pub chat: [u8; 32], // private chat hash or group descriptor key
+    pub index: u32,
+    pub length: u32, // slots used, a message spans one or more slots
+    pub count: u32, // messages stored
+    pub _padding: [u8; 4],
+}
+
+const UPLOAD_BUFFER_VERSION: [u8; 1] = [2];
+// Uploads are committed as a single message, so they are capped below the page size.
+const UPLOAD_BUFFER_MAX_LENGTH: usize = 4 * 1024;
+const UPLOAD_BUFFER_SIZE: usize = 8 // discriminator
+    + 32 // owner
+    + 32 // chat
+    + 8 // generation
+    + 32 // content hash
+    + 1 // scheme
+    + 4 + MAX_NONCE_LENGTH // nonce
+    + 1 // kind
+    + 4; // content length
+// UploadBuffer stages a message larger than one transaction, seeded by the owner, the chat and its generation.
+// It is filled by write_upload_chunk and closed by commit_upload or abort_upload.
+#[account]
+pub struct UploadBuffer {
+    pub owner: Pubkey,
+    pub chat: [u8; 32], // private chat hash or group descriptor key
+    pub generation: u64, // generation of the private chat, 0 for groups
+    pub content_hash: [u8; 32], // sha256 of the assembled content
+    pub scheme: EncryptionScheme,
+    pub nonce: Vec<u8>,
+    pub kind: MessageKind,
+    pub content: Vec<u8>,
+}
+
+const REACTION_VERSION: [u8; 1] = [1];
+const MAX_REACTION_KINDS: usize = 16;
+const MESSAGE_REACTIONS_SIZE: usize = 8 // discriminator
+    + 32 // chat
+    + 8 // generation
+    + 32 // payer
+    + 8 // seq
+    + 4 + MAX_REACTION_KINDS * (4 + 4); // counts
+const REACTION_SIZE: usize = 8 // discriminator
+    + 32 // chat
+    + 8 // generation
+    + 8 // seq
+    + 32 // wallet
+    + 4; // code
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ReactionCount {
+    pub code: u32,
+    pub count: u32,
+}
+
+// MessageReactions aggregates the reaction counts of a message, seeded by the chat and the message seq.
+#[account]
+pub struct MessageReactions {
+    pub chat: [u8; 32], // private chat hash or group descriptor key
+    pub generation: u64, // generation of the private chat, 0 for groups
+    pub payer: Pubkey, // first reactor, it paid the rent
+    pub seq: u64,
+    pub counts: Vec<ReactionCount>,
+}
+
+impl MessageReactions {
+    pub fn add(&mut self, code: u32) -> Result<()> {
+        match self.counts.iter_mut().find(|c| c.code == code) {
+            Some(reaction_count) => reaction_count.count += 1,
+            None => {
+                require!(self.counts.len() < MAX_REACTION_KINDS, ErrorCode::TooManyReactionKinds);
+                self.counts.push(ReactionCount { code, count: 1 });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, code: u32) {
+        if let Some(reaction_count) = self.counts.iter_mut().find(|c| c.code == code) {
+            reaction_count.count -= 1;
+        }
+        self.counts.retain(|c| c.count > 0);
+    }
+}
+
+// Reaction is a wallet's reaction to a message, seeded by the message, the wallet and the code.
+#[account]
+pub struct Reaction {
+    pub chat: [u8; 32],
+    pub generation: u64,
+    pub seq: u64,
+    pub wallet: Pubkey,
+    pub code: u32,
+}
+
+const READ_MARKER_VERSION: [u8; 1] = [1];
+const READ_MARKER_SIZE: usize = 8 // discriminator
+    + 32 // chat
+    + 8 // generation
+    + 32 // wallet
+    + 8 // last read seq
+    + 8; // updated at
+// ReadMarker is the last message a wallet has read in a chat, seeded by the chat and the wallet.
+// Unread messages are the chat's message_count minus last_read_seq + 1.
+#[account]
+pub struct ReadMarker {
+    pub chat: [u8; 32], // private chat hash or group descriptor key
+    pub generation: u64, // generation of the private chat, 0 for groups
+    pub wallet: Pubkey,
+    pub last_read_seq: u64,
+    pub updated_at: i64,
 }
 
-#[derive(Accounts)]
-#[instruction(content: Vec<u8>)]
-pub struct SendMessageToGroup<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    #[account(mut, realloc = group_send_message_gd_realloc!(group_descriptor, content), realloc::payer = payer, realloc::zero = true)]
-    pub group_descriptor: Account<'info, GroupDescriptor>,
-    pub system_program: Program<'info, System>,
+const GROUP_KEY_VERSION: [u8; 1] = [1];
+const GROUP_KEY_MAX_ENVELOPE: usize = 128;
+const GROUP_KEY_SIZE: usize = 8 // discriminator
+    + 32 // group
+    + 32 // wallet
+    + 4 // epoch
+    + 32 // posted by
+    + 4 + GROUP_KEY_MAX_ENVELOPE; // envelope
+// GroupKeyEnvelope is the group key of an epoch encrypted to a member's WalletDescriptor pubkey,
+// seeded by the group, the member and the epoch.
+#[account]
+pub struct GroupKeyEnvelope {
+    pub group: Pubkey,
+    pub wallet: Pubkey,
+    pub epoch: u32,
+    pub posted_by: Pubkey,
+    pub envelope: Vec<u8>,
 }
 
-#[derive(Accounts)]
-pub struct LeaveGroup<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
-    #[account(mut)]
-    pub group_descriptor: Account<'info, GroupDescriptor>,
-    pub system_program: Program<'info, System>,
+// Event-only messages are not stored on-chain, indexers rebuild history from the emitted events.
+// Their seq counts the chat's event-only messages, so it does not take the seq of a stored message.
+#[event]
+pub struct PrivateMessageEvent {
+    pub chat: [u8; 32],
+    pub sender: Pubkey,
+    pub receiver: Pubkey,
+    pub seq: u64,
+    pub scheme: EncryptionScheme,
+    pub nonce: Vec<u8>,
+    pub kind: MessageKind,
+    pub device_envelopes: Vec<DeviceEnvelope>,
+    pub content: Vec<u8>,
+    pub attachment: Option<ContentRef>,
+    pub timestamp: i64,
 }
 
-#[derive(Accounts)]
-#[instruction(target: Pubkey)]
-pub struct KickFromGroup<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    #[account(mut, seeds = [b"wallet_descriptor", target.as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump)]
-    pub target_descriptor: Account<'info, WalletDescriptor>,
-    #[account(mut)]
-    pub group_descriptor: Account<'info, GroupDescriptor>,
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct GroupMessageEvent {
+    pub group: Pubkey,
+    pub sender: Pubkey,
+    pub seq: u64,
+    pub scheme: EncryptionScheme,
+    pub nonce: Vec<u8>,
+    pub key_epoch: Option<u32>,
+    pub kind: MessageKind,
+    pub device_envelopes: Vec<DeviceEnvelope>,
+    pub content: Vec<u8>,
+    pub attachment: Option<ContentRef>,
+    pub timestamp: i64,
 }
 
-#[derive(Accounts)]
-#[instruction(title: Vec<u8>)]
-pub struct RenameGroup<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    #[account(mut, 
-        realloc = group_rename_gd_realloc!(group_descriptor, title), realloc::payer = payer, realloc::zero = true)]
-    pub group_descriptor: Account<'info, GroupDescriptor>,
-    pub system_program: Program<'info, System>,
+const MAX_MENTIONS: usize = 16;
+// MentionEvent names the wallets mentioned by a group message, `all` mentions every member.
+#[event]
+pub struct MentionEvent {
+    pub group: Pubkey,
+    pub sender: Pubkey,
+    pub seq: u64,
+    pub event_only: bool, // seq is the one of an event-only message
+    pub mentions: Vec<Pubkey>,
+    pub all: bool,
 }
 
-#[derive(Accounts)]
-pub struct CloseGroup<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    #[account(mut)]
-    pub group_descriptor: Account<'info, GroupDescriptor>,
-    pub system_program: Program<'info, System>,
+// GroupKeyEpochEvent asks the owner and admins to post the keys of the new epoch.
+#[event]
+pub struct GroupKeyEpochEvent {
+    pub group: Pubkey,
+    pub epoch: u32,
 }
 
-#[derive(Accounts)]
-pub struct JoinGroup<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    #[account(mut, seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()], bump, realloc = 8 // discriminator
-        + 32 
-        + 4 + (payer_descriptor.peers.len())*(32 + 1 ) 
-        + 4 + (payer_descriptor.groups.len() + 1) * ( 32 + 1 )
-    , realloc::payer = payer, realloc::zero = true)]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
-    #[account(mut,  realloc = group_invite_gd_realloc!(group_descriptor), realloc::payer = payer, realloc::zero = true)]
-    pub group_descriptor: Account<'info, GroupDescriptor>,
-    pub system_program: Program<'info, System>,
+// PubkeyUpdateEvent lets peers re-verify a wallet's new encryption key.
+#[event]
+pub struct PubkeyUpdateEvent {
+    pub wallet: Pubkey,
+    pub pubkey: [u8; 32],
+    pub previous: [u8; 32],
+    pub valid_from: i64,
+    pub valid_from_slot: u64,
 }
 
+// DeviceEvent tells peers a device key was added to or revoked from a wallet.
+#[event]
+pub struct DeviceEvent {
+    pub wallet: Pubkey,
+    pub pubkey: [u8; 32],
+    pub added: bool,
+    pub timestamp: i64,
+}
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum PeerState{
-    Invited = 0,
-    Requested = 1,
-    Accepted = 2,
-    Rejected = 3,
+// PrekeyConsumedEvent hands the sender the bundle for its X3DH session, the owner drops the used one-time prekey.
+#[event]
+pub struct PrekeyConsumedEvent {
+    pub wallet: Pubkey,
+    pub consumer: Pubkey,
+    pub identity_key: [u8; 32],
+    pub signed_prekey_id: u32,
+    pub signed_prekey: [u8; 32],
+    pub signed_prekey_signature: [u8; 64],
+    pub one_time_prekey: Option<OneTimePrekey>,
+    pub remaining: u32,
 }
 
-// Peer is a peer in a private chat.
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct Peer {
+#[event]
+pub struct MessagePinEvent {
+    pub chat: [u8; 32], // private chat hash or group descriptor key
+    pub seq: u64,
     pub wallet: Pubkey,
-    pub state: PeerState,
+    pub pinned: bool,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum GroupPeerState{
-    Invited = 0,
-    Joined = 1,
-    Rejected = 2,
-    Left = 3,
-    Kicked = 4,
+// Ring buffers keep the last `capacity` messages of up to RING_SLOT_SIZE bytes each, the capacity is chosen
+// when the ring buffer is enabled and bounded by the 10 KiB limit of accounts created by the program.
+const MAX_RING_BUFFER_CAPACITY: u16 = 32;
+const RING_SLOT_SIZE: usize = 248;
+const RING_BUFFER_SIZE: usize = 8 // discriminator
+    + 32 // chat
+    + 8 // seq
+    + 2 // head
+    + 2 // capacity
+    + 4; // padding
+const RING_SLOT_SPACE: usize = 8 // seq
+    + 8 // timestamp
+    + 32 // sender
+    + 2 // length
+    + 1 // scheme
+    + 1 // kind
+    + 4 // key epoch
+    + 1 // kind version
+    + 1 // flags
+    + 6 // padding
+    + RING_SLOT_SIZE; // content
+
+#[zero_copy]
+pub struct RingSlot {
+    pub seq: u64,
+    pub timestamp: i64,
+    pub sender: Pubkey,
+    pub length: u16, // nonce and content length
+    pub scheme: u8, // EncryptionScheme, the nonce of the scheme is stored ahead of the content
+    pub kind: u8, // MessageKind
+    pub key_epoch: u32, // group key epoch of encrypted group messages, set when flags has MESSAGE_KEY_EPOCH
+    pub kind_version: u8,
+    pub flags: u8,
+    pub _padding: [u8; 6],
+    pub content: [u8; RING_SLOT_SIZE],
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct Group {
-    pub account: Pubkey,
-    pub state: GroupPeerState,
+// RingBuffer is a fixed-capacity message store allocated up front, the oldest slot is overwritten on send.
+// It is zero-copy so a send only touches the header and the slot being written, `capacity` slots follow the header.
+#[account(zero_copy)]
+pub struct RingBuffer {
+    pub chat: [u8; 32], // private chat hash or group descriptor key
+    pub seq: u64, // sequence number of the next message
+    pub head: u16, // slot the next message is written to
+    pub capacity: u16,
+    pub _padding: [u8; 4],
 }
 
-const WALLET_DESCRIPTOR_VERSION: [u8; 1] = [1];
-// WalletDescriptor is a descriptor for a wallet.
-#[account]
-pub struct WalletDescriptor {
-    pub pubkey: [u8; 32],
-    pub peers: Vec<Peer>,
-    pub groups: Vec<Group>,
+impl RingBuffer {
+    pub fn push(&mut self, slots: &mut [RingSlot], seq: u64, message: &Message) -> Result<()> {
+        let length = message.nonce.len() + message.content.len();
+        require!(length <= RING_SLOT_SIZE, ErrorCode::MessageTooLarge);
+        require!(message.attachment.is_none() && message.reply_to.is_none() && message.device_envelopes.is_empty(), ErrorCode::UnsupportedInRingBuffer);
+
+        let slot = &mut slots[self.head as usize];
+        slot.seq = seq;
+        slot.sender = message.sender;
+        slot.timestamp = message.timestamp;
+        slot.scheme = message.scheme as u8;
+        slot.kind = message.kind as u8;
+        slot.kind_version = message.kind_version;
+        slot.key_epoch = message.key_epoch.unwrap_or_default();
+        slot.flags = if message.key_epoch.is_some() { MESSAGE_KEY_EPOCH } else { 0 };
+        slot.length = length as u16;
+        slot.content = [0; RING_SLOT_SIZE];
+        slot.content[..message.nonce.len()].copy_from_slice(&message.nonce);
+        slot.content[message.nonce.len()..length].copy_from_slice(&message.content);
+
+        self.head = (self.head + 1) % self.capacity;
+        self.seq = seq + 1;
+
+        Ok(())
+    }
 }
 
-const MESSAGE_VERSION: [u8; 1] = [1];
-// PrivateMessage is a message in a private chat.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum ChatMode{
+    Paged = 0,
+    Ring = 1,
+}
+
+const LEGACY_PRIVATE_CHAT_VERSION: [u8; 1] = [1];
+const LEGACY_MESSAGES_OFFSET: usize = 8 + 32*2 + 4; // discriminator, wallets and length, then the messages
+// LegacyMessage is a message of a baseline chat or group, stored inline in its account.
 #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct Message{
+pub struct LegacyMessage {
     pub sender: Pubkey,
     pub encrypted: bool,
     pub content: Vec<u8>,
     pub timestamp: i64,
 }
 
-const PRIVATE_CHAT_VERSION: [u8; 1] = [1];
-// PrivateChat is a chat between two wallets.
-#[account]
-pub struct PrivateChat {
+// LegacyPrivateChat is the Borsh layout of baseline chats, kept to migrate them with migrate_private_chat.
+#[account(discriminator = PrivateChat::DISCRIMINATOR)]
+pub struct LegacyPrivateChat {
     pub wallets: [Pubkey; 2],
     pub length: u32,
-    pub messages: Vec<Message>,
+    pub messages: Vec<LegacyMessage>,
+}
+
+const PRIVATE_CHAT_VERSION: [u8; 1] = [3];
+const MAX_PINNED_MESSAGES: usize = 5;
+const PRIVATE_CHAT_SIZE: usize = 8 // discriminator
+    + 32*2 // wallets
+    + 8 // messages count
+    + 4 // current page
+    + 4 // current page length
+    + 1 // mode
+    + 1 // close votes
+    + 1 // pinned count
+    + 5 // padding
+    + 8*2 // deposits
+    + 32 // close refund to
+    + 8*MAX_PINNED_MESSAGES // pinned
+    + 8 // message ttl
+    + 1 // allowed schemes
+    + 3 // padding
+    + 4 // legacy messages
+    + 4 // open pages
+    + 1 // closing
+    + 1 // prekey sessions
+    + 2 // padding
+    + 8 // generation
+    + 8; // event count
+// PrivateChat is a chat between two wallets, its messages live in MessagePage accounts.
+// It is zero-copy, new fields are carved out of the reserved bytes so existing accounts keep their layout.
+#[account(zero_copy)]
+pub struct PrivateChat {
+    pub wallets: [Pubkey; 2],
+    pub message_count: u64,
+    pub current_page: u32,
+    pub page_length: u32, // slots used in the current page
+    pub mode: u8, // ChatMode
+    pub close_votes: u8, // bit per wallet that requested the close
+    pub pinned_count: u8,
+    pub _padding: [u8; 5],
+    pub deposits: [u64; 2], // lamports each wallet paid into the chat accounts
+    pub close_refund_to: Pubkey, // refund recipient of the close request, default for pro-rata
+    pub pinned: [u64; MAX_PINNED_MESSAGES], // seqs of the pinned messages, first pinned_count are set
+    pub message_ttl: i64, // seconds until new messages expire, 0 keeps them
+    pub allowed_schemes: u8, // EncryptionScheme bits, 0 on chats created before it means the defaults
+    pub _padding2: [u8; 3],
+    pub legacy_messages: u32, // messages of the baseline chat still to migrate
+    pub open_pages: u32, // pages left to close once the close is agreed
+    pub closing: u8, // set once the close is agreed, messages can no longer be sent
+    pub prekey_sessions: u8, // bit per wallet that consumed a prekey of its peer to start its session
+    pub _padding3: [u8; 2],
+    pub generation: u64, // slot the chat was created in, seeds its reactions, read markers and upload buffers
+    pub event_count: u64, // event-only messages, numbered apart from the stored ones
+}
+
+impl PrivateChat {
+    pub fn next_page(&self) -> u32 {
+        next_page(self.current_page, self.page_length)
+    }
+
+    pub fn allowed_schemes(&self) -> u8 {
+        if self.allowed_schemes == 0 { PRIVATE_CHAT_DEFAULT_SCHEMES } else { self.allowed_schemes }
+    }
+
+    pub fn vote_bit(&self, wallet: Pubkey) -> u8 {
+        if self.wallets[0] == wallet { 1 } else { 2 }
+    }
+
+    pub fn pin(&mut self, seq: u64) -> Result<()> {
+        pin(&mut self.pinned, &mut self.pinned_count, seq)
+    }
+
+    pub fn unpin(&mut self, seq: u64) -> Result<()> {
+        unpin(&mut self.pinned, &mut self.pinned_count, seq)
+    }
+
+    pub fn deposit(&mut self, wallet: Pubkey, lamports: u64) {
+        if let Some(index) = self.wallets.iter().position(|w| w == &wallet) {
+            self.deposits[index] += lamports;
+        }
+    }
+
+    // Deposits made before they were tracked are not known, so refunds only take what is recorded.
+    pub fn withdraw(&mut self, wallet: Pubkey, lamports: u64) {
+        if let Some(index) = self.wallets.iter().position(|w| w == &wallet) {
+            self.deposits[index] = self.deposits[index].saturating_sub(lamports);
+        }
+    }
+}
+
+// Pinned seqs are kept in a fixed array, the first `count` are set.
+fn pin(pinned: &mut [u64; MAX_PINNED_MESSAGES], count: &mut u8, seq: u64) -> Result<()> {
+    let length = *count as usize;
+    require!(!pinned[..length].contains(&seq), ErrorCode::AlreadyPinned);
+    require!(length < MAX_PINNED_MESSAGES, ErrorCode::PinListFull);
+
+    pinned[length] = seq;
+    *count += 1;
+
+    Ok(())
+}
+
+fn unpin(pinned: &mut [u64; MAX_PINNED_MESSAGES], count: &mut u8, seq: u64) -> Result<()> {
+    let length = *count as usize;
+    let position = pinned[..length].iter().position(|p| p == &seq).ok_or(ErrorCode::NotPinned)?;
+
+    pinned.copy_within(position + 1..length, position);
+    pinned[length - 1] = 0;
+    *count -= 1;
+
+    Ok(())
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
@@ -717,79 +4247,503 @@ pub enum GroupState{
     Closed = 1
 }
 
-#[account]
+const GROUP_DESCRIPTOR_SIZE: usize = 8 // discriminator
+    + 32 // owner
+    + 8 // messages count
+    + 8 // message ttl
+    + 8*MAX_PINNED_MESSAGES // pinned
+    + 4 // current page
+    + 4 // current page length
+    + 4 // members count
+    + 4 // key epoch
+    + 1 // group type
+    + 1 // state
+    + 1 // mode
+    + 1 // pinned count
+    + 1 // allowed schemes
+    + 3 // padding
+    + 4 // legacy messages
+    + 4 // padding
+    + 8 // event count
+    + 48; // reserved
+// sha256("account:GroupDescriptorV2")[..8], baseline descriptors at the same address keep the default one.
+const GROUP_DESCRIPTOR_DISCRIMINATOR: [u8; 8] = [191, 73, 239, 123, 87, 139, 95, 247];
+// GroupDescriptor is the header of a group, members and messages live in GroupMember and MessagePage accounts.
+// It is zero-copy, the Borsh GroupMetadata follows it and new fields are carved out of the reserved bytes.
+#[account(zero_copy, discriminator = &GROUP_DESCRIPTOR_DISCRIMINATOR)]
 pub struct GroupDescriptor {
+    pub owner: Pubkey,
+    pub message_count: u64,
+    pub message_ttl: i64, // seconds until new messages expire, 0 keeps them
+    pub pinned: [u64; MAX_PINNED_MESSAGES], // seqs of the pinned messages, first pinned_count are set
+    pub current_page: u32,
+    pub page_length: u32, // slots used in the current page
+    pub member_count: u32,
+    pub key_epoch: u32, // current group key epoch, rotated when a member leaves or is kicked
+    pub group_type: u8, // GroupType
+    pub state: u8, // GroupState
+    pub mode: u8, // ChatMode
+    pub pinned_count: u8,
+    pub allowed_schemes: u8, // EncryptionScheme bits
+    pub _padding: [u8; 3],
+    pub legacy_messages: u32, // messages of the baseline descriptor still to migrate
+    pub _padding2: [u8; 4],
+    pub event_count: u64, // event-only messages, numbered apart from the stored ones
+    pub _reserved: [u8; 48],
+}
+
+impl GroupDescriptor {
+    pub fn next_page(&self) -> u32 {
+        next_page(self.current_page, self.page_length)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state == GroupState::Active as u8
+    }
+
+    pub fn pin(&mut self, seq: u64) -> Result<()> {
+        pin(&mut self.pinned, &mut self.pinned_count, seq)
+    }
+
+    pub fn unpin(&mut self, seq: u64) -> Result<()> {
+        unpin(&mut self.pinned, &mut self.pinned_count, seq)
+    }
+}
+
+// sha256("account:GroupDescriptor")[..8], the discriminator of baseline descriptors.
+const LEGACY_GROUP_DESCRIPTOR_DISCRIMINATOR: [u8; 8] = [93, 120, 160, 173, 34, 61, 250, 39];
+// LegacyGroup is a member of a baseline group, or a group of a baseline wallet.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct LegacyGroup {
+    pub account: Pubkey,
+    pub state: GroupPeerState,
+}
+
+// LegacyGroupDescriptor is the Borsh layout of baseline groups, kept to migrate them with migrate_group.
+#[account(discriminator = &LEGACY_GROUP_DESCRIPTOR_DISCRIMINATOR)]
+pub struct LegacyGroupDescriptor {
     pub title: Vec<u8>,
     pub description: Vec<u8>,
     pub image_url: Vec<u8>,
     pub owner: Pubkey,
     pub group_type: GroupType,
-    pub state: GroupState,  
-    pub members: Vec<Group>,
+    pub state: GroupState,
+    pub members: Vec<LegacyGroup>,
     pub length: u32,
-    pub messages: Vec<Message>
+    pub messages: Vec<LegacyMessage>,
+}
+
+// GroupMetadata is the Borsh tail of a GroupDescriptor, rewritten in full when it changes.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct GroupMetadata {
+    pub title: Vec<u8>,
+    pub description: Vec<u8>,
+    pub image_url: Vec<u8>,
 }
 
 #[macro_export]
 macro_rules! group_create_gd_realloc {
     ($title:expr, $description:expr, $image_url:expr) => {
-        8 // discriminator
+        GROUP_DESCRIPTOR_SIZE
         + (4 + $title.len()) // title length + title
         + (4 + $description.len()) // description length + description
         + (4 + $image_url.len()) // image_url length + image_url
-        + 32 // owner 
-        + 1 // group_type
-        + 1 // state
-        + 4 + (1) * (32 + 1) // one initial member (owner)
-        + 4 // messages full length 
-        + (4 + 0) // messages length + messages
-    }
-}
-
-#[macro_export]
-macro_rules! group_invite_gd_realloc {
-    ($group_descriptor:expr) => {
-        8 // discriminator
-        + (4 + $group_descriptor.title.len()) // title length + title
-        + (4 + $group_descriptor.description.len()) // description length + description
-        + (4 + $group_descriptor.image_url.len()) // image_url length + image_url
-        + 32 // owner 
-        + 1 // group_type
-        + 1 // state
-        + 4 + ($group_descriptor.members.len() + 1) * (32 + 1) // one initial member (owner)
-        + 4 // messages full length 
-        + (4 + $group_descriptor.length as usize) // messages length + messages
     }
 }
 
 #[macro_export]
 macro_rules! group_rename_gd_realloc {
-    ($group_descriptor:expr, $title:expr) => {
-        8 // discriminator
+    ($metadata:expr, $title:expr) => {
+        GROUP_DESCRIPTOR_SIZE
         + (4 + $title.len()) // title length + title
-        + (4 + $group_descriptor.description.len()) // description length + description
-        + (4 + $group_descriptor.image_url.len()) // image_url length + image_url
-        + 32 // owner 
-        + 1 // group_type
-        + 1 // state
-        + 4 + ($group_descriptor.members.len()) * (32 + 1) // members length + members
-        + 4 // messages full length 
-        + (4 + $group_descriptor.length as usize) // messages length + messages
+        + (4 + $metadata.description.len()) // description length + description
+        + (4 + $metadata.image_url.len()) // image_url length + image_url
     }
 }
 
-#[macro_export]
-macro_rules! group_send_message_gd_realloc {
-    ($group_descriptor:expr, $content:expr) => {
-        8 // discriminator
-        + (4 + $group_descriptor.title.len()) // title length + title
-        + (4 + $group_descriptor.description.len()) // description length + description
-        + (4 + $group_descriptor.image_url.len()) // image_url length + image_url
-        + 32 // owner 
-        + 1 // group_type
-        + 1 // state
-        + 4 + $group_descriptor.members.len() * (32 + 1) // one initial member (owner)
-        + 4 // messages full length 
-        + (4 + $group_descriptor.length as usize + 32 + 4 + 1 + $content.len() + 8) // messages length + messages
-    }
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::prelude::borsh::to_vec;
+    use bytemuck::Zeroable;
+
+    fn text_message(seq: u64, content: Vec<u8>) -> Message {
+        Message {
+            seq,
+            sender: Pubkey::new_unique(),
+            scheme: EncryptionScheme::None,
+            nonce: vec![],
+            kind: MessageKind::Text,
+            kind_version: MESSAGE_KIND_VERSION,
+            key_epoch: None,
+            device_envelopes: vec![],
+            content,
+            attachment: None,
+            reply_to: None,
+            timestamp: 1_700_000_000,
+            expires_at: None,
+            edited_at: None,
+            previous_hash: None,
+            deleted: false,
+        }
+    }
+
+    fn text_body(text: &str) -> Vec<u8> {
+        to_vec(&TextBody { text: text.to_string() }).unwrap()
+    }
+
+    #[test]
+    fn next_page_moves_on_once_the_page_is_full() {
+        assert_eq!(next_page(0, 0), 0);
+        assert_eq!(next_page(3, MESSAGE_PAGE_SLOTS - 1), 3);
+        assert_eq!(next_page(3, MESSAGE_PAGE_SLOTS), 4);
+    }
+
+    #[test]
+    fn ring_buffer_push_wraps_around() {
+        let mut ring = RingBuffer::zeroed();
+        ring.capacity = 2;
+        let mut slots = [RingSlot::zeroed(); 2];
+
+        for seq in 0..3 {
+            ring.push(&mut slots, seq, &text_message(seq, text_body("hi"))).unwrap();
+        }
+
+        assert_eq!(ring.head, 1);
+        assert_eq!(ring.seq, 3);
+        assert_eq!(slots[0].seq, 2);
+        assert_eq!(slots[1].seq, 1);
+        assert_eq!(slots[0].length as usize, text_body("hi").len());
+    }
+
+    #[test]
+    fn ring_buffer_push_rejects_what_a_slot_cannot_hold() {
+        let mut ring = RingBuffer::zeroed();
+        ring.capacity = 1;
+        let mut slots = [RingSlot::zeroed(); 1];
+
+        let message = text_message(0, vec![0; RING_SLOT_SIZE + 1]);
+        assert_eq!(ring.push(&mut slots, 0, &message).unwrap_err(), ErrorCode::MessageTooLarge.into());
+
+        let mut message = text_message(0, text_body("hi"));
+        message.reply_to = Some(ReplyTo { seq: 0, chat: None });
+        assert_eq!(ring.push(&mut slots, 0, &message).unwrap_err(), ErrorCode::UnsupportedInRingBuffer.into());
+        assert_eq!(ring.head, 0);
+    }
+
+    #[test]
+    fn message_space_counts_header_and_body() {
+        // nonce, device envelopes and content lengths, attachment and reply tags
+        let fixed = MESSAGE_HEADER_SIZE + 4 + 4 + 4 + 1 + 1;
+
+        let message = text_message(0, vec![0; MESSAGE_SLOT_SIZE - fixed]);
+        assert_eq!(message.space(), MESSAGE_SLOT_SIZE);
+        assert_eq!(message.slots(), 1);
+
+        let message = text_message(0, vec![0; MESSAGE_SLOT_SIZE - fixed + 1]);
+        assert_eq!(message.space(), MESSAGE_SLOT_SIZE + 1);
+        assert_eq!(message.slots(), 2);
+
+        let mut message = text_message(0, vec![0; 1000]);
+        message.deleted = true;
+        assert_eq!(message.space(), MESSAGE_HEADER_SIZE);
+        assert_eq!(message.slots(), 1);
+    }
+
+    #[test]
+    fn message_round_trips_through_slots() {
+        let mut message = text_message(7, vec![1; 300]);
+        message.expires_at = Some(1_700_000_600);
+        message.key_epoch = Some(2);
+
+        let data = message.to_slots().unwrap();
+        assert_eq!(data.len(), message.slots() * MESSAGE_SLOT_SIZE);
+
+        let decoded = Message::from_slots(&data).unwrap();
+        assert_eq!(decoded.seq, 7);
+        assert_eq!(decoded.sender, message.sender);
+        assert_eq!(decoded.content, message.content);
+        assert_eq!(decoded.expires_at, Some(1_700_000_600));
+        assert_eq!(decoded.key_epoch, Some(2));
+        assert_eq!(decoded.edited_at, None);
+        assert!(!decoded.deleted);
+    }
+
+    #[test]
+    fn reaction_counts_drop_at_zero() {
+        let mut reactions = MessageReactions { chat: [0; 32], generation: 0, payer: Pubkey::default(), seq: 0, counts: vec![] };
+
+        reactions.add(1).unwrap();
+        reactions.add(1).unwrap();
+        reactions.add(2).unwrap();
+        assert_eq!(reactions.counts.iter().map(|c| (c.code, c.count)).collect::<Vec<_>>(), vec![(1, 2), (2, 1)]);
+
+        reactions.remove(2);
+        reactions.remove(1);
+        assert_eq!(reactions.counts.iter().map(|c| (c.code, c.count)).collect::<Vec<_>>(), vec![(1, 1)]);
+
+        for code in 2..=MAX_REACTION_KINDS as u32 {
+            reactions.add(code).unwrap();
+        }
+        assert_eq!(reactions.add(100).unwrap_err(), ErrorCode::TooManyReactionKinds.into());
+    }
+
+    #[test]
+    fn pin_and_unpin_keep_the_list_packed() {
+        let mut pinned = [0u64; MAX_PINNED_MESSAGES];
+        let mut count = 0u8;
+
+        for seq in 1..=MAX_PINNED_MESSAGES as u64 {
+            pin(&mut pinned, &mut count, seq).unwrap();
+        }
+        assert_eq!(pin(&mut pinned, &mut count, 9).unwrap_err(), ErrorCode::PinListFull.into());
+
+        unpin(&mut pinned, &mut count, 2).unwrap();
+        assert_eq!(count as usize, MAX_PINNED_MESSAGES - 1);
+        assert_eq!(pinned, [1, 3, 4, 5, 0]);
+
+        assert_eq!(pin(&mut pinned, &mut count, 3).unwrap_err(), ErrorCode::AlreadyPinned.into());
+        assert_eq!(unpin(&mut pinned, &mut count, 2).unwrap_err(), ErrorCode::NotPinned.into());
+    }
+
+    #[test]
+    fn validate_body_checks_plaintext_bodies() {
+        assert!(validate_body(MessageKind::Text, &text_body("hello"), &None, false, MAX_TEXT_LENGTH).is_ok());
+        assert!(validate_body(MessageKind::Text, &text_body(""), &None, false, MAX_TEXT_LENGTH).is_err());
+        assert!(validate_body(MessageKind::Text, &text_body(&"a".repeat(MAX_TEXT_LENGTH + 1)), &None, false, MAX_TEXT_LENGTH).is_err());
+        // uploaded bodies are bounded by the buffer instead
+        assert!(validate_body(MessageKind::Text, &text_body(&"a".repeat(MAX_TEXT_LENGTH + 1)), &None, false, UPLOAD_BUFFER_MAX_LENGTH).is_ok());
+        assert!(validate_body(MessageKind::Text, b"not borsh", &None, false, MAX_TEXT_LENGTH).is_err());
+
+        let mut trailing = text_body("hello");
+        trailing.push(0);
+        assert!(validate_body(MessageKind::Text, &trailing, &None, false, MAX_TEXT_LENGTH).is_err());
+
+        // Ciphertext is opaque, only the attachment of attachment messages is checked.
+        assert!(validate_body(MessageKind::Text, b"ciphertext", &None, true, MAX_TEXT_LENGTH).is_ok());
+        assert!(validate_body(MessageKind::Attachment, b"ciphertext", &None, true, MAX_TEXT_LENGTH).is_err());
+
+        let poll = |options: Vec<&str>| to_vec(&PollBody {
+            question: "lunch?".to_string(),
+            options: options.into_iter().map(String::from).collect(),
+            multiple_choice: false,
+        }).unwrap();
+        assert!(validate_body(MessageKind::Poll, &poll(vec!["yes", "no"]), &None, false, MAX_TEXT_LENGTH).is_ok());
+        assert!(validate_body(MessageKind::Poll, &poll(vec!["yes"]), &None, false, MAX_TEXT_LENGTH).is_err());
+    }
+
+    // Ed25519 precompile data with one signature, its offsets pointing into the instruction itself.
+    fn ed25519_data(signer: &[u8; 32], message: &[u8]) -> Vec<u8> {
+        let mut data = vec![1, 0];
+        for offset in [16u16, u16::MAX, 80, u16::MAX, 112, message.len() as u16, u16::MAX] {
+            data.extend_from_slice(&offset.to_le_bytes());
+        }
+        data.extend_from_slice(&[7; 64]);
+        data.extend_from_slice(signer);
+        data.extend_from_slice(message);
+        data
+    }
+
+    #[test]
+    fn key_proof_accepts_either_edwards_form() {
+        let public_key = curve25519_dalek::constants::X25519_BASEPOINT.0;
+        let statement = key_statement(Pubkey::new_unique(), public_key);
+
+        for sign in [0, 1] {
+            let signer = MontgomeryPoint(public_key).to_edwards(sign).unwrap().compress().to_bytes();
+            let data = ed25519_data(&signer, &statement);
+            assert_eq!(check_ed25519_statement(&data, public_key, &statement).unwrap(), [7; 64]);
+        }
+    }
+
+    #[test]
+    fn key_proof_rejects_other_keys_and_statements() {
+        let public_key = curve25519_dalek::constants::X25519_BASEPOINT.0;
+        let signer = MontgomeryPoint(public_key).to_edwards(0).unwrap().compress().to_bytes();
+        let statement = key_statement(Pubkey::new_unique(), public_key);
+
+        let data = ed25519_data(&[1; 32], &statement);
+        assert_eq!(check_ed25519_statement(&data, public_key, &statement).unwrap_err(), ErrorCode::InvalidKeyProof.into());
+
+        let other = key_statement(Pubkey::new_unique(), public_key);
+        let data = ed25519_data(&signer, &other);
+        assert_eq!(check_ed25519_statement(&data, public_key, &statement).unwrap_err(), ErrorCode::InvalidKeyProof.into());
+
+        // The signed message must live in the precompile instruction itself.
+        let mut data = ed25519_data(&signer, &statement);
+        data[14..16].copy_from_slice(&0u16.to_le_bytes());
+        assert_eq!(check_ed25519_statement(&data, public_key, &statement).unwrap_err(), ErrorCode::InvalidKeyProof.into());
+    }
+
+    #[test]
+    fn prekeys_are_consumed_oldest_first() {
+        let mut bundle = PrekeyBundle {
+            wallet: Pubkey::new_unique(),
+            identity_key: [1; 32],
+            signed_prekey_id: 0,
+            signed_prekey: [0; 32],
+            signed_prekey_signature: [0; 64],
+            updated_at: 0,
+            next_prekey_id: 2,
+            one_time_prekeys: vec![OneTimePrekey { id: 0, key: [2; 32] }, OneTimePrekey { id: 1, key: [3; 32] }],
+        };
+        let consumer = Pubkey::new_unique();
+
+        // A bundle whose signed prekey was cleared by a key rotation hands nothing out.
+        assert_eq!(consume_prekey(&mut bundle, consumer).unwrap_err(), ErrorCode::SignedPrekeyNotSet.into());
+        assert_eq!(bundle.one_time_prekeys.len(), 2);
+
+        bundle.signed_prekey = [4; 32];
+        consume_prekey(&mut bundle, consumer).unwrap();
+        assert_eq!(bundle.one_time_prekeys.iter().map(|k| k.id).collect::<Vec<_>>(), vec![1]);
+        consume_prekey(&mut bundle, consumer).unwrap();
+        // an empty pool still hands out the signed prekey
+        consume_prekey(&mut bundle, consumer).unwrap();
+        assert!(bundle.one_time_prekeys.is_empty());
+    }
+
+    #[test]
+    fn device_envelopes_target_distinct_registered_devices() {
+        let registry = DeviceRegistry {
+            wallet: Pubkey::new_unique(),
+            devices: vec![Device { pubkey: [1; 32], added_at: 0 }, Device { pubkey: [2; 32], added_at: 0 }],
+        };
+        let envelope = |device: [u8; 32], length: usize| DeviceEnvelope { device, envelope: vec![0; length] };
+
+        assert!(validate_device_envelopes(&[], false, &[]).is_ok());
+        assert!(validate_device_envelopes(&[envelope([1; 32], 48), envelope([2; 32], 48)], true, &[&registry]).is_ok());
+
+        // plaintext messages carry no message key
+        assert_eq!(validate_device_envelopes(&[envelope([1; 32], 48)], false, &[&registry]).unwrap_err(), ErrorCode::InvalidDeviceEnvelopes.into());
+        assert_eq!(validate_device_envelopes(&[envelope([1; 32], 48), envelope([1; 32], 48)], true, &[&registry]).unwrap_err(),
+            ErrorCode::InvalidDeviceEnvelopes.into());
+        assert_eq!(validate_device_envelopes(&[envelope([1; 32], 0)], true, &[&registry]).unwrap_err(), ErrorCode::InvalidDeviceEnvelopes.into());
+        assert_eq!(validate_device_envelopes(&[envelope([1; 32], DEVICE_ENVELOPE_MAX_LENGTH + 1)], true, &[&registry]).unwrap_err(),
+            ErrorCode::InvalidDeviceEnvelopes.into());
+        assert_eq!(validate_device_envelopes(&[envelope([3; 32], 48)], true, &[&registry]).unwrap_err(), ErrorCode::DeviceNotFound.into());
+
+        let too_many: Vec<DeviceEnvelope> = (0..=MAX_DEVICE_ENVELOPES as u8).map(|i| envelope([i; 32], 48)).collect();
+        assert_eq!(validate_device_envelopes(&too_many, true, &[&registry]).unwrap_err(), ErrorCode::InvalidDeviceEnvelopes.into());
+    }
+
+    #[test]
+    fn schemes_are_checked_against_the_allowlist_and_nonce() {
+        let nonce = [0u8; MAX_NONCE_LENGTH];
+        assert!(validate_scheme(EncryptionScheme::None, &[], GROUP_SCHEMES).is_ok());
+        assert!(validate_scheme(EncryptionScheme::GroupEpochAead, &nonce, GROUP_SCHEMES).is_ok());
+        assert_eq!(validate_scheme(EncryptionScheme::X25519AesCbc, &[], GROUP_SCHEMES).unwrap_err(), ErrorCode::SchemeNotAllowed.into());
+        assert_eq!(validate_scheme(EncryptionScheme::X25519XChaCha20Poly1305, &nonce[1..], PRIVATE_CHAT_SCHEMES).unwrap_err(),
+            ErrorCode::InvalidNonce.into());
+        // AES-CBC keeps its IV in the content
+        assert_eq!(validate_scheme(EncryptionScheme::X25519AesCbc, &nonce, PRIVATE_CHAT_SCHEMES).unwrap_err(), ErrorCode::InvalidNonce.into());
+
+        // chats created before the allowlist allow the defaults
+        let mut chat = PrivateChat::zeroed();
+        assert_eq!(chat.allowed_schemes(), PRIVATE_CHAT_DEFAULT_SCHEMES);
+        chat.allowed_schemes = EncryptionScheme::X25519XChaCha20Poly1305.bit();
+        assert_eq!(validate_scheme(EncryptionScheme::None, &[], chat.allowed_schemes()).unwrap_err(), ErrorCode::SchemeNotAllowed.into());
+    }
+
+    #[test]
+    fn expires_at_rejects_overflowing_ttls() {
+        assert_eq!(expires_at(1_700_000_000, 0).unwrap(), None);
+        assert_eq!(expires_at(1_700_000_000, 60).unwrap(), Some(1_700_000_060));
+        assert_eq!(expires_at(1_700_000_000, MAX_MESSAGE_TTL).unwrap(), Some(1_700_000_000 + MAX_MESSAGE_TTL));
+        assert_eq!(expires_at(1_700_000_000, i64::MAX).unwrap_err(), ErrorCode::InvalidTtl.into());
+    }
+
+    fn group_member(group: Pubkey, wallet: Pubkey, state: GroupPeerState) -> GroupMember {
+        GroupMember { group, wallet, state, role: GroupRole::Member, joined_at: 0 }
+    }
+
+    #[test]
+    fn mentions_name_distinct_joined_members() {
+        let group = Pubkey::new_unique();
+        let (alice, bob, carol) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let owner = crate::ID;
+        let mut accounts: Vec<(Pubkey, u64, Vec<u8>)> = [
+            group_member(group, alice, GroupPeerState::Joined),
+            group_member(group, bob, GroupPeerState::Joined),
+            group_member(group, carol, GroupPeerState::Left),
+            group_member(Pubkey::new_unique(), carol, GroupPeerState::Joined),
+        ].into_iter().map(|member| {
+            let mut data = vec![];
+            member.try_serialize(&mut data).unwrap();
+            (Pubkey::new_unique(), 1, data)
+        }).collect();
+        let infos: Vec<AccountInfo> = accounts.iter_mut()
+            .map(|(key, lamports, data)| AccountInfo::new(key, false, false, lamports, data, &owner, false, 0))
+            .collect();
+        let sender = group_member(group, alice, GroupPeerState::Joined);
+
+        assert!(validate_mentions(group, &sender, &[alice, bob], false, &infos[..2]).is_ok());
+        assert_eq!(validate_mentions(group, &sender, &[alice, alice], false, &infos).unwrap_err(), ErrorCode::DuplicateMention.into());
+        // accounts in another order, missing, of a wallet that left or of another group
+        assert_eq!(validate_mentions(group, &sender, &[bob], false, &infos).unwrap_err(), ErrorCode::InvalidMention.into());
+        assert_eq!(validate_mentions(group, &sender, &[alice, bob], false, &infos[..1]).unwrap_err(), ErrorCode::InvalidMention.into());
+        assert_eq!(validate_mentions(group, &sender, &[carol], false, &infos[2..3]).unwrap_err(), ErrorCode::InvalidMention.into());
+        assert_eq!(validate_mentions(group, &sender, &[carol], false, &infos[3..]).unwrap_err(), ErrorCode::InvalidMention.into());
+
+        let too_many = vec![alice; MAX_MENTIONS + 1];
+        assert_eq!(validate_mentions(group, &sender, &too_many, false, &infos).unwrap_err(), ErrorCode::TooManyMentions.into());
+
+        // only the owner and admins mention everyone
+        assert_eq!(validate_mentions(group, &sender, &[], true, &infos).unwrap_err(), ErrorCode::InvalidRole.into());
+        let admin = GroupMember { role: GroupRole::Admin, ..sender };
+        assert!(validate_mentions(group, &admin, &[], true, &infos).is_ok());
+    }
+
+    fn legacy_message(content: &[u8]) -> LegacyMessage {
+        LegacyMessage { sender: Pubkey::new_unique(), encrypted: false, content: content.to_vec(), timestamp: 1_700_000_000 }
+    }
+
+    #[test]
+    fn legacy_group_is_walked_in_place() {
+        let owner = Pubkey::new_unique();
+        let legacy = LegacyGroupDescriptor {
+            title: b"title".to_vec(),
+            description: vec![],
+            image_url: b"url".to_vec(),
+            owner,
+            group_type: GroupType::Public,
+            state: GroupState::Active,
+            members: vec![LegacyGroup { account: owner, state: GroupPeerState::Joined }],
+            length: 2,
+            messages: vec![legacy_message(b"hi"), legacy_message(b"there")],
+        };
+        let mut data = LEGACY_GROUP_DESCRIPTOR_DISCRIMINATOR.to_vec();
+        legacy.serialize(&mut data).unwrap();
+
+        let metadata_end = skip_metadata(&data, 8).unwrap();
+        assert_eq!(data[8..metadata_end], to_vec(&GroupMetadata { title: legacy.title, description: legacy.description, image_url: legacy.image_url }).unwrap());
+        assert_eq!(data[metadata_end..metadata_end + 32], owner.to_bytes());
+        let members_end = skip_vec(&data, metadata_end + 34, 33).unwrap();
+        assert_eq!(data[members_end + 4..], to_vec(&legacy.messages).unwrap()); // after the length
+        assert_eq!(skip_vec(&data[..members_end - 1], metadata_end + 34, 33).unwrap_err(), ErrorCode::InvalidMigration.into());
+
+        let (key, program) = (Pubkey::new_unique(), crate::ID);
+        let length = data.len();
+        let mut lamports = 0;
+        let info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &program, false, 0);
+        let (first, next) = read_legacy_message(&info, members_end + 8).unwrap();
+        assert_eq!(first.content, b"hi");
+        let (second, end) = read_legacy_message(&info, next).unwrap();
+        assert_eq!(second.content, b"there");
+        assert_eq!(end, length);
+        assert!(read_legacy_message(&info, end).is_err());
+    }
+
+    #[test]
+    fn legacy_chat_messages_follow_the_header() {
+        let legacy = LegacyPrivateChat {
+            wallets: [Pubkey::new_unique(), Pubkey::new_unique()],
+            length: 1,
+            messages: vec![legacy_message(b"hi")],
+        };
+        let data = [PrivateChat::DISCRIMINATOR, &to_vec(&legacy).unwrap()].concat();
+
+        assert_eq!(<[Pubkey; 2]>::deserialize(&mut &data[8..]).unwrap(), legacy.wallets);
+        assert_eq!(u32::deserialize(&mut &data[LEGACY_MESSAGES_OFFSET..]).unwrap(), 1);
+        assert_eq!(data[LEGACY_MESSAGES_OFFSET + 4..], to_vec(&legacy.messages[0]).unwrap());
+    }
+}
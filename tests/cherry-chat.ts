@@ -1,16 +1,884 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
+import {
+  Ed25519Program,
+  Keypair,
+  PublicKey,
+  SystemProgram,
+  Transaction,
+} from "@solana/web3.js";
+import { createHash } from "crypto";
+import { expect } from "chai";
 import { CherryChat } from "../target/types/cherry_chat";
 
+const { BN } = anchor;
+const P = new BN(2).pow(new BN(255)).subn(19);
+
+// Identity key for the XEdDSA proof: an Ed25519 key with a zero sign bit and its X25519 form.
+const identityKey = () => {
+  for (;;) {
+    const keypair = Keypair.generate();
+    const edwards = keypair.publicKey.toBuffer();
+    if (edwards[31] & 0x80) continue;
+
+    // u = (1 + y) / (1 - y) mod p
+    const y = new BN(edwards, "le");
+    const u = y.addn(1).mul(new BN(1).sub(y).umod(P).invm(P)).umod(P);
+    return { keypair, x25519: u.toArrayLike(Buffer, "le", 32) };
+  }
+};
+
+const chatHash = (a: PublicKey, b: PublicKey) => {
+  const [first, second] = Buffer.compare(a.toBuffer(), b.toBuffer()) < 0 ? [a, b] : [b, a];
+  return createHash("sha256")
+    .update(Buffer.concat([first.toBuffer(), second.toBuffer()]))
+    .digest();
+};
+
+const textBody = (text: string) => {
+  const bytes = Buffer.from(text);
+  const length = Buffer.alloc(4);
+  length.writeUInt32LE(bytes.length);
+  return Buffer.concat([length, bytes]);
+};
+
+const sleep = (ms: number) => new Promise((resolve) => setTimeout(resolve, ms));
+
 describe("cherry-chat", () => {
   // Configure the client to use the local cluster.
-  anchor.setProvider(anchor.AnchorProvider.env());
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
 
   const program = anchor.workspace.cherryChat as Program<CherryChat>;
+  const connection = provider.connection;
+
+  const pda = (seeds: Buffer[]) => PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+  const u32 = (value: number) => {
+    const bytes = Buffer.alloc(4);
+    bytes.writeUInt32LE(value);
+    return bytes;
+  };
+  const u64 = (value: anchor.BN) => value.toArrayLike(Buffer, "le", 8);
+  const contactPda = (wallet: PublicKey, peer: PublicKey) =>
+    pda([Buffer.from("contact"), wallet.toBuffer(), peer.toBuffer(), Buffer.from([1])]);
+  const chatPda = (hash: Buffer) => pda([Buffer.from("privite_chat"), hash, Buffer.from([3])]);
+  const pagePda = (hash: Buffer, index: number) =>
+    pda([Buffer.from("message_page"), hash, u32(index), Buffer.from([1])]);
+  const descriptorPda = (wallet: PublicKey) => pda([Buffer.from("wallet_descriptor"), wallet.toBuffer(), Buffer.from([2])]);
+  const memberPda = (group: PublicKey, wallet: PublicKey) =>
+    pda([Buffer.from("group_member"), group.toBuffer(), wallet.toBuffer(), Buffer.from([1])]);
+
+  const balance = (key: PublicKey) => connection.getBalance(key);
+  const lamports = async (keys: PublicKey[]) =>
+    (await Promise.all(keys.map(balance))).reduce((sum, value) => sum + value, 0);
+
+  const fund = async (...wallets: Keypair[]) => {
+    const tx = new Transaction();
+    for (const wallet of wallets) {
+      tx.add(
+        SystemProgram.transfer({
+          fromPubkey: provider.wallet.publicKey,
+          toPubkey: wallet.publicKey,
+          lamports: anchor.web3.LAMPORTS_PER_SOL,
+        })
+      );
+    }
+    await provider.sendAndConfirm(tx);
+  };
+
+  // Identity keys of the registered wallets, they sign their prekeys.
+  const identities = new Map<string, ReturnType<typeof identityKey>>();
+  const register = async (wallet: Keypair) => {
+    const identity = identityKey();
+    identities.set(wallet.publicKey.toBase58(), identity);
+    const statement = Buffer.concat([
+      Buffer.from("cherry-chat:pubkey:v1:"),
+      wallet.publicKey.toBuffer(),
+      identity.x25519,
+    ]);
+
+    await program.methods
+      .register(Array.from(identity.x25519))
+      .accounts({ payer: wallet.publicKey })
+      .preInstructions([
+        Ed25519Program.createInstructionWithPrivateKey({
+          privateKey: identity.keypair.secretKey,
+          message: statement,
+        }),
+      ])
+      .signers([wallet])
+      .rpc();
+  };
+
+  // Registers both wallets, then the inviter opens the chat and the invitee accepts it.
+  const openChat = async (inviter: Keypair, invitee: Keypair) => {
+    await fund(inviter, invitee);
+    await register(inviter);
+    await register(invitee);
+
+    const hash = chatHash(inviter.publicKey, invitee.publicKey);
+    await program.methods
+      .invite(Array.from(hash), { none: {} }, Buffer.alloc(0), Buffer.alloc(0), { text: {} }, [])
+      .accountsPartial({
+        payer: inviter.publicKey,
+        invitee: invitee.publicKey,
+        privateChat: chatPda(hash),
+        messagePage: pagePda(hash, 0),
+        senderDevices: null,
+        receiverDevices: null,
+        prekeyBundle: null,
+      })
+      .signers([inviter])
+      .rpc();
+
+    await program.methods
+      .accept()
+      .accounts({ payer: invitee.publicKey, peer: inviter.publicKey })
+      .signers([invitee])
+      .rpc();
+
+    return hash;
+  };
+
+  const send = async (sender: Keypair, hash: Buffer, text: string, prekeyBundle: PublicKey | null = null) => {
+    const chat = await program.account.privateChat.fetch(chatPda(hash));
+    const page = chat.pageLength >= 32 ? chat.currentPage + 1 : chat.currentPage;
+
+    await program.methods
+      .sendmessage(Array.from(hash), { none: {} }, Buffer.alloc(0), textBody(text), null, null, { text: {} }, [])
+      .accountsPartial({
+        payer: sender.publicKey,
+        privateChat: chatPda(hash),
+        messagePage: pagePda(hash, page),
+        ringBuffer: null,
+        replyPage: null,
+        replyRing: null,
+        senderDevices: null,
+        receiverDevices: null,
+        prekeyBundle,
+      })
+      .signers([sender])
+      .rpc();
+  };
+
+  // First message of a page: its header starts right after the page header, its text body after the
+  // nonce, device envelope and content lengths.
+  const firstMessage = async (page: PublicKey) => {
+    const data = (await connection.getAccountInfo(page)).data;
+    const header = 56;
+    const textLength = data.readUInt32LE(header + 112 + 12);
+    return {
+      flags: data[header + 104],
+      kindVersion: data[header + 107],
+      text: data.subarray(header + 112 + 16, header + 112 + 16 + textLength).toString(),
+    };
+  };
+
+  const edit = (sender: Keypair, hash: Buffer, seq: number, text: string) =>
+    program.methods
+      .editMessage(0, new BN(seq), textBody(text), Buffer.alloc(0), false)
+      .accountsPartial({
+        payer: sender.publicKey,
+        messagePage: pagePda(hash, 0),
+        privateChat: chatPda(hash),
+        groupDescriptor: null,
+        payerMember: null,
+      })
+      .signers([sender])
+      .rpc();
+
+  const close = (payer: Keypair, peer: PublicKey, hash: Buffer, pages: PublicKey[]) =>
+    program.methods
+      .closePrivateChat(Array.from(hash), null)
+      .accountsPartial({
+        payer: payer.publicKey,
+        peer,
+        privateChat: chatPda(hash),
+        ringBuffer: null,
+        refundRecipient: null,
+      })
+      .remainingAccounts(pages.map((pubkey) => ({ pubkey, isSigner: false, isWritable: true })))
+      .signers([payer])
+      .rpc();
+
+  // Creates a group owned by a registered wallet.
+  const createGroup = async (owner: Keypair, groupType: any = { public: {} }) => {
+    const { createdGroups } = await program.account.walletDescriptor.fetch(descriptorPda(owner.publicKey));
+    const group = pda([Buffer.from("group_descriptor"), owner.publicKey.toBuffer(), u64(createdGroups)]);
+    await program.methods
+      .createGroup(groupType, Buffer.from("group"), Buffer.alloc(0), Buffer.alloc(0))
+      .accountsPartial({ payer: owner.publicKey, groupDescriptor: group })
+      .signers([owner])
+      .rpc();
+    return group;
+  };
+
+  // Sends a plaintext group message, mentioned wallets pass their GroupMember accounts.
+  const sendToGroup = async (sender: Keypair, group: PublicKey, text: string, mentions: PublicKey[] = [], mentionAll = false) => {
+    const descriptor = await program.account.groupDescriptor.fetch(group);
+    const page = descriptor.pageLength >= 32 ? descriptor.currentPage + 1 : descriptor.currentPage;
+
+    await program.methods
+      .sendMessageToGroup(textBody(text), null, null, { text: {} }, mentions, mentionAll, { none: {} }, Buffer.alloc(0), [])
+      .accountsPartial({
+        payer: sender.publicKey,
+        groupDescriptor: group,
+        messagePage: pagePda(group.toBuffer(), page),
+        ringBuffer: null,
+        replyPage: null,
+        replyRing: null,
+      })
+      .remainingAccounts(mentions.map((wallet) => ({ pubkey: memberPda(group, wallet), isSigner: false, isWritable: false })))
+      .signers([sender])
+      .rpc();
+  };
 
   it("Is initialized!", async () => {
-    // Add your test here.
-    const tx = await program.methods.register().rpc();
-    console.log("Your transaction signature", tx);
+    const wallet = Keypair.generate();
+    await fund(wallet);
+    await register(wallet);
+
+    const descriptor = await program.account.walletDescriptor.fetch(
+      pda([Buffer.from("wallet_descriptor"), wallet.publicKey.toBuffer(), Buffer.from([2])])
+    );
+    expect(descriptor.peerCount).to.equal(0);
+  });
+
+  it("rejects registrations without a proof of the key", async () => {
+    const wallet = Keypair.generate();
+    await fund(wallet);
+    const identity = identityKey();
+
+    let error: any = null;
+    await program.methods
+      .register(Array.from(identity.x25519))
+      .accounts({ payer: wallet.publicKey })
+      .signers([wallet])
+      .rpc()
+      .catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("MissingKeyProof");
+
+    // A proof signed by another key does not prove the registered one.
+    const other = identityKey();
+    error = null;
+    await program.methods
+      .register(Array.from(identity.x25519))
+      .accounts({ payer: wallet.publicKey })
+      .preInstructions([
+        Ed25519Program.createInstructionWithPrivateKey({
+          privateKey: other.keypair.secretKey,
+          message: Buffer.concat([Buffer.from("cherry-chat:pubkey:v1:"), wallet.publicKey.toBuffer(), identity.x25519]),
+        }),
+      ])
+      .signers([wallet])
+      .rpc()
+      .catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("InvalidKeyProof");
+  });
+
+  it("closes a chat and splits its rent by deposit", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const hash = await openChat(alice, bob);
+    await send(bob, hash, "hello alice");
+
+    const accounts = [
+      chatPda(hash),
+      pagePda(hash, 0),
+      contactPda(alice.publicKey, bob.publicKey),
+      contactPda(bob.publicKey, alice.publicKey),
+    ];
+    const total = await lamports(accounts);
+    const chat = await program.account.privateChat.fetch(chatPda(hash));
+    const [aliceDeposit, bobDeposit] = chat.deposits;
+
+    // The deposits account for every lamport held by the chat accounts.
+    expect(aliceDeposit.add(bobDeposit).toNumber()).to.equal(total);
+    expect(bobDeposit.toNumber()).to.be.greaterThan(0);
+
+    const aliceBefore = await balance(alice.publicKey);
+    const bobBefore = await balance(bob.publicKey);
+
+    // The first request only records the vote.
+    await close(alice, bob.publicKey, hash, []);
+    expect(await balance(chatPda(hash))).to.be.greaterThan(0);
+
+    await close(bob, alice.publicKey, hash, [pagePda(hash, 0)]);
+
+    for (const account of accounts) {
+      expect(await connection.getAccountInfo(account)).to.equal(null);
+    }
+    const aliceShare = new BN(total).mul(aliceDeposit).div(aliceDeposit.add(bobDeposit)).toNumber();
+    expect((await balance(alice.publicKey)) - aliceBefore).to.equal(aliceShare);
+    expect((await balance(bob.publicKey)) - bobBefore).to.equal(total - aliceShare);
+
+    const aliceDescriptor = await program.account.walletDescriptor.fetch(
+      pda([Buffer.from("wallet_descriptor"), alice.publicKey.toBuffer(), Buffer.from([2])])
+    );
+    expect(aliceDescriptor.peerCount).to.equal(0);
+  });
+
+  it("closes a chat in batches of pages", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const hash = await openChat(alice, bob);
+    // Long messages span several slots, so the chat moves on to a second page.
+    while ((await program.account.privateChat.fetch(chatPda(hash))).currentPage < 1) {
+      await send(alice, hash, "x".repeat(600));
+    }
+
+    await close(alice, bob.publicKey, hash, []);
+    await close(bob, alice.publicKey, hash, [pagePda(hash, 1)]);
+
+    // Page 0 is still open, so the chat is not refunded yet.
+    expect(await connection.getAccountInfo(pagePda(hash, 1))).to.equal(null);
+    const chat = await program.account.privateChat.fetch(chatPda(hash));
+    expect(chat.closing).to.equal(1);
+    expect(chat.openPages).to.equal(1);
+
+    // Sends are rejected while the close is in progress.
+    let error: any = null;
+    await send(alice, hash, "too late").catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("ChatClosing");
+
+    const total = await lamports([
+      chatPda(hash),
+      pagePda(hash, 0),
+      contactPda(alice.publicKey, bob.publicKey),
+      contactPda(bob.publicKey, alice.publicKey),
+    ]);
+    const aliceBefore = await balance(alice.publicKey);
+    const bobBefore = await balance(bob.publicKey);
+
+    await close(bob, alice.publicKey, hash, [pagePda(hash, 0)]);
+
+    expect(await connection.getAccountInfo(chatPda(hash))).to.equal(null);
+    const refunded = (await balance(alice.publicKey)) - aliceBefore + (await balance(bob.publicKey)) - bobBefore;
+    expect(refunded).to.equal(total);
+  });
+
+  it("closes the reactions and read markers a closed chat leaves behind", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const hash = await openChat(alice, bob);
+    await send(alice, hash, "hello");
+
+    const { generation } = await program.account.privateChat.fetch(chatPda(hash));
+    const reactionsPda = pda([Buffer.from("message_reactions"), hash, u64(generation), u64(new BN(0)), Buffer.from([1])]);
+    const reactionPda = pda([
+      Buffer.from("reaction"), hash, u64(generation), u64(new BN(0)), bob.publicKey.toBuffer(), u32(1), Buffer.from([1]),
+    ]);
+    const markerPda = pda([Buffer.from("read_marker"), hash, u64(generation), bob.publicKey.toBuffer(), Buffer.from([1])]);
+
+    await program.methods
+      .addReaction(Array.from(hash), new BN(0), 1)
+      .accountsPartial({
+        payer: bob.publicKey,
+        privateChat: chatPda(hash),
+        messageReactions: reactionsPda,
+        reaction: reactionPda,
+        messagePage: pagePda(hash, 0),
+        messageRing: null,
+      })
+      .signers([bob])
+      .rpc();
+    await program.methods
+      .markRead(Array.from(hash), new BN(0))
+      .accountsPartial({ payer: bob.publicKey, privateChat: chatPda(hash), readMarker: markerPda })
+      .signers([bob])
+      .rpc();
+
+    const closeAccounts = (recipient = bob.publicKey) =>
+      program.methods
+        .closeChatAccounts(Array.from(hash))
+        .accountsPartial({ payer: alice.publicKey, privateChat: chatPda(hash), groupDescriptor: null, messagePage: null })
+        .remainingAccounts(
+          [reactionsPda, reactionPda, markerPda].flatMap((pubkey) => [
+            { pubkey, isSigner: false, isWritable: true },
+            { pubkey: recipient, isSigner: false, isWritable: true },
+          ])
+        )
+        .signers([alice])
+        .rpc();
+
+    // The accounts of a live chat stay open.
+    let error: any = null;
+    await closeAccounts().catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("ChatAccountInUse");
+
+    await close(alice, bob.publicKey, hash, []);
+    await close(bob, alice.publicKey, hash, [pagePda(hash, 0)]);
+
+    // The rent only goes to the wallet recorded in the accounts.
+    error = null;
+    await closeAccounts(alice.publicKey).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("InvalidChatAccount");
+
+    // Bob paid for all three, so he gets their rent back.
+    const rent = await lamports([reactionsPda, reactionPda, markerPda]);
+    const bobBefore = await balance(bob.publicKey);
+    await closeAccounts();
+    expect((await balance(bob.publicKey)) - bobBefore).to.equal(rent);
+    expect(await connection.getAccountInfo(reactionsPda)).to.equal(null);
+  });
+
+  it("rejects reactions to missing or deleted messages and in closing chats", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const hash = await openChat(alice, bob);
+    await send(alice, hash, "hello");
+
+    const react = (messagePage: PublicKey | null, code: number) =>
+      program.methods
+        .addReaction(Array.from(hash), new BN(0), code)
+        .accountsPartial({ payer: bob.publicKey, privateChat: chatPda(hash), messagePage, messageRing: null })
+        .signers([bob])
+        .rpc();
+
+    // The message has to be proven by its page.
+    let error: any = null;
+    await react(null, 1).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("MessageNotFound");
+    await react(pagePda(hash, 0), 1);
+
+    await program.methods
+      .redactMessage(0, new BN(0))
+      .accountsPartial({
+        payer: alice.publicKey,
+        sender: alice.publicKey,
+        messagePage: pagePda(hash, 0),
+        privateChat: chatPda(hash),
+        groupDescriptor: null,
+        payerMember: null,
+      })
+      .signers([alice])
+      .rpc();
+    error = null;
+    await react(pagePda(hash, 0), 2).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("MessageNotFound");
+
+    await send(alice, hash, "again");
+    await close(alice, bob.publicKey, hash, []);
+    await close(bob, alice.publicKey, hash, []);
+    error = null;
+    await react(pagePda(hash, 0), 3).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("ChatClosing");
+  });
+
+  it("edits and redacts messages", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const hash = await openChat(alice, bob);
+    await send(alice, hash, "helo");
+
+    await edit(alice, hash, 0, "hello");
+    let message = await firstMessage(pagePda(hash, 0));
+    expect(message.text).to.equal("hello");
+    expect(message.kindVersion).to.equal(1);
+
+    // Only the sender edits a message.
+    let error: any = null;
+    await edit(bob, hash, 0, "hijacked").catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("NotMessageSender");
+
+    // Only the sender redacts a private message, and the freed rent goes back to them.
+    error = null;
+    const redact = (payer: Keypair) =>
+      program.methods
+        .redactMessage(0, new BN(0))
+        .accountsPartial({
+          payer: payer.publicKey,
+          sender: alice.publicKey,
+          messagePage: pagePda(hash, 0),
+          privateChat: chatPda(hash),
+          groupDescriptor: null,
+          payerMember: null,
+        })
+        .signers([payer])
+        .rpc();
+    await redact(bob).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("NotAllowedToRedact");
+
+    await redact(alice);
+    message = await firstMessage(pagePda(hash, 0));
+    expect(message.flags & (1 << 4)).to.not.equal(0);
+
+    error = null;
+    await edit(alice, hash, 0, "back").catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("MessageDeleted");
+  });
+
+  it("rejects edits while the chat is closing", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const hash = await openChat(alice, bob);
+    await send(alice, hash, "hello");
+
+    await close(alice, bob.publicKey, hash, []);
+    await close(bob, alice.publicKey, hash, []);
+    expect((await program.account.privateChat.fetch(chatPda(hash))).closing).to.equal(1);
+
+    let error: any = null;
+    await edit(alice, hash, 0, "edited").catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("ChatClosing");
+  });
+
+  it("commits uploaded texts above the message text limit", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const mallory = Keypair.generate();
+    const hash = await openChat(alice, bob);
+    await fund(mallory);
+
+    const bufferPda = (wallet: Keypair) =>
+      pda([Buffer.from("upload_buffer"), wallet.publicKey.toBuffer(), hash, u64(new BN(0)), Buffer.from([2])]);
+    const open = (wallet: Keypair, content: Buffer, scheme: any, nonce: Buffer) =>
+      program.methods
+        .openUploadBuffer(Array.from(hash), new BN(0), content.length, scheme, nonce,
+          Array.from(createHash("sha256").update(content).digest()), { text: {} })
+        .accountsPartial({
+          payer: wallet.publicKey,
+          uploadBuffer: bufferPda(wallet),
+          privateChat: chatPda(hash),
+          groupDescriptor: null,
+          payerMember: null,
+        })
+        .signers([wallet])
+        .rpc();
+
+    const content = textBody("u".repeat(2000));
+
+    // Buffers are only opened by members, in a scheme the chat allows.
+    let error: any = null;
+    await open(mallory, content, { none: {} }, Buffer.alloc(0)).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("NotInChat");
+    error = null;
+    await open(alice, content, { groupEpochAead: {} }, Buffer.alloc(24)).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("SchemeNotAllowed");
+
+    await open(alice, content, { none: {} }, Buffer.alloc(0));
+    for (let offset = 0; offset < content.length; offset += 800) {
+      await program.methods
+        .writeUploadChunk(offset, content.subarray(offset, offset + 800))
+        .accountsPartial({ payer: alice.publicKey, uploadBuffer: bufferPda(alice) })
+        .signers([alice])
+        .rpc();
+    }
+    await program.methods
+      .commitUpload(Array.from(hash), null, [])
+      .accountsPartial({
+        payer: alice.publicKey,
+        privateChat: chatPda(hash),
+        uploadBuffer: bufferPda(alice),
+        messagePage: pagePda(hash, 0),
+        ringBuffer: null,
+        senderDevices: null,
+        receiverDevices: null,
+      })
+      .signers([alice])
+      .rpc();
+
+    expect((await firstMessage(pagePda(hash, 0))).text).to.equal("u".repeat(2000));
+    expect(await connection.getAccountInfo(bufferPda(alice))).to.equal(null);
+  });
+
+  it("consumes one one-time prekey per sender and chat", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const hash = await openChat(alice, bob);
+    const bundle = pda([Buffer.from("prekey_bundle"), bob.publicKey.toBuffer(), Buffer.from([1])]);
+
+    const prekey = Keypair.generate().publicKey.toBuffer();
+    await program.methods
+      .setSignedPrekey(7, Array.from(prekey))
+      .accounts({ payer: bob.publicKey })
+      .preInstructions([
+        Ed25519Program.createInstructionWithPrivateKey({
+          privateKey: identities.get(bob.publicKey.toBase58()).keypair.secretKey,
+          message: Buffer.concat([Buffer.from("cherry-chat:prekey:v1:"), bob.publicKey.toBuffer(), u32(7), prekey]),
+        }),
+      ])
+      .signers([bob])
+      .rpc();
+    await program.methods
+      .addOneTimePrekeys([1, 2].map(() => Array.from(Keypair.generate().publicKey.toBuffer())))
+      .accounts({ payer: bob.publicKey })
+      .signers([bob])
+      .rpc();
+
+    await send(alice, hash, "hello", bundle);
+    expect((await program.account.prekeyBundle.fetch(bundle)).oneTimePrekeys.length).to.equal(1);
+    expect((await program.account.privateChat.fetch(chatPda(hash))).prekeySessions).to.equal(1);
+
+    // Later messages of the same sender cannot drain the pool.
+    let error: any = null;
+    await send(alice, hash, "again", bundle).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("SessionEstablished");
+    expect((await program.account.prekeyBundle.fetch(bundle)).oneTimePrekeys.length).to.equal(1);
+
+    // Nor can a bundle that is not the receiver's.
+    error = null;
+    await send(bob, hash, "hi", bundle).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("InvalidPrekeyBundle");
+  });
+
+  it("lets members who left join again and kicked members come back through an invite", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    await fund(alice, bob);
+    await register(alice);
+    await register(bob);
+    const group = await createGroup(alice);
+
+    const join = () =>
+      program.methods.joinGroup().accountsPartial({ payer: bob.publicKey, groupDescriptor: group }).signers([bob]).rpc();
+    await join();
+    await program.methods.leaveGroup().accountsPartial({ payer: bob.publicKey, groupDescriptor: group }).signers([bob]).rpc();
+    await join();
+    expect((await program.account.groupMember.fetch(memberPda(group, bob.publicKey))).state).to.deep.equal({ joined: {} });
+
+    await program.methods
+      .kickFromGroup(bob.publicKey)
+      .accountsPartial({ payer: alice.publicKey, groupDescriptor: group })
+      .signers([alice])
+      .rpc();
+    let error: any = null;
+    await join().catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("NotInvited");
+
+    await program.methods
+      .inviteToGroup(bob.publicKey)
+      .accountsPartial({ payer: alice.publicKey, groupDescriptor: group })
+      .signers([alice])
+      .rpc();
+    await program.methods
+      .acceptInviteToGroup()
+      .accountsPartial({ payer: bob.publicKey, groupDescriptor: group })
+      .signers([bob])
+      .rpc();
+
+    // Every rejoin is counted once.
+    expect((await program.account.walletDescriptor.fetch(descriptorPda(bob.publicKey))).groupCount).to.equal(1);
+    expect((await program.account.groupDescriptor.fetch(group)).memberCount).to.equal(2);
+  });
+
+  it("numbers event-only messages apart from stored ones", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const hash = await openChat(alice, bob);
+
+    await program.methods
+      .emitMessage(Array.from(hash), { none: {} }, Buffer.alloc(0), textBody("not stored"), null, { text: {} }, [])
+      .accountsPartial({ payer: alice.publicKey, privateChat: chatPda(hash), senderDevices: null, receiverDevices: null })
+      .signers([alice])
+      .rpc();
+    let chat = await program.account.privateChat.fetch(chatPda(hash));
+    expect(chat.messageCount.toNumber()).to.equal(0);
+    expect(chat.eventCount.toNumber()).to.equal(1);
+
+    // The next stored message still takes seq 0.
+    await send(bob, hash, "stored");
+    chat = await program.account.privateChat.fetch(chatPda(hash));
+    expect(chat.messageCount.toNumber()).to.equal(1);
+    await edit(bob, hash, 0, "stored and edited");
+  });
+
+  it("only mentions joined members and lets admins mention everyone", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const carol = Keypair.generate();
+    await fund(alice, bob, carol);
+    await Promise.all([alice, bob, carol].map(register));
+    const group = await createGroup(alice);
+    await program.methods.joinGroup().accountsPartial({ payer: bob.publicKey, groupDescriptor: group }).signers([bob]).rpc();
+
+    await sendToGroup(alice, group, "hi bob", [bob.publicKey]);
+    await sendToGroup(alice, group, "hi all", [], true);
+
+    let error: any = null;
+    await sendToGroup(alice, group, "hi carol", [carol.publicKey]).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("InvalidMention");
+    error = null;
+    await sendToGroup(bob, group, "hi all", [], true).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("InvalidRole");
+    expect((await program.account.groupDescriptor.fetch(group)).messageCount.toNumber()).to.equal(2);
+  });
+
+  it("rejects pin changes in closed groups", async () => {
+    const alice = Keypair.generate();
+    await fund(alice);
+    await register(alice);
+    const group = await createGroup(alice);
+    await sendToGroup(alice, group, "pin me");
+
+    const pin = (pinned: boolean) =>
+      (pinned ? program.methods.pinGroupMessage(new BN(0)) : program.methods.unpinGroupMessage(new BN(0)))
+        .accountsPartial({ payer: alice.publicKey, groupDescriptor: group })
+        .signers([alice])
+        .rpc();
+    await pin(true);
+    await program.methods.closeGroup().accountsPartial({ payer: alice.publicKey, groupDescriptor: group }).signers([alice]).rpc();
+
+    let error: any = null;
+    await pin(false).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("GroupIsNotActive");
+    expect((await program.account.groupDescriptor.fetch(group)).pinnedCount).to.equal(1);
+  });
+
+  it("lets participants restrict the schemes of a private chat", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const mallory = Keypair.generate();
+    const hash = await openChat(alice, bob);
+    await fund(mallory);
+
+    const setSchemes = (payer: Keypair, schemes: number) =>
+      program.methods
+        .setAllowedSchemes(Array.from(hash), schemes)
+        .accountsPartial({ payer: payer.publicKey, privateChat: chatPda(hash) })
+        .signers([payer])
+        .rpc();
+
+    let error: any = null;
+    await setSchemes(mallory, 0b0100).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("NotInChat");
+    // GroupEpochAead is a group scheme.
+    error = null;
+    await setSchemes(alice, 0b1000).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("SchemeNotAllowed");
+
+    // Only X25519XChaCha20Poly1305 is left, plaintext messages are refused.
+    await setSchemes(alice, 0b0100);
+    error = null;
+    await send(bob, hash, "plain").catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("SchemeNotAllowed");
+  });
+
+  it("lets group admins restrict the schemes of open groups", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    await fund(alice, bob);
+    await register(alice);
+    await register(bob);
+    const group = await createGroup(alice);
+    await program.methods.joinGroup().accountsPartial({ payer: bob.publicKey, groupDescriptor: group }).signers([bob]).rpc();
+
+    const setSchemes = (payer: Keypair, schemes: number) =>
+      program.methods
+        .setGroupAllowedSchemes(schemes)
+        .accountsPartial({ payer: payer.publicKey, groupDescriptor: group })
+        .signers([payer])
+        .rpc();
+
+    let error: any = null;
+    await setSchemes(bob, 0b1000).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("InvalidRole");
+    // X25519AesCbc is a private chat scheme.
+    error = null;
+    await setSchemes(alice, 0b0010).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("SchemeNotAllowed");
+
+    // Only GroupEpochAead is left, plaintext messages are refused.
+    await setSchemes(alice, 0b1000);
+    error = null;
+    await sendToGroup(bob, group, "plain").catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("SchemeNotAllowed");
+
+    await program.methods.closeGroup().accountsPartial({ payer: alice.publicKey, groupDescriptor: group }).signers([alice]).rpc();
+    error = null;
+    await setSchemes(alice, 0b1001).catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("GroupIsNotActive");
+  });
+
+  it("rejects message TTLs above the bound", async () => {
+    const alice = Keypair.generate();
+    const bob = Keypair.generate();
+    const hash = await openChat(alice, bob);
+
+    let error: any = null;
+    await program.methods
+      .setMessageTtl(Array.from(hash), new BN("9223372036854775807"))
+      .accountsPartial({ payer: alice.publicKey, privateChat: chatPda(hash) })
+      .signers([alice])
+      .rpc()
+      .catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("InvalidTtl");
+
+    // Sends still go through, the chat keeps its previous TTL.
+    await send(bob, hash, "still here");
+    const chat = await program.account.privateChat.fetch(chatPda(hash));
+    expect(chat.messageTtl.toNumber()).to.equal(0);
+  });
+
+  it("purges expired messages and refunds their senders", async () => {
+    const carol = Keypair.generate();
+    const dave = Keypair.generate();
+    const cranker = Keypair.generate();
+    const hash = await openChat(carol, dave);
+    await fund(cranker);
+
+    await program.methods
+      .setMessageTtl(Array.from(hash), new anchor.BN(1))
+      .accountsPartial({ payer: carol.publicKey, privateChat: chatPda(hash) })
+      .signers([carol])
+      .rpc();
+    await send(dave, hash, "y".repeat(400));
+    await sleep(3000);
+
+    // Lamports sent to the page on top of its rent are not paid out.
+    const page = pagePda(hash, 0);
+    await provider.sendAndConfirm(
+      new Transaction().add(SystemProgram.transfer({ fromPubkey: provider.wallet.publicKey, toPubkey: page, lamports: 1_000_000 }))
+    );
+    const pageBefore = await connection.getAccountInfo(page);
+    const daveDepositBefore = (await program.account.privateChat.fetch(chatPda(hash))).deposits[1];
+    const daveBefore = await balance(dave.publicKey);
+    const crankerBefore = await balance(cranker.publicKey);
+
+    await program.methods
+      .purgeExpired(0)
+      .accountsPartial({
+        payer: cranker.publicKey,
+        messagePage: page,
+        privateChat: chatPda(hash),
+        groupDescriptor: null,
+      })
+      .remainingAccounts([{ pubkey: dave.publicKey, isSigner: false, isWritable: true }])
+      .signers([cranker])
+      .rpc();
+
+    // The message is cut down to its header slot and the page shrinks with it.
+    const pageAfter = await connection.getAccountInfo(page);
+    expect(pageAfter.data.length).to.equal(56 + 256);
+    const freed = pageBefore.lamports - pageAfter.lamports;
+    expect(freed).to.equal(
+      (await connection.getMinimumBalanceForRentExemption(pageBefore.data.length)) -
+        (await connection.getMinimumBalanceForRentExemption(56 + 256))
+    );
+
+    const bounty = Math.floor((freed * 1_000) / 10_000);
+    expect((await balance(cranker.publicKey)) - crankerBefore).to.equal(bounty);
+    expect((await balance(dave.publicKey)) - daveBefore).to.equal(freed - bounty);
+
+    // The sender's deposit drops by everything freed, the bounty included.
+    const chat = await program.account.privateChat.fetch(chatPda(hash));
+    expect(daveDepositBefore.sub(chat.deposits[1]).toNumber()).to.equal(freed);
+    // The next message goes right after the cut down one.
+    expect(chat.pageLength).to.equal(1);
+
+    let error: any = null;
+    await program.methods
+      .purgeExpired(0)
+      .accountsPartial({
+        payer: cranker.publicKey,
+        messagePage: page,
+        privateChat: chatPda(hash),
+        groupDescriptor: null,
+      })
+      .remainingAccounts([{ pubkey: dave.publicKey, isSigner: false, isWritable: true }])
+      .signers([cranker])
+      .rpc()
+      .catch((e) => (error = e));
+    expect(error?.error?.errorCode?.code).to.equal("NothingToPurge");
   });
 });